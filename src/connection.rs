@@ -1,13 +1,29 @@
 use std::{
-    collections::HashMap,
+    cell::{Cell, RefCell},
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt::{self, Write},
     future::Future,
     pin::Pin,
     rc::Rc,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard, Weak},
     task::{Context, Poll, Waker},
+    time::Duration,
 };
 
+use futures::{
+    channel::mpsc,
+    future::{self, Either, LocalBoxFuture, Shared},
+    FutureExt, Stream, StreamExt,
+};
 use seed::prelude::*;
+use seed::{div, C};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::next_id::NextId;
+use crate::platform::{self, TaskHandle};
+use crate::transport::{Transport, TransportFactory};
 
 #[derive(Clone, Debug)]
 pub(crate) enum Msg {
@@ -15,163 +31,5039 @@ pub(crate) enum Msg {
     Closed,
     Failed,
     Received(String),
+    /// Binary frame, decoded by [`Codec::decode_binary`].
+    ReceivedBytes(Vec<u8>),
     Reconnect,
+    /// Sent each time the backoff stream attempts to reconnect.
+    Reconnecting {
+        #[allow(dead_code)]
+        attempt: u32,
+    },
+    /// Sent when the connection is opened again after being lost.
+    Reconnected,
+    /// Sent periodically while the connection is open, see [`ConnectionConfig`].
+    Heartbeat,
+    /// The connection has stayed open for [`BackoffPolicy::stable_after`].
+    Stable,
+    /// Sent when reconnecting has been given up after [`BackoffPolicy::max_retries`].
+    /// Use [`Connection::retry`] to try again.
+    GaveUp,
+    /// Sent when the server has not chosen any of [`ConnectionConfig::protocols`],
+    /// followed by [`Msg::Failed`].
+    ProtocolRejected,
 }
 
-#[derive(Clone)]
-pub(crate) struct Connection {
-    data: Arc<Mutex<ConnectionData>>,
+/// Lifecycle of a [`Connection`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ConnectionState {
+    /// Opening the websocket for the first time (or after [`Connection::open`])
+    Connecting,
+    /// The websocket is open, waiting for the server to accept
+    /// [`ConnectionConfig::auth_provider`] credentials
+    Authenticating,
+    Open,
+    /// The connection has been lost and it is being reopened
+    Reconnecting,
+    /// Closed by [`Connection::close`] (or [`Connection::stop_reconnecting`]).
+    /// It is not reopened automatically.
+    Closed,
+    /// Reconnecting has been given up after [`BackoffPolicy::max_retries`] failed attempts.
+    /// It is not reopened automatically, see [`Connection::retry`].
+    Failed,
 }
 
-pub(crate) struct ConnectionData {
-    url: String,
-    websocket: WebSocket,
-    reconnector: Option<StreamHandle>,
+impl ConnectionState {
+    /// Whether the connection stays down until reopened by the application.
+    fn is_terminal(self) -> bool {
+        matches!(self, ConnectionState::Closed | ConnectionState::Failed)
+    }
+}
 
-    next_free_id: u64,
-    requests: HashMap<u64, RequestEntry>,
+#[derive(Debug)]
+pub(crate) enum RequestError {
+    /// The message could not be written to the websocket.
+    Send(WebSocketError),
+    /// The request could not be serialized.
+    Encode(serde_json::Error),
+    /// The response could not be deserialized.
+    Decode(serde_json::Error),
+    /// The request could not be serialized to MessagePack.
+    MsgPackEncode(rmp_serde::encode::Error),
+    /// The response could not be deserialized from MessagePack.
+    MsgPackDecode(rmp_serde::decode::Error),
+    /// The server has answered with an error (`{id}|ERR|{message}` frame).
+    Server(String),
+    /// The request has been removed by a reconnect hook (or by [`Connection::set_url`]).
+    Cancelled,
+    /// Reconnecting has been given up.
+    ConnectionLost,
+    /// The connection has been closed by [`Connection::close`] (or dropped).
+    Closed,
+    /// Too many requests are waiting for the websocket to open, see [`QueuePolicy`].
+    QueueFull,
+    /// The request has been replayed [`ConnectionConfig::max_replays`] times already.
+    MaxRetriesExceeded,
+    /// The connection is being shut down by [`Connection::shutdown`].
+    ShuttingDown,
+    /// The server has rejected credentials (`AUTH_ERR|{reason}` frame).
+    /// The connection is closed then.
+    AuthFailed(String),
+    /// [`ConnectionConfig::max_in_flight`] requests are pending already,
+    /// see [`AdmissionPolicy`].
+    TooManyRequests,
 }
 
-impl Connection {
-    pub(crate) fn new(url: &str, orders: &mut impl Orders<Msg>) -> Self {
-        Self {
-            data: Arc::new(Mutex::new(ConnectionData {
-                url: url.to_owned(),
-                websocket: create_websocket(url, orders),
-                reconnector: None,
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Send(error) => write!(f, "cannot send request: {error:?}"),
+            RequestError::Encode(error) => write!(f, "cannot encode request: {error}"),
+            RequestError::Decode(error) => write!(f, "cannot decode response: {error}"),
+            RequestError::MsgPackEncode(error) => write!(f, "cannot encode request: {error}"),
+            RequestError::MsgPackDecode(error) => write!(f, "cannot decode response: {error}"),
+            RequestError::Server(message) => write!(f, "server error: {message}"),
+            RequestError::Cancelled => write!(f, "request cancelled"),
+            RequestError::ConnectionLost => write!(f, "connection lost"),
+            RequestError::Closed => write!(f, "connection closed"),
+            RequestError::QueueFull => write!(f, "too many requests queued"),
+            RequestError::MaxRetriesExceeded => write!(f, "request replayed too many times"),
+            RequestError::ShuttingDown => write!(f, "connection shutting down"),
+            RequestError::AuthFailed(reason) => write!(f, "authentication failed: {reason}"),
+            RequestError::TooManyRequests => write!(f, "too many requests in flight"),
+        }
+    }
+}
 
-                next_free_id: 0,
-                requests: HashMap::new(),
-            })),
+impl Error for RequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RequestError::Encode(error) | RequestError::Decode(error) => Some(error),
+            RequestError::MsgPackEncode(error) => Some(error),
+            RequestError::MsgPackDecode(error) => Some(error),
+            _ => None,
         }
     }
+}
 
-    pub(crate) fn update(msg: Msg, model: &mut Self, orders: &mut impl Orders<Msg>) {
-        let mut data = model.data.lock().unwrap();
-        match msg {
-            Msg::Failed | Msg::Closed => {
-                if data.reconnector.is_none() {
-                    data.reconnector = Some(
-                        orders.stream_with_handle(streams::backoff(Some(16), |_| Msg::Reconnect)),
-                    );
-                }
+impl From<WebSocketError> for RequestError {
+    fn from(error: WebSocketError) -> Self {
+        RequestError::Send(error)
+    }
+}
+
+/// Encodes request with given id and message into a frame.
+pub(crate) type RequestEncoder = Rc<dyn Fn(u64, &str) -> String>;
+
+/// Returns the authentication frame, e.g. with a fresh token.
+pub(crate) type AuthProvider = Rc<dyn Fn() -> String>;
+
+/// Called with frames pushed by the server.
+pub(crate) type PushHandler = Rc<dyn Fn(String)>;
+
+/// Called with id and content (or error message) of responses to unknown requests.
+pub(crate) type OrphanHandler = Rc<dyn Fn(u64, Payload)>;
+
+/// Called at a point of connection's lifecycle, see [`Connection::on_open`].
+pub(crate) type LifecycleHook = Rc<dyn Fn()>;
+
+/// Called with number of the reconnection attempt, see [`Connection::on_reconnect_scheduled`].
+pub(crate) type ReconnectScheduledHook = Rc<dyn Fn(u32)>;
+
+/// Called each time the websocket is opened, before pending requests are replayed.
+pub(crate) type ReconnectHook = Rc<dyn Fn(&mut ConnectionData)>;
+
+/// Frame accepting credentials sent by `ConnectionConfig::auth_provider`.
+const AUTH_OK_FRAME: &str = "AUTH_OK";
+/// Id segment of frames rejecting credentials.
+const AUTH_ERR_ID: &str = "AUTH_ERR";
+
+/// Id segment of frames that do not expect any reply.
+const NO_REPLY_ID: &str = "-";
+
+/// Id segment of frames pushed by the server (not responses to requests).
+/// Frames without any id segment are treated as notifications as well.
+const NOTIFICATION_ID: &str = "*";
+
+/// Number of connection epochs, which make the upper bits of request ids
+/// (below `PING_ID_START`), so ids of different connections differ.
+const EPOCH_COUNT: u32 = 1 << 31;
+
+/// Content of frames telling the server that nobody waits for response of the request anymore.
+const CANCEL_CONTENT: &str = "CANCEL";
+
+/// Content of frames sent by [`Connection::ping`].
+const PING_CONTENT: &str = "PING";
+
+/// Ids of pings start here, so they do not collide with ids of requests.
+const PING_ID_START: u64 = 1 << 63;
+
+/// Ids of control frames ([`ReservedId`]) start here, above ids of pings.
+const RESERVED_ID_START: u64 = u64::MAX - 1024;
+
+// Ids of requests, pings and control frames must not overlap
+const _: () = assert!((EPOCH_COUNT as u64) << 32 <= PING_ID_START);
+const _: () = assert!(PING_ID_START < RESERVED_ID_START);
+
+/// Fixed ids of control frames sent by the server (from `RESERVED_ID_START`).
+/// They are handled before responses are matched with requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReservedId {
+    /// Keep-alive frame, which only postpones [`ConnectionConfig::pong_deadline`]
+    KeepAlive,
+    /// The server is going away (e.g. being redeployed), so the websocket is reopened
+    GoAway,
+}
+
+impl ReservedId {
+    const ALL: [ReservedId; 2] = [ReservedId::KeepAlive, ReservedId::GoAway];
+
+    pub(crate) fn id(self) -> u64 {
+        RESERVED_ID_START + self as u64
+    }
+
+    fn from_id(id: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|reserved| reserved.id() == id)
+    }
+}
+
+/// Prefix of frames carrying several requests, see [`Codec::encode_batch`].
+const BATCH_PREFIX: &str = "BATCH";
+
+/// Separates requests in a batch frame.
+const BATCH_SEPARATOR: char = '\x1f';
+
+/// Length of the id prefix of binary frames.
+const BINARY_ID_LENGTH: usize = 8;
+
+/// Configuration of a [`Connection`].
+#[derive(Clone)]
+pub(crate) struct ConnectionConfig {
+    /// How often `ping_frame` is sent while the connection is open.
+    pub(crate) heartbeat_interval: Duration,
+    /// Frame sent as a heartbeat.
+    pub(crate) ping_frame: String,
+    /// Frame the server replies to `ping_frame` with. It is not treated as a notification.
+    pub(crate) pong_frame: String,
+    /// The connection is considered dead (and reconnected) when no frame
+    /// has been received for this long.
+    pub(crate) pong_deadline: Duration,
+    pub(crate) backoff: BackoffPolicy,
+    /// Maximal number of pending requests. Unlimited if `None`.
+    pub(crate) max_in_flight: Option<usize>,
+    /// What happens to requests made when `max_in_flight` is reached.
+    pub(crate) admission_policy: AdmissionPolicy,
+    /// Maximal number of requests waiting for the websocket to open.
+    pub(crate) queue_limit: usize,
+    /// What happens to requests made when `queue_limit` is reached.
+    pub(crate) queue_policy: QueuePolicy,
+    /// Maximal number of times a pending request is replayed after reconnection,
+    /// so a request which makes the server drop the connection is not sent forever.
+    /// Unlimited if `None`.
+    pub(crate) max_replays: Option<u32>,
+    /// If set, its frame is sent first each time the websocket is opened and pending
+    /// requests wait until the server accepts it with `AUTH_OK` frame.
+    pub(crate) auth_provider: Option<AuthProvider>,
+    /// Format of frames, unless another [`Codec`] is set by [`ConnectionBuilder::codec`].
+    pub(crate) framing: Framing,
+    /// Subprotocols offered to the server (`Sec-WebSocket-Protocol` header),
+    /// see [`Connection::negotiated_protocol`].
+    pub(crate) protocols: Vec<String>,
+    /// Called (after the update) with responses to requests which are not pending (anymore),
+    /// e.g. cancelled ones or duplicates, counted by [`ConnectionStats::orphan_responses`].
+    pub(crate) on_orphan_response: Option<OrphanHandler>,
+    /// [`Priority::Low`] requests waiting for longer are sent (or admitted)
+    /// like [`Priority::Normal`] ones, so they are not starved.
+    pub(crate) promote_low_after: Duration,
+    /// Frames observed by [`Connection::tap`] are truncated to this many characters (or bytes).
+    pub(crate) tap_preview_length: usize,
+    /// Maximal number of frames kept for [`Connection::tap`] while nobody is subscribed
+    /// (after the first subscription).
+    pub(crate) tap_buffer: usize,
+    /// Textual request frames longer than this (in bytes) are split into chunk frames
+    /// (see [`Codec::encode_chunk`]) for the server to reassemble. Unlimited if `None`.
+    ///
+    /// Escaping by [`JsonEnvelopeCodec`] may make chunk frames slightly longer.
+    pub(crate) max_frame_size: Option<usize>,
+    /// Chunked responses longer than this (in bytes) are discarded.
+    pub(crate) max_reassembly_size: usize,
+    /// Chunked responses not completed within this time are discarded.
+    pub(crate) reassembly_timeout: Duration,
+}
+
+/// Encodes and decodes textual frames.
+pub(crate) trait Codec {
+    fn encode_request(&self, id: u64, body: &str) -> String;
+
+    /// Encodes message which does not expect any response.
+    fn encode_notification(&self, body: &str) -> String;
+
+    /// Encodes several requests into a single frame.
+    /// The server answers each of them with a separate response.
+    fn encode_batch(&self, requests: &[(u64, &str)]) -> String;
+
+    /// Encodes `seq`-th (starting at 1) of `total` parts of an oversized frame
+    /// of the request with `id`, see [`ConnectionConfig::max_frame_size`].
+    fn encode_chunk(&self, id: u64, seq: usize, total: usize, part: &str) -> String;
+
+    /// Encodes frame following all parts of the request with `id`.
+    fn encode_chunk_end(&self, id: u64) -> String;
+
+    fn decode(&self, frame: &str) -> Result<Frame, FrameError>;
+
+    /// Encodes binary request frame, by default as little-endian `u64` id followed by `body`.
+    fn encode_binary_request(&self, id: u64, body: &[u8]) -> Result<Vec<u8>, RequestError> {
+        let mut frame = Vec::with_capacity(BINARY_ID_LENGTH + body.len());
+        frame.extend_from_slice(&id.to_le_bytes());
+        frame.extend_from_slice(body);
+        Ok(frame)
+    }
+
+    /// Decodes binary frame, by default as little-endian `u64` id followed by the response.
+    fn decode_binary(&self, frame: &[u8]) -> Result<BinaryFrame, FrameError> {
+        if frame.len() < BINARY_ID_LENGTH {
+            return Err(FrameError::TooShort(frame.len()));
+        }
+        let (id, body) = frame.split_at(BINARY_ID_LENGTH);
+        Ok(BinaryFrame {
+            id: u64::from_le_bytes(id.try_into().unwrap()),
+            result: Ok(body.to_vec()),
+        })
+    }
+}
+
+/// Default codec of frames: `{id}{delimiter}{content}`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Framing {
+    /// Separates the id from the content (and the status from the payload of responses).
+    delimiter: char,
+    /// Radix of ids, between 2 and 36.
+    radix: u32,
+}
+
+/// Settings passed to [`Framing::new`] which would make frames ambiguous.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InvalidFraming {
+    /// Radix of ids is not between 2 and 36.
+    Radix(u32),
+    /// The delimiter is a digit of ids or a part of reserved markers (e.g. `*` of notifications).
+    Delimiter(char),
+}
+
+impl fmt::Display for InvalidFraming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidFraming::Radix(radix) => {
+                write!(f, "radix of ids must be between 2 and 36, got {radix}")
             }
-            Msg::Reconnect => {
-                data.websocket = create_websocket(&data.url, orders);
+            InvalidFraming::Delimiter(delimiter) => {
+                write!(f, "{delimiter:?} cannot delimit frames")
             }
-            Msg::Opened => {
-                data.reconnector = None;
-                for entry in data.requests.values() {
-                    let _ = send_message(&entry.request, &data.websocket);
+        }
+    }
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Self {
+            delimiter: '|',
+            radix: 10,
+        }
+    }
+}
+
+impl Codec for Framing {
+    fn encode_request(&self, id: u64, body: &str) -> String {
+        format!("{}{}{body}", self.encode_id(id), self.delimiter)
+    }
+
+    fn encode_notification(&self, body: &str) -> String {
+        format!("{NO_REPLY_ID}{}{body}", self.delimiter)
+    }
+
+    fn encode_batch(&self, requests: &[(u64, &str)]) -> String {
+        let requests = requests
+            .iter()
+            .map(|&(id, body)| self.encode_request(id, body))
+            .collect::<Vec<_>>()
+            .join(&BATCH_SEPARATOR.to_string());
+        format!("{BATCH_PREFIX}{}{requests}", self.delimiter)
+    }
+
+    fn encode_chunk(&self, id: u64, seq: usize, total: usize, part: &str) -> String {
+        let d = self.delimiter;
+        format!("{}{d}CHUNK{d}{seq}/{total}{d}{part}", self.encode_id(id))
+    }
+
+    fn encode_chunk_end(&self, id: u64) -> String {
+        format!("{}{}CHUNK_END", self.encode_id(id), self.delimiter)
+    }
+
+    fn decode(&self, frame: &str) -> Result<Frame, FrameError> {
+        Ok(match self.parse_frame(frame)? {
+            PipeFrame::Response {
+                id,
+                content: "CHUNK_END",
+            } => Frame::ChunkEnd { id },
+            PipeFrame::Response { id, content } => {
+                if let Some(chunk) = self.strip_status("CHUNK", content) {
+                    return self.decode_chunk(id, chunk);
                 }
-            }
-            Msg::Received(packet) => {
-                seed::log!(packet);
-                let (rid, content) = packet.split_once('|').unwrap();
-                let rid: u64 = rid.parse().unwrap();
-                let entry = data.requests.remove(&rid);
-                if let Some(entry) = entry {
-                    entry.set_response(content.to_string());
+                match self.strip_status("PART", content) {
+                    Some(chunk) => Frame::Part {
+                        id,
+                        chunk: chunk.to_owned(),
+                    },
+                    None => Frame::Response {
+                        id,
+                        result: self
+                            .decode_response(content)
+                            .map(str::to_owned)
+                            .map_err(str::to_owned),
+                    },
                 }
             }
+            PipeFrame::Notification(content) => Frame::Notification(content.to_owned()),
+            PipeFrame::NoReply => Frame::NoReply,
+            PipeFrame::AuthOk => Frame::AuthOk,
+            PipeFrame::AuthErr(reason) => Frame::AuthErr(reason.to_owned()),
+        })
+    }
+}
+
+impl Framing {
+    #[allow(dead_code)]
+    pub(crate) fn new(delimiter: char, radix: u32) -> Result<Self, InvalidFraming> {
+        if !(2..=36).contains(&radix) {
+            return Err(InvalidFraming::Radix(radix));
         }
+        let reserved = [
+            AUTH_OK_FRAME,
+            AUTH_ERR_ID,
+            NO_REPLY_ID,
+            NOTIFICATION_ID,
+            BATCH_PREFIX,
+        ]
+        .iter()
+        .any(|marker| marker.contains(delimiter));
+        // Batched requests and counts of chunks are separated by these
+        let separator = matches!(delimiter, BATCH_SEPARATOR | '/');
+        if delimiter.is_digit(radix) || reserved || separator {
+            return Err(InvalidFraming::Delimiter(delimiter));
+        }
+        Ok(Self { delimiter, radix })
     }
 
-    pub(crate) fn request(&self, message: &str) -> impl Future<Output = String> {
-        let state = Arc::new(Mutex::new(ResponseFutureState {
-            response_message: None,
-            waker: None,
-        }));
+    fn encode_id(&self, mut id: u64) -> String {
+        if self.radix == 10 {
+            return id.to_string();
+        }
+        let radix = u64::from(self.radix);
+        let mut digits = Vec::new();
+        loop {
+            digits.push(char::from_digit((id % radix) as u32, self.radix).unwrap());
+            id /= radix;
+            if id == 0 {
+                break;
+            }
+        }
+        digits.into_iter().rev().collect()
+    }
 
-        let data = &mut *self.data.lock().unwrap();
+    fn parse_frame<'a>(&self, frame: &'a str) -> Result<PipeFrame<'a>, FrameError> {
+        if frame.is_empty() {
+            return Err(FrameError::Empty);
+        }
+        match frame.split_once(self.delimiter) {
+            None if frame == AUTH_OK_FRAME => Ok(PipeFrame::AuthOk),
+            None => Ok(PipeFrame::Notification(frame)),
+            Some((AUTH_ERR_ID, reason)) => Ok(PipeFrame::AuthErr(reason)),
+            Some((NOTIFICATION_ID, content)) => Ok(PipeFrame::Notification(content)),
+            Some((NO_REPLY_ID, _)) => Ok(PipeFrame::NoReply),
+            Some((id, content)) => match u64::from_str_radix(id, self.radix) {
+                Ok(id) => Ok(PipeFrame::Response { id, content }),
+                Err(_) => Err(FrameError::InvalidId(id.to_owned())),
+            },
+        }
+    }
 
-        let id = data.next_free_id;
-        data.next_free_id = data.next_free_id.wrapping_add(1);
+    /// Splits content of a response frame into the result of the request.
+    ///
+    /// `OK|{payload}` (or `END|{payload}` of a streamed response) is a success
+    /// and `ERR|{message}` a failure.
+    /// Content without any of these prefixes is a success (legacy format).
+    fn decode_response<'a>(&self, content: &'a str) -> Result<&'a str, &'a str> {
+        if let Some(message) = self.strip_status("ERR", content) {
+            Err(message)
+        } else {
+            Ok(self
+                .strip_status("OK", content)
+                .or_else(|| self.strip_status("END", content))
+                .unwrap_or(content))
+        }
+    }
 
-        let request = format!("{id}|{message}");
+    /// Decodes `{seq}/{total}|{part}` content of a chunk frame.
+    fn decode_chunk(&self, id: u64, chunk: &str) -> Result<Frame, FrameError> {
+        let invalid = || FrameError::InvalidChunk(chunk.to_owned());
+        let (counts, part) = chunk.split_once(self.delimiter).ok_or_else(invalid)?;
+        let (seq, total) = counts.split_once('/').ok_or_else(invalid)?;
+        Ok(Frame::Chunk {
+            id,
+            seq: seq.parse().map_err(|_| invalid())?,
+            total: total.parse().map_err(|_| invalid())?,
+            part: part.to_owned(),
+        })
+    }
 
-        let _ = data.websocket.send_text(&request);
+    /// Strips `{status}|` prefix from content of a response frame.
+    fn strip_status<'a>(&self, status: &str, content: &'a str) -> Option<&'a str> {
+        content
+            .strip_prefix(status)
+            .and_then(|rest| rest.strip_prefix(self.delimiter))
+    }
+}
 
-        data.requests.insert(
-            id,
-            RequestEntry {
-                request,
-                future_state: state.clone(),
-            },
-        );
+/// Codec of JSON envelopes: requests are sent as `{"id":1,"body":"..."}`
+/// and notifications as `{"body":"..."}`.
+///
+/// Received envelopes with an id are responses (`{"id":1,"body":...}`), errors
+/// (`{"id":1,"error":"..."}`) or parts of streamed responses (`{"id":1,"part":"..."}`).
+/// Oversized frames are chunked as `{"id":1,"chunk":"...","seq":1,"total":2}`
+/// followed by `{"id":1,"chunk_end":true}`, in both directions.
+/// Envelopes without an id are notifications. Authentication frames are not supported.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct JsonEnvelopeCodec;
+
+impl Codec for JsonEnvelopeCodec {
+    fn encode_request(&self, id: u64, body: &str) -> String {
+        serde_json::json!({ "id": id, "body": body }).to_string()
+    }
+
+    fn encode_notification(&self, body: &str) -> String {
+        serde_json::json!({ "body": body }).to_string()
+    }
+
+    fn encode_batch(&self, requests: &[(u64, &str)]) -> String {
+        let requests = requests
+            .iter()
+            .map(|&(id, body)| serde_json::json!({ "id": id, "body": body }))
+            .collect::<Vec<_>>();
+        serde_json::json!({ "batch": requests }).to_string()
+    }
+
+    fn encode_chunk(&self, id: u64, seq: usize, total: usize, part: &str) -> String {
+        serde_json::json!({ "id": id, "chunk": part, "seq": seq, "total": total }).to_string()
+    }
+
+    fn encode_chunk_end(&self, id: u64) -> String {
+        serde_json::json!({ "id": id, "chunk_end": true }).to_string()
+    }
+
+    fn decode(&self, frame: &str) -> Result<Frame, FrameError> {
+        let envelope: serde_json::Value = serde_json::from_str(frame)
+            .map_err(|error| FrameError::InvalidEnvelope(error.to_string()))?;
+        // Bodies which are not strings are passed on as JSON
+        let field = |key: &str| {
+            envelope.get(key).map(|value| {
+                value
+                    .as_str()
+                    .map_or_else(|| value.to_string(), str::to_owned)
+            })
+        };
+        let missing_body = || FrameError::InvalidEnvelope("missing body".to_owned());
 
-        ResponseFuture { state }
+        let Some(id) = envelope.get("id") else {
+            return field("body")
+                .map(Frame::Notification)
+                .ok_or_else(missing_body);
+        };
+        let id = id
+            .as_u64()
+            .ok_or_else(|| FrameError::InvalidId(id.to_string()))?;
+        if envelope.get("chunk_end").is_some() {
+            Ok(Frame::ChunkEnd { id })
+        } else if let Some(part) = field("chunk") {
+            let count = |key: &str| {
+                envelope
+                    .get(key)
+                    .and_then(serde_json::Value::as_u64)
+                    .and_then(|count| usize::try_from(count).ok())
+            };
+            let (Some(seq), Some(total)) = (count("seq"), count("total")) else {
+                return Err(FrameError::InvalidChunk(frame.to_owned()));
+            };
+            Ok(Frame::Chunk {
+                id,
+                seq,
+                total,
+                part,
+            })
+        } else if let Some(chunk) = field("part") {
+            Ok(Frame::Part { id, chunk })
+        } else if let Some(message) = field("error") {
+            Ok(Frame::Response {
+                id,
+                result: Err(message),
+            })
+        } else {
+            let body = field("body").ok_or_else(missing_body)?;
+            Ok(Frame::Response {
+                id,
+                result: Ok(body),
+            })
+        }
     }
 }
 
-//------------------------------------------------------------------------------
-// Operations on raw websockets
-//------------------------------------------------------------------------------
+/// Codec of MessagePack envelopes of binary frames: binary requests are sent as
+/// `{"id": 1, "body": <bin>}` maps and the server answers with the same envelope
+/// or with `{"id": 1, "error": "..."}`.
+///
+/// Textual frames are handled by `framing`, unless the codec is [binary-only](Self::binary_only).
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct MsgPackCodec {
+    framing: Framing,
+    binary_only: bool,
+}
 
-fn create_websocket(url: &str, orders: &mut impl Orders<Msg>) -> WebSocket {
-    let msg_sender = orders.msg_sender();
+impl MsgPackCodec {
+    #[allow(dead_code)]
+    pub(crate) fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            binary_only: false,
+        }
+    }
 
-    WebSocket::builder(url, orders)
-        .on_open(|| Msg::Opened)
-        .on_message(move |msg| decode_message(msg, msg_sender))
-        .on_close(|_| Msg::Closed)
-        .on_error(|| Msg::Failed)
-        .build_and_open()
-        .unwrap()
+    /// The server sends binary frames only, so received textual frames are malformed.
+    #[allow(dead_code)]
+    pub(crate) fn binary_only(mut self) -> Self {
+        self.binary_only = true;
+        self
+    }
 }
 
-fn decode_message(message: WebSocketMessage, msg_sender: Rc<dyn Fn(Option<Msg>)>) {
-    if message.contains_text() {
-        msg_sender(Some(Msg::Received(message.text().unwrap())));
-    } else {
-        panic!("Unsupported message type");
+impl Codec for MsgPackCodec {
+    fn encode_request(&self, id: u64, body: &str) -> String {
+        self.framing.encode_request(id, body)
+    }
+
+    fn encode_notification(&self, body: &str) -> String {
+        self.framing.encode_notification(body)
+    }
+
+    fn encode_batch(&self, requests: &[(u64, &str)]) -> String {
+        self.framing.encode_batch(requests)
+    }
+
+    fn encode_chunk(&self, id: u64, seq: usize, total: usize, part: &str) -> String {
+        self.framing.encode_chunk(id, seq, total, part)
+    }
+
+    fn encode_chunk_end(&self, id: u64) -> String {
+        self.framing.encode_chunk_end(id)
+    }
+
+    fn decode(&self, frame: &str) -> Result<Frame, FrameError> {
+        if self.binary_only {
+            return Err(FrameError::UnexpectedText);
+        }
+        self.framing.decode(frame)
+    }
+
+    fn encode_binary_request(&self, id: u64, body: &[u8]) -> Result<Vec<u8>, RequestError> {
+        // Writes to a vector fail only if the body does not fit into a MessagePack binary
+        let mut frame = Vec::with_capacity(body.len() + 16);
+        rmp::encode::write_map_len(&mut frame, 2).unwrap();
+        rmp::encode::write_str(&mut frame, "id").unwrap();
+        rmp::encode::write_uint(&mut frame, id).unwrap();
+        rmp::encode::write_str(&mut frame, "body").unwrap();
+        rmp::encode::write_bin(&mut frame, body).map_err(|error| {
+            RequestError::MsgPackEncode(rmp_serde::encode::Error::InvalidValueWrite(error))
+        })?;
+        Ok(frame)
+    }
+
+    fn decode_binary(&self, frame: &[u8]) -> Result<BinaryFrame, FrameError> {
+        let invalid =
+            |error: &dyn std::fmt::Display| FrameError::InvalidEnvelope(error.to_string());
+        let mut rest = frame;
+        let (mut id, mut result) = (None, None);
+        for _ in 0..rmp::decode::read_map_len(&mut rest).map_err(|error| invalid(&error))? {
+            let (key, tail) =
+                rmp::decode::read_str_from_slice(rest).map_err(|error| invalid(&error))?;
+            rest = tail;
+            match key {
+                "id" => {
+                    id = Some(rmp::decode::read_int(&mut rest).map_err(|error| invalid(&error))?)
+                }
+                "body" => {
+                    let length =
+                        rmp::decode::read_bin_len(&mut rest).map_err(|error| invalid(&error))?;
+                    let length = usize::try_from(length).unwrap_or(usize::MAX);
+                    if rest.len() < length {
+                        return Err(invalid(&"truncated body"));
+                    }
+                    let (body, tail) = rest.split_at(length);
+                    rest = tail;
+                    result = Some(Ok(body.to_vec()));
+                }
+                "error" => {
+                    let (message, tail) =
+                        rmp::decode::read_str_from_slice(rest).map_err(|error| invalid(&error))?;
+                    rest = tail;
+                    result = Some(Err(message.to_owned()));
+                }
+                key => return Err(invalid(&format!("unexpected key {key}"))),
+            }
+        }
+        match (id, result) {
+            (Some(id), Some(result)) => Ok(BinaryFrame { id, result }),
+            (None, _) => Err(invalid(&"missing id")),
+            (_, None) => Err(invalid(&"missing body")),
+        }
     }
 }
 
-fn send_message(message: impl AsRef<str>, websocket: &WebSocket) -> Result<(), WebSocketError> {
-    websocket.send_text(message)
+/// Handling of requests made when the queue of requests waiting for the websocket is full.
+/// Rejected or dropped requests fail with [`RequestError::QueueFull`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub(crate) enum QueuePolicy {
+    /// Reject new requests
+    #[default]
+    RejectNew,
+    /// Drop the oldest queued request to make room for the new one
+    DropOldest,
 }
 
-//------------------------------------------------------------------------------
-// Tracking requests
-//------------------------------------------------------------------------------
+/// Handling of requests made when [`ConnectionConfig::max_in_flight`] requests are pending.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub(crate) enum AdmissionPolicy {
+    /// Wait for a free slot; waiting requests are sent in the order they were made
+    #[default]
+    Wait,
+    /// Fail with [`RequestError::TooManyRequests`]
+    FailFast,
+}
 
-struct RequestEntry {
-    request: String,
-    future_state: Arc<Mutex<ResponseFutureState>>,
+/// Urgency of a request, see [`RequestOptions::priority`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum Priority {
+    /// Background work, e.g. prefetching
+    Low,
+    #[default]
+    Normal,
+    /// Requests the user waits for
+    High,
 }
 
-impl RequestEntry {
-    fn set_response(self, message: String) {
-        let mut state = self.future_state.lock().unwrap();
+/// Options of [`Connection::request_with`].
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RequestOptions {
+    /// More urgent requests are sent first when the websocket opens and are admitted first
+    /// over [`ConnectionConfig::max_in_flight`]. Requests already sent are not affected.
+    pub(crate) priority: Priority,
+    /// Maximal number of times the request is made again after a retryable failure
+    /// (see [`RetryOn`]), waiting for [`ConnectionConfig::backoff`] delay before each retry.
+    pub(crate) retries: u8,
+    pub(crate) retry_on: RetryOn,
+    /// Whether the server may handle the request more than once, so it can be retried
+    /// even if it might have been received already.
+    pub(crate) idempotent: bool,
+}
 
-        state.response_message = Some(message);
-        if let Some(waker) = state.waker.take() {
-            waker.wake();
+impl RequestOptions {
+    fn should_retry(&self, error: &RequestError) -> bool {
+        match error {
+            RequestError::QueueFull | RequestError::TooManyRequests => true,
+            RequestError::ConnectionLost | RequestError::MaxRetriesExceeded => {
+                self.idempotent && self.retry_on == RetryOn::Transient
+            }
+            _ => false,
         }
     }
 }
 
-struct ResponseFuture {
-    state: Arc<Mutex<ResponseFutureState>>,
+/// Failures after which requests are retried, see [`RequestOptions::retries`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum RetryOn {
+    /// Failures after which the request has definitely not been written to the websocket
+    /// ([`RequestError::QueueFull`] and [`RequestError::TooManyRequests`])
+    #[default]
+    NotSent,
+    /// Also failures after which the server may have received the request
+    /// ([`RequestError::ConnectionLost`] and [`RequestError::MaxRetriesExceeded`]).
+    /// Applies to [`RequestOptions::idempotent`] requests only.
+    Transient,
 }
 
-struct ResponseFutureState {
-    response_message: Option<String>,
-    waker: Option<Waker>,
+/// Handling of notifications ([`Connection::notify`]) sent while the websocket is not open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum NotifyPolicy {
+    /// Try to send right away, fail if the websocket is not open
+    #[default]
+    SendNow,
+    /// Queue until the websocket is opened (the queue is limited by
+    /// [`ConnectionConfig::queue_limit`] and [`ConnectionConfig::queue_policy`])
+    Queue,
 }
 
-impl Future for ResponseFuture {
-    type Output = String;
+/// Delays between reconnection attempts.
+#[derive(Clone, Debug)]
+pub(crate) struct BackoffPolicy {
+    /// Delay before the first attempt.
+    pub(crate) initial: Duration,
+    /// Maximal delay between attempts.
+    pub(crate) max: Duration,
+    /// Each delay is longer than the previous one by this factor.
+    pub(crate) multiplier: f64,
+    /// Randomize delays (between half and full length), so clients do not reconnect at once.
+    pub(crate) jitter: bool,
+    /// Give up after so many failed attempts in a row. Retry forever if `None`.
+    pub(crate) max_retries: Option<u32>,
+    /// Delays start from `initial` again once the connection stays open for this long.
+    pub(crate) stable_after: Duration,
+}
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut state = self.state.lock().unwrap();
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(16),
+            multiplier: 2.0,
+            jitter: true,
+            max_retries: None,
+            stable_after: Duration::from_secs(10),
+        }
+    }
+}
 
-        if let Some(message) = state.response_message.take() {
-            Poll::Ready(message)
-        } else {
-            state.waker = Some(cx.waker().clone());
-            Poll::Pending
+impl BackoffPolicy {
+    /// Returns delay (in milliseconds) after `attempt` failed attempts.
+    fn delay(&self, attempt: u32) -> u32 {
+        let exponent = i32::try_from(attempt).unwrap_or(i32::MAX);
+        let delay = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        let mut delay = delay.min(self.max.as_secs_f64()) * 1000.0;
+        if self.jitter {
+            delay *= 0.5 + platform::random() / 2.0;
+        }
+        delay as u32
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(30),
+            ping_frame: "PING".to_owned(),
+            pong_frame: "PONG".to_owned(),
+            pong_deadline: Duration::from_secs(65),
+            backoff: BackoffPolicy::default(),
+            max_in_flight: None,
+            admission_policy: AdmissionPolicy::default(),
+            queue_limit: 256,
+            queue_policy: QueuePolicy::default(),
+            max_replays: None,
+            auth_provider: None,
+            framing: Framing::default(),
+            on_orphan_response: None,
+            promote_low_after: Duration::from_secs(10),
+            tap_preview_length: 200,
+            tap_buffer: 100,
+            protocols: Vec::new(),
+            max_frame_size: None,
+            max_reassembly_size: 16 * 1024 * 1024,
+            reassembly_timeout: Duration::from_secs(30),
         }
     }
 }
+
+#[derive(Clone)]
+pub(crate) struct Connection {
+    data: Arc<Mutex<ConnectionData>>,
+}
+
+pub(crate) struct ConnectionData {
+    url: String,
+    websocket: Box<dyn Transport>,
+    /// Creates the transport instead of a [`WebSocket`], e.g. `MockTransport`
+    transport_factory: Option<TransportFactory>,
+    /// Schedules the next reconnection attempt
+    reconnector: Option<TaskHandle>,
+    /// Number of reconnection attempts since the connection was last stable
+    reconnect_attempt: u32,
+    /// Resets `reconnect_attempt` once the connection is stable
+    stabilizer: Option<TaskHandle>,
+    state: ConnectionState,
+    config: ConnectionConfig,
+    /// Encodes and decodes textual frames
+    codec: Rc<dyn Codec>,
+    /// Sends `Msg::Heartbeat` while the connection is open
+    heartbeat: Option<TaskHandle>,
+    /// Time (in milliseconds) when the last frame has been received or the connection opened
+    last_received: f64,
+    /// Re-encodes pending requests replayed after reconnection
+    replay_encoder: Option<RequestEncoder>,
+    reconnect_hook: Option<ReconnectHook>,
+
+    /// Allocates request ids of the current epoch (see `epoch_ids()`)
+    next_free_id: NextId,
+    /// Incremented each time a new websocket is created
+    epoch: u32,
+    requests: HashMap<u64, RequestEntry>,
+    /// Ids handed out by `Connection::request_traced()` for requests not made yet
+    reserved_ids: HashSet<u64>,
+    /// Allocates ping ids (from `PING_ID_START`), so they do not collide with requests
+    next_ping_id: NextId,
+    /// Pending pings - they are not replayed after reconnection
+    pings: HashMap<u64, RequestEntry>,
+    /// Requests removed by `retain_requests()`, failed once the lock is released
+    cancelled_requests: Vec<RequestEntry>,
+    /// Ids of requests made while the websocket was not open, in order.
+    /// They are sent when it is opened.
+    outgoing: VecDeque<u64>,
+    /// Notifications sent with `NotifyPolicy::Queue` while the websocket was not open, in order
+    outgoing_notifications: VecDeque<String>,
+    /// Wakers of requests waiting for a free slot
+    /// and of `shutdown()` waiting for pending requests.
+    /// They are woken by `release()` whenever requests are removed.
+    slot_wakers: Vec<Waker>,
+    /// Whether a request has been removed since `slot_wakers` were last woken
+    slots_freed: bool,
+    /// Requests waiting for a free slot, in order they were made
+    admission_queue: VecDeque<Admission>,
+    next_ticket: u64,
+    /// Set by `Connection::shutdown()` until the connection is opened again
+    shutdown: Option<Shared<LocalBoxFuture<'static, ShutdownReport>>>,
+
+    /// Subscribers of `raw_frames()`
+    raw_frame_senders: Vec<mpsc::UnboundedSender<String>>,
+    /// Observes frames in both directions, see `Connection::tap()`
+    tap: Tap,
+    /// Subscribers of `notifications()`
+    notification_senders: Vec<mpsc::UnboundedSender<String>>,
+    stats: ConnectionStats,
+    /// Called with frames nobody waits for, see `Connection::set_push_handler()`
+    push_handler: Option<PushHandler>,
+    /// Subscribers of `Connection::state_stream()`
+    state_senders: Vec<mpsc::UnboundedSender<ConnectionState>>,
+    /// Registered with `Connection::on_open()` & co.
+    hooks: ConnectionHooks,
+    /// Chunked frames being received, by id
+    reassemblies: HashMap<u64, Reassembly>,
+}
+
+/// Oversized frame received in chunks, see [`Frame::Chunk`].
+struct Reassembly {
+    /// Parts received so far, by their sequence numbers
+    parts: BTreeMap<usize, String>,
+    total: usize,
+    /// Length of received parts (in bytes)
+    size: usize,
+    started_at: f64,
+}
+
+/// Upper bounds (exclusive, in milliseconds) of all but the last bucket
+/// of [`ConnectionStats::latency_histogram`].
+pub(crate) const LATENCY_BUCKETS_MS: [f64; 4] = [10.0, 50.0, 200.0, 1000.0];
+
+/// Counters of events on a [`Connection`], since its creation.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ConnectionStats {
+    /// Requests made (including queued ones and pings)
+    pub(crate) requests: u64,
+    /// Responses to pending requests
+    pub(crate) responses: u64,
+    /// Reconnection attempts
+    pub(crate) reconnects: u64,
+    /// Numbers of responses by their latency (since the request was made), see [`LATENCY_BUCKETS_MS`]
+    pub(crate) latency_histogram: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    /// Frames which could not be parsed (and were ignored)
+    pub(crate) malformed_frames: u64,
+    /// Responses to requests which were not pending, e.g. cancelled or duplicate ones
+    pub(crate) orphan_responses: u64,
+    /// Notifications received while nobody was subscribed
+    pub(crate) dropped_notifications: u64,
+    /// Chunked frames discarded because they were incomplete, too long
+    /// or not completed in time, see [`ConnectionConfig::max_reassembly_size`]
+    pub(crate) discarded_chunked: u64,
+    /// Requests (including replayed ones) which failed to be written to the websocket
+    pub(crate) send_failures: u64,
+    /// Requests made again after a retryable failure, see [`RequestOptions::retries`]
+    pub(crate) retries: u64,
+    /// Requests currently waiting for the websocket to open or for a free slot,
+    /// indexed by [`Priority`] (low, normal, high)
+    pub(crate) queued_by_priority: [u64; 3],
+}
+
+impl ConnectionStats {
+    fn record_latency(&mut self, latency: f64) {
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| latency < bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_histogram[bucket] += 1;
+    }
+}
+
+/// Configures and creates a [`Connection`].
+pub(crate) struct ConnectionBuilder {
+    url: String,
+    replay_encoder: Option<RequestEncoder>,
+    config: ConnectionConfig,
+    codec: Option<Rc<dyn Codec>>,
+    transport_factory: Option<TransportFactory>,
+}
+
+impl ConnectionBuilder {
+    #[allow(dead_code)]
+    pub(crate) fn config(mut self, config: ConnectionConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// See [`Connection::set_replay_encoder`].
+    #[allow(dead_code)]
+    pub(crate) fn replay_encoder(mut self, encoder: RequestEncoder) -> Self {
+        self.replay_encoder = Some(encoder);
+        self
+    }
+
+    /// Sets format of frames, instead of [`ConnectionConfig::framing`].
+    #[allow(dead_code)]
+    pub(crate) fn codec(mut self, codec: Rc<dyn Codec>) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Uses transports created by `factory` instead of websockets, e.g. `MockTransport`.
+    #[allow(dead_code)]
+    pub(crate) fn transport(mut self, factory: TransportFactory) -> Self {
+        self.transport_factory = Some(factory);
+        self
+    }
+
+    // `ConnectionData` is not `Send`, connections live on the main thread
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub(crate) fn build(self, orders: &mut impl Orders<Msg>) -> Connection {
+        let framing = self.config.framing;
+        let tap = Tap::new(&self.config);
+        Connection {
+            data: Arc::new(Mutex::new(ConnectionData {
+                websocket: create_transport(
+                    &self.url,
+                    &self.config.protocols,
+                    self.transport_factory.as_ref(),
+                    orders,
+                ),
+                transport_factory: self.transport_factory,
+                url: self.url,
+                reconnector: None,
+                reconnect_attempt: 0,
+                stabilizer: None,
+                state: ConnectionState::Connecting,
+                config: self.config,
+                codec: self.codec.unwrap_or_else(|| Rc::new(framing)),
+                heartbeat: None,
+                last_received: now(),
+                replay_encoder: self.replay_encoder,
+                reconnect_hook: None,
+
+                next_free_id: NextId::new(epoch_ids(0)),
+                epoch: 0,
+                requests: HashMap::new(),
+                reserved_ids: HashSet::new(),
+                next_ping_id: NextId::new(PING_ID_START..RESERVED_ID_START),
+                pings: HashMap::new(),
+                cancelled_requests: Vec::new(),
+                outgoing: VecDeque::new(),
+                outgoing_notifications: VecDeque::new(),
+                slot_wakers: Vec::new(),
+                slots_freed: false,
+                admission_queue: VecDeque::new(),
+                next_ticket: 0,
+                shutdown: None,
+
+                raw_frame_senders: Vec::new(),
+                tap,
+                notification_senders: Vec::new(),
+                stats: ConnectionStats::default(),
+                push_handler: None,
+                state_senders: Vec::new(),
+                hooks: ConnectionHooks::default(),
+                reassemblies: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl ConnectionData {
+    /// Removes the request, freeing its slot (waiting tasks are woken by `release()`).
+    fn remove_request(&mut self, id: u64) -> Option<RequestEntry> {
+        let entry = self.requests.remove(&id);
+        self.slots_freed |= entry.is_some();
+        entry
+    }
+
+    /// Removes all requests, freeing their slots (waiting tasks are woken by `release()`).
+    fn take_requests(&mut self) -> HashMap<u64, RequestEntry> {
+        self.slots_freed |= !self.requests.is_empty();
+        std::mem::take(&mut self.requests)
+    }
+
+    /// Stores part of a chunked frame, see [`Frame::Chunk`].
+    fn add_chunk(&mut self, id: u64, seq: usize, total: usize, part: String) {
+        self.discard_stale_reassemblies();
+        let max_size = self.config.max_reassembly_size;
+        // Each part is at least a byte long
+        if seq == 0 || seq > total || total > max_size {
+            platform::error(&format!("Invalid chunk: {id} {seq}/{total}"));
+            self.stats.malformed_frames += 1;
+            return;
+        }
+        let reassembly = self.reassemblies.entry(id).or_insert_with(|| Reassembly {
+            parts: BTreeMap::new(),
+            total,
+            size: 0,
+            started_at: now(),
+        });
+        reassembly.size += part.len();
+        reassembly.parts.insert(seq, part);
+        if reassembly.total != total || reassembly.size > max_size {
+            platform::error(&format!(
+                "Discarding chunked frame {id}, it is inconsistent or too long"
+            ));
+            self.reassemblies.remove(&id);
+            self.stats.discarded_chunked += 1;
+        }
+    }
+
+    /// Returns the whole chunked frame with `id`, if all of its parts have been received.
+    fn finish_chunks(&mut self, id: u64) -> Option<String> {
+        let Some(reassembly) = self.reassemblies.remove(&id) else {
+            platform::error(&format!("End of unknown chunked frame {id}"));
+            self.stats.discarded_chunked += 1;
+            return None;
+        };
+        if reassembly.parts.len() != reassembly.total {
+            platform::error(&format!("Discarding incomplete chunked frame {id}"));
+            self.stats.discarded_chunked += 1;
+            return None;
+        }
+        Some(reassembly.parts.into_values().collect())
+    }
+
+    /// Discards chunked frames not completed within `reassembly_timeout`.
+    fn discard_stale_reassemblies(&mut self) {
+        let timeout = self.config.reassembly_timeout.as_secs_f64() * 1000.0;
+        let now = now();
+        let count = self.reassemblies.len();
+        self.reassemblies
+            .retain(|_, reassembly| now - reassembly.started_at < timeout);
+        self.stats.discarded_chunked += (count - self.reassemblies.len()) as u64;
+    }
+
+    /// Keeps only pending requests for which `keep` returns `true`, e.g. to drop outdated ones
+    /// before replay. Futures of removed requests resolve with [`RequestError::Cancelled`].
+    #[allow(dead_code)]
+    pub(crate) fn retain_requests(&mut self, mut keep: impl FnMut(u64, &Payload) -> bool) {
+        for (id, entry) in std::mem::take(&mut self.requests) {
+            if keep(id, &entry.payload) {
+                self.requests.insert(id, entry);
+            } else {
+                self.slots_freed = true;
+                self.cancelled_requests.push(entry);
+            }
+        }
+    }
+
+    /// Sends a frame as is, e.g. to authenticate before pending requests are replayed.
+    pub(crate) fn send_frame(&self, frame: &str) -> Result<(), RequestError> {
+        send_message(frame, &*self.websocket, &self.tap).map_err(RequestError::Send)
+    }
+
+    /// Sends requests queued while the websocket was not open (in order),
+    /// then replays older ones and sends queued notifications.
+    ///
+    /// Returns requests which exceeded `max_replays`, to be failed once the lock is released.
+    fn send_pending(&mut self) -> Vec<RequestEntry> {
+        let queued: HashSet<u64> = self.outgoing.iter().copied().collect();
+        // More urgent requests go first, the order within a priority is kept
+        let mut outgoing = Vec::from(std::mem::take(&mut self.outgoing));
+        outgoing
+            .sort_by_key(|id| Reverse(self.requests.get(id).map(|entry| self.priority_of(entry))));
+        self.outgoing = outgoing.into();
+        let mut flushed = true;
+        while let Some(id) = self.outgoing.front().copied() {
+            if let Some(entry) = self.requests.get_mut(&id) {
+                entry.sent_at = send_payload(
+                    id,
+                    &entry.payload,
+                    None,
+                    &*self.codec,
+                    self.config.max_frame_size,
+                    &*self.websocket,
+                    &self.tap,
+                )
+                .is_ok()
+                .then(now);
+                if entry.sent_at.is_none() {
+                    self.stats.send_failures += 1;
+                    // Wait for the next open
+                    flushed = false;
+                    break;
+                }
+            }
+            self.outgoing.pop_front();
+        }
+        let mut exhausted = Vec::new();
+        if flushed {
+            if let Some(max_replays) = self.config.max_replays {
+                let ids: Vec<u64> = self
+                    .requests
+                    .iter()
+                    .filter(|(id, entry)| !queued.contains(id) && entry.replays >= max_replays)
+                    .map(|(id, _)| *id)
+                    .collect();
+                exhausted.extend(ids.into_iter().filter_map(|id| self.remove_request(id)));
+            }
+            let mut replays: Vec<u64> = self
+                .requests
+                .keys()
+                .filter(|id| !queued.contains(id))
+                .copied()
+                .collect();
+            // Requests made at the same time keep the order of their ids
+            replays.sort_by(|a_id, b_id| {
+                let (a, b) = (&self.requests[a_id], &self.requests[b_id]);
+                (self.priority_of(b).cmp(&self.priority_of(a)))
+                    .then(a.created_at.total_cmp(&b.created_at))
+                    .then(a_id.cmp(b_id))
+            });
+            let mut replayed = 0;
+            for id in replays {
+                let entry = self.requests.get_mut(&id).unwrap();
+                entry.replays += 1;
+                let encoder = self.replay_encoder.as_ref();
+                entry.sent_at = send_payload(
+                    id,
+                    &entry.payload,
+                    encoder,
+                    &*self.codec,
+                    self.config.max_frame_size,
+                    &*self.websocket,
+                    &self.tap,
+                )
+                .is_ok()
+                .then(now);
+                if entry.sent_at.is_some() {
+                    replayed += 1;
+                } else {
+                    self.stats.send_failures += 1;
+                }
+            }
+            if replayed > 0 {
+                platform::log(&format!("Replayed requests: {replayed}"));
+            }
+            while let Some(message) = self.outgoing_notifications.front() {
+                let frame = self.codec.encode_notification(message);
+                if send_message(frame, &*self.websocket, &self.tap).is_err() {
+                    self.stats.send_failures += 1;
+                    break;
+                }
+                self.outgoing_notifications.pop_front();
+            }
+        }
+        exhausted
+    }
+
+    fn priority_of(&self, entry: &RequestEntry) -> Priority {
+        self.effective_priority(entry.priority, entry.created_at)
+    }
+
+    /// Priority of a request made at `created_at`, raised for low priority ones
+    /// waiting for longer than `ConnectionConfig::promote_low_after`.
+    fn effective_priority(&self, priority: Priority, created_at: f64) -> Priority {
+        let promote_after = self.config.promote_low_after.as_secs_f64() * 1000.0;
+        if priority == Priority::Low && now() - created_at >= promote_after {
+            Priority::Normal
+        } else {
+            priority
+        }
+    }
+
+    /// Ticket of the waiting request to be admitted next -
+    /// the most urgent one, the oldest one among equally urgent.
+    fn next_admission(&self) -> Option<u64> {
+        self.admission_queue
+            .iter()
+            .min_by_key(|admission| {
+                let priority = self.effective_priority(admission.priority, admission.created_at);
+                (Reverse(priority), admission.ticket)
+            })
+            .map(|admission| admission.ticket)
+    }
+
+    /// Changes the state and reports the change to `Connection::state_stream()` subscribers.
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+        self.state_senders
+            .retain(|sender| sender.unbounded_send(state).is_ok());
+    }
+
+    /// Updates stats with response to the request with `id`, or to no pending one.
+    fn record_response(&mut self, id: u64, entry: Option<&RequestEntry>) {
+        match entry {
+            Some(entry) => {
+                self.stats.responses += 1;
+                self.stats.record_latency(now() - entry.created_at);
+            }
+            None => {
+                self.stats.orphan_responses += 1;
+                if id < PING_ID_START && id >> 32 != u64::from(self.epoch) {
+                    platform::warn(&format!(
+                        "Ignoring response to request {id} of a previous connection"
+                    ));
+                } else {
+                    platform::debug(&format!("Response to unknown request {id}"));
+                }
+            }
+        }
+    }
+
+    /// Starts a new epoch, so requests made from now on get ids distinct from
+    /// ids of requests sent over previous websockets.
+    fn next_epoch(&mut self) {
+        self.epoch = (self.epoch + 1) % EPOCH_COUNT;
+        self.next_free_id = NextId::new(epoch_ids(self.epoch));
+    }
+
+    /// Broadcasts notification to all subscribers or drops it if there are none.
+    fn push_notification(&mut self, notification: String) {
+        self.notification_senders
+            .retain(|sender| sender.unbounded_send(notification.clone()).is_ok());
+        if self.notification_senders.is_empty() && self.push_handler.is_none() {
+            self.stats.dropped_notifications += 1;
+        }
+    }
+}
+
+/// Runs when the last [`Connection`] clone is dropped. Nobody can hold the lock then
+/// (locking requires a clone), so pending requests are failed without locking.
+impl Drop for ConnectionData {
+    fn drop(&mut self) {
+        // Normal closure
+        let _ = self.websocket.close(Some(1000), None);
+        let requests = std::mem::take(&mut self.requests);
+        let pings = std::mem::take(&mut self.pings);
+        for entry in requests.into_values().chain(pings.into_values()) {
+            entry.set_error(|| RequestError::Closed);
+        }
+        std::mem::take(&mut self.slot_wakers)
+            .into_iter()
+            .for_each(Waker::wake);
+    }
+}
+
+impl Connection {
+    /// Shorthand for `Connection::builder(url).build(orders)`.
+    #[allow(dead_code)]
+    pub(crate) fn new(url: &str, orders: &mut impl Orders<Msg>) -> Self {
+        Self::builder(url).build(orders)
+    }
+
+    pub(crate) fn builder(url: &str) -> ConnectionBuilder {
+        ConnectionBuilder {
+            url: url.to_owned(),
+            replay_encoder: None,
+            config: ConnectionConfig::default(),
+            codec: None,
+            transport_factory: None,
+        }
+    }
+
+    pub(crate) fn update(msg: Msg, model: &mut Self, orders: &mut impl Orders<Msg>) {
+        let mut effects = Vec::new();
+        Self::handle(msg, model, &mut effects);
+        model.apply(effects, orders);
+    }
+
+    /// Handles `msg`, collecting what should be ordered into `effects`.
+    /// Orders may run application's code using the connection, so they are given
+    /// by [`Connection::apply`] once the lock is released.
+    fn handle(msg: Msg, model: &mut Self, effects: &mut Vec<Effect>) {
+        let mut data = model.data.lock().unwrap();
+        if matches!(msg, Msg::Closed) {
+            let hooks = data.hooks.on_close.clone();
+            run_hooks(hooks, effects, |hook| hook());
+        }
+        match msg {
+            Msg::Failed | Msg::Closed => {
+                // Heartbeats are resumed once the connection is opened again
+                data.heartbeat = None;
+                data.stabilizer = None;
+                let pings = std::mem::take(&mut data.pings);
+                if !pings.is_empty() {
+                    // Fail them after the lock is released
+                    effects.push(Effect::Perform(
+                        async move {
+                            for entry in pings.into_values() {
+                                entry.set_error(|| RequestError::ConnectionLost);
+                            }
+                        }
+                        .boxed_local(),
+                    ));
+                }
+                if data.reconnector.is_some() || data.state.is_terminal() || data.shutdown.is_some()
+                {
+                    return;
+                }
+                let policy = &data.config.backoff;
+                if policy
+                    .max_retries
+                    .is_some_and(|max_retries| data.reconnect_attempt >= max_retries)
+                {
+                    data.set_state(ConnectionState::Failed);
+                    data.outgoing.clear();
+                    data.outgoing_notifications.clear();
+                    let requests = data.take_requests();
+                    release(data);
+                    for entry in requests.into_values() {
+                        entry.set_error(|| RequestError::ConnectionLost);
+                    }
+                    effects.push(Effect::Send(Msg::GaveUp));
+                    return;
+                }
+                let delay = policy.delay(data.reconnect_attempt);
+                data.set_state(ConnectionState::Reconnecting);
+                let attempt = data.reconnect_attempt + 1;
+                let hooks = data.hooks.on_reconnect_scheduled.clone();
+                run_hooks(hooks, effects, move |hook| hook(attempt));
+                effects.push(Effect::Reconnect { delay });
+            }
+            Msg::Reconnect => {
+                if data.state.is_terminal() {
+                    return;
+                }
+                data.reconnector = None;
+                data.reconnect_attempt += 1;
+                data.stats.reconnects += 1;
+                data.next_epoch();
+                effects.push(Effect::ReopenTransport);
+                effects.push(Effect::Send(Msg::Reconnecting {
+                    attempt: data.reconnect_attempt,
+                }));
+            }
+            Msg::Opened => {
+                if !data.config.protocols.is_empty() && data.websocket.protocol().is_empty() {
+                    platform::error(&format!(
+                        "Server has chosen none of protocols: {:?}",
+                        data.config.protocols
+                    ));
+                    let _ = data.websocket.close(None, None);
+                    effects.push(Effect::Send(Msg::ProtocolRejected));
+                    effects.push(Effect::Send(Msg::Failed));
+                    return;
+                }
+                if let Some(hook) = data.reconnect_hook.clone() {
+                    hook(&mut data);
+                }
+                let hooks = data.hooks.on_open.clone();
+                run_hooks(hooks, effects, |hook| hook());
+                data.reconnector = None;
+                data.last_received = now();
+                let interval =
+                    u32::try_from(data.config.heartbeat_interval.as_millis()).unwrap_or(u32::MAX);
+                effects.push(Effect::Heartbeat { interval });
+                if data.reconnect_attempt > 0 {
+                    effects.push(Effect::Send(Msg::Reconnected));
+                    // A connection dropped right after opening keeps backing off
+                    let after = u32::try_from(data.config.backoff.stable_after.as_millis())
+                        .unwrap_or(u32::MAX);
+                    effects.push(Effect::Stabilize { after });
+                }
+                if let Some(auth_provider) = data.config.auth_provider.clone() {
+                    data.set_state(ConnectionState::Authenticating);
+                    if let Err(error) = data.send_frame(&auth_provider()) {
+                        platform::error(&format!("Failed to send authentication frame: {error:?}"));
+                    }
+                    // Pending requests are sent once the server accepts the credentials
+                    release_and_fail(data, Vec::new());
+                    return;
+                }
+                data.set_state(ConnectionState::Open);
+                let exhausted = data.send_pending();
+                release_and_fail(data, exhausted);
+            }
+            Msg::Received(packet) => {
+                data.tap.text(FrameDirection::In, &packet);
+                data.last_received = now();
+                data.raw_frame_senders
+                    .retain(|sender| sender.unbounded_send(packet.clone()).is_ok());
+                if packet == data.config.pong_frame {
+                    return;
+                }
+                let codec = Rc::clone(&data.codec);
+                let (rid, result) = match codec.decode(&packet) {
+                    Ok(Frame::Response { id, result }) => (id, result),
+                    Ok(Frame::Part { id, chunk }) => {
+                        match data.requests.get(&id) {
+                            Some(RequestEntry {
+                                chunks: Some(sender),
+                                ..
+                            }) => {
+                                let _ = sender.unbounded_send(chunk);
+                            }
+                            Some(_) => {
+                                platform::error(&format!(
+                                    "Partial response to request {id} which is not streamed"
+                                ));
+                                data.stats.malformed_frames += 1;
+                            }
+                            // E.g. the part arrived after the final frame
+                            None => data.record_response(id, None),
+                        }
+                        return;
+                    }
+                    Ok(Frame::Chunk {
+                        id,
+                        seq,
+                        total,
+                        part,
+                    }) => {
+                        data.add_chunk(id, seq, total, part);
+                        return;
+                    }
+                    Ok(Frame::ChunkEnd { id }) => {
+                        let Some(frame) = data.finish_chunks(id) else {
+                            return;
+                        };
+                        drop(data);
+                        // Handled like a frame received as a whole
+                        Self::handle(Msg::Received(frame), model, effects);
+                        return;
+                    }
+                    Ok(Frame::Notification(content)) => {
+                        data.push_notification(content);
+                        let push_handler = data.push_handler.clone();
+                        // The handler may use the connection
+                        drop(data);
+                        if let Some(push_handler) = push_handler {
+                            push_handler(packet.clone());
+                        }
+                        return;
+                    }
+                    // Reply to a notification (e.g. from an echo server) - nobody waits for it.
+                    Ok(Frame::NoReply) => return,
+                    Ok(Frame::AuthOk) => {
+                        if data.state != ConnectionState::Authenticating {
+                            platform::error(&format!("Unexpected authentication frame: {packet}"));
+                            return;
+                        }
+                        data.set_state(ConnectionState::Open);
+                        let exhausted = data.send_pending();
+                        release_and_fail(data, exhausted);
+                        return;
+                    }
+                    Ok(Frame::AuthErr(reason)) => {
+                        // Reconnecting with the same credentials is pointless
+                        drop(data);
+                        model.close_with(|| RequestError::AuthFailed(reason.clone()));
+                        return;
+                    }
+                    Err(error) => {
+                        platform::error(&format!("Malformed frame ({error:?}): {packet}"));
+                        data.stats.malformed_frames += 1;
+                        return;
+                    }
+                };
+                if rid >= RESERVED_ID_START {
+                    match ReservedId::from_id(rid) {
+                        Some(ReservedId::KeepAlive) => {}
+                        Some(ReservedId::GoAway) => {
+                            platform::log("Server is going away, reconnecting");
+                            // Reconnected by `Msg::Closed`
+                            let _ = data.websocket.close(None, None);
+                        }
+                        None => {
+                            platform::error(&format!("Frame with unknown reserved id: {packet}"));
+                            data.stats.malformed_frames += 1;
+                        }
+                    }
+                    return;
+                }
+                let entry = data.remove_request(rid).or_else(|| data.pings.remove(&rid));
+                data.record_response(rid, entry.as_ref());
+                let push_handler = entry.is_none().then(|| data.push_handler.clone()).flatten();
+                let on_orphan_response = data.config.on_orphan_response.clone();
+                release(data);
+                if let Some(push_handler) = push_handler {
+                    push_handler(packet.clone());
+                }
+                match (entry, result) {
+                    (Some(entry), _) if entry.raw => entry.set_response(Payload::Text(packet)),
+                    (Some(entry), Ok(content)) => entry.set_response(Payload::Text(content)),
+                    (Some(entry), Err(message)) => {
+                        entry.set_error(|| RequestError::Server(message.clone()))
+                    }
+                    (None, Ok(content) | Err(content)) => {
+                        report_orphan(on_orphan_response, rid, Payload::Text(content), effects);
+                    }
+                }
+            }
+            Msg::ReceivedBytes(packet) => {
+                data.tap.binary(FrameDirection::In, &packet);
+                data.last_received = now();
+                let (rid, result) = match data.codec.decode_binary(&packet) {
+                    Ok(BinaryFrame { id, result }) => (id, result),
+                    Err(error) => {
+                        platform::error(&format!("Malformed binary frame ({error:?}): {packet:?}"));
+                        data.stats.malformed_frames += 1;
+                        return;
+                    }
+                };
+                let entry = data.remove_request(rid);
+                data.record_response(rid, entry.as_ref());
+                let on_orphan_response = data.config.on_orphan_response.clone();
+                release(data);
+                match (entry, result) {
+                    (Some(entry), Ok(body)) => entry.set_response(Payload::Bytes(body)),
+                    (Some(entry), Err(message)) => {
+                        entry.set_error(|| RequestError::Server(message.clone()))
+                    }
+                    (None, Ok(body)) => {
+                        report_orphan(on_orphan_response, rid, Payload::Bytes(body), effects);
+                    }
+                    (None, Err(message)) => {
+                        report_orphan(on_orphan_response, rid, Payload::Text(message), effects);
+                    }
+                }
+            }
+            Msg::Heartbeat => {
+                data.discard_stale_reassemblies();
+                let deadline = data.config.pong_deadline.as_secs_f64() * 1000.0;
+                if now() - data.last_received > deadline {
+                    // The websocket may be dead without being closed (e.g. behind a proxy)
+                    platform::error(&format!(
+                        "No frame received within {:?}",
+                        data.config.pong_deadline
+                    ));
+                    let _ = data.websocket.close(None, None);
+                    data.heartbeat = None;
+                    effects.push(Effect::Send(Msg::Failed));
+                } else {
+                    let _ = send_message(&data.config.ping_frame, &*data.websocket, &data.tap);
+                }
+            }
+            Msg::Stable => {
+                data.stabilizer = None;
+                data.reconnect_attempt = 0;
+            }
+            Msg::Reconnecting { .. } | Msg::Reconnected | Msg::GaveUp | Msg::ProtocolRejected => {}
+        }
+    }
+
+    fn apply(&self, effects: Vec<Effect>, orders: &mut impl Orders<Msg>) {
+        for effect in effects {
+            debug_assert!(
+                self.data.try_lock().is_ok(),
+                "Orders must not be given while the connection is locked"
+            );
+            match effect {
+                Effect::Send(msg) => {
+                    orders.send_msg(msg);
+                }
+                Effect::Perform(cmd) => {
+                    orders.perform_cmd(cmd);
+                }
+                Effect::Reconnect { delay } => {
+                    let reconnector = platform::perform_cmd_with_handle(
+                        orders,
+                        platform::timeout(delay).map(|()| Msg::Reconnect),
+                    );
+                    self.data.lock().unwrap().reconnector = Some(reconnector);
+                }
+                Effect::Heartbeat { interval } => {
+                    let heartbeat = platform::stream_with_handle(
+                        orders,
+                        platform::interval(interval).map(|()| Msg::Heartbeat),
+                    );
+                    self.data.lock().unwrap().heartbeat = Some(heartbeat);
+                }
+                Effect::Stabilize { after } => {
+                    let stabilizer = platform::perform_cmd_with_handle(
+                        orders,
+                        platform::timeout(after).map(|()| Msg::Stable),
+                    );
+                    self.data.lock().unwrap().stabilizer = Some(stabilizer);
+                }
+                Effect::ReopenTransport => self.reopen_transport(orders),
+            }
+        }
+    }
+
+    /// Replaces the websocket with a new one. Chunked frames being received are discarded,
+    /// their rest would arrive over the old websocket.
+    ///
+    /// The transport is created without holding the lock, as creating it gives orders.
+    fn reopen_transport(&self, orders: &mut impl Orders<Msg>) {
+        let data = self.data.lock().unwrap();
+        let url = data.url.clone();
+        let protocols = data.config.protocols.clone();
+        let factory = data.transport_factory.clone();
+        drop(data);
+        let websocket = create_transport(&url, &protocols, factory.as_ref(), orders);
+        let mut data = self.data.lock().unwrap();
+        data.websocket = websocket;
+        data.reassemblies.clear();
+    }
+
+    /// Sends request and resolves with its response (or server's error).
+    pub(crate) fn request(
+        &self,
+        message: &str,
+    ) -> impl Future<Output = Result<String, RequestError>> {
+        self.request_with(message, RequestOptions::default())
+    }
+
+    /// Like [`Connection::request`], but with custom options.
+    ///
+    /// Retried requests keep their id (unless it has been reused meanwhile),
+    /// so a late response to an earlier attempt completes the request as well.
+    /// Resolves with the first response or the last error.
+    pub(crate) fn request_with(
+        &self,
+        message: &str,
+        options: RequestOptions,
+    ) -> impl Future<Output = Result<String, RequestError>> {
+        let payload = Payload::Text(message.to_owned());
+        if options.retries == 0 {
+            return self
+                .start_request(payload, options.priority)
+                .map(|response| response.map(Payload::into_text))
+                .left_future();
+        }
+        let connection = self.clone();
+        async move {
+            let mut id = None;
+            let mut attempt = 0;
+            loop {
+                let (request_id, response) = connection
+                    .start_request_as(
+                        payload.clone(),
+                        options.priority,
+                        id,
+                        RequestKind::default(),
+                    )
+                    .await;
+                id = request_id.or(id);
+                match response {
+                    Err(error) if attempt < options.retries && options.should_retry(&error) => {
+                        let delay = {
+                            let mut data = connection.data.lock().unwrap();
+                            data.stats.retries += 1;
+                            data.config.backoff.delay(u32::from(attempt))
+                        };
+                        attempt += 1;
+                        platform::timeout(delay).await;
+                    }
+                    response => return response.map(Payload::into_text),
+                }
+            }
+        }
+        .right_future()
+    }
+
+    /// Like [`Connection::request`], but waits for a free slot
+    /// even with [`AdmissionPolicy::FailFast`].
+    #[allow(dead_code)]
+    pub(crate) fn request_backpressured(
+        &self,
+        message: &str,
+    ) -> impl Future<Output = Result<String, RequestError>> {
+        let admission = AdmissionFuture::new(&self.data, Priority::default());
+        let connection = self.clone();
+        let message = message.to_owned();
+        async move {
+            admission.await;
+            let (_id, _sent, response) = connection.send_request(Payload::Text(message));
+            response.await.map(Payload::into_text)
+        }
+    }
+
+    /// Measures round-trip time (in milliseconds) of a ping frame (`{id}|PING`),
+    /// which the server should answer like a request.
+    ///
+    /// Pings are not replayed after reconnection - a ping pending when the connection
+    /// is lost resolves with [`RequestError::ConnectionLost`].
+    #[allow(dead_code)]
+    pub(crate) fn ping(&self) -> impl Future<Output = Result<f64, RequestError>> {
+        let state = ResponseFutureState::new();
+        let data = &mut *self.data.lock().unwrap();
+
+        let id = data
+            .next_ping_id
+            .allocate(|id| data.pings.contains_key(&id));
+
+        let sent_at = now();
+        if let Err(error) = send_message(
+            data.codec.encode_request(id, PING_CONTENT),
+            &*data.websocket,
+            &data.tap,
+        ) {
+            return Either::Right(future::ready(Err(error.into())));
+        }
+        data.stats.requests += 1;
+        data.pings.insert(
+            id,
+            RequestEntry {
+                payload: Payload::Text(PING_CONTENT.to_owned()),
+                future_states: vec![state.clone()],
+                sent_at: Some(sent_at),
+                replays: 0,
+                chunks: None,
+                created_at: now(),
+                dedup_key: None,
+                raw: false,
+                plain: false,
+                priority: Priority::default(),
+            },
+        );
+
+        let response = ResponseFuture {
+            state,
+            id,
+            connection: Arc::downgrade(&self.data),
+        };
+        Either::Left(response.map(move |response| response.map(|_| now() - sent_at)))
+    }
+
+    /// Like [`Connection::request`], but sends `request` serialized to JSON
+    /// and deserializes the response from JSON.
+    #[allow(dead_code)]
+    pub(crate) fn request_json<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        request: &Req,
+    ) -> impl Future<Output = Result<Resp, RequestError>> {
+        let response = serde_json::to_string(request).map(|message| self.request(&message));
+        async move {
+            let response = response.map_err(RequestError::Encode)?.await?;
+            serde_json::from_str(&response).map_err(RequestError::Decode)
+        }
+    }
+
+    /// Like [`Connection::request_json`], but `request` and the response are serialized
+    /// to MessagePack and sent in binary frames (see [`Connection::request_bytes`]).
+    /// Maps are encoded with field names, so the schema can evolve.
+    #[allow(dead_code)]
+    pub(crate) fn request_msgpack<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        request: &Req,
+    ) -> impl Future<Output = Result<Resp, RequestError>> {
+        let response = rmp_serde::to_vec_named(request).map(|payload| self.request_bytes(&payload));
+        async move {
+            let response = response.map_err(RequestError::MsgPackEncode)?.await?;
+            rmp_serde::from_slice(&response).map_err(RequestError::MsgPackDecode)
+        }
+    }
+
+    /// Like [`Connection::request`], but sends a binary frame (prefixed by little-endian
+    /// request id) and expects a binary response.
+    pub(crate) fn request_bytes(
+        &self,
+        payload: &[u8],
+    ) -> impl Future<Output = Result<Vec<u8>, RequestError>> {
+        self.start_request(Payload::Bytes(payload.to_vec()), Priority::default())
+            .map(|response| response.map(Payload::into_bytes))
+    }
+
+    /// Like [`Connection::request`], but also returns the id of the request
+    /// (e.g. to correlate it in logs). The id is allocated right away,
+    /// even if the request waits for a free slot.
+    ///
+    /// Unlike [`Connection::request`], the request is not kept for replay after reconnection
+    /// if it could not be sent - the future resolves with [`RequestError::Send`] instead.
+    #[allow(dead_code)]
+    pub(crate) fn request_traced(
+        &self,
+        message: &str,
+    ) -> (u64, impl Future<Output = Result<String, RequestError>>) {
+        let id = {
+            let data = &mut *self.data.lock().unwrap();
+            let id = data
+                .next_free_id
+                .allocate(|id| data.requests.contains_key(&id) || data.reserved_ids.contains(&id));
+            // Not handed out again while the request waits for a free slot
+            data.reserved_ids.insert(id);
+            id
+        };
+        let reservation = IdReservation {
+            id,
+            connection: Arc::downgrade(&self.data),
+        };
+        let kind = RequestKind {
+            no_replay: true,
+            ..RequestKind::default()
+        };
+        let response = self
+            .start_request_as(
+                Payload::Text(message.to_owned()),
+                Priority::default(),
+                Some(id),
+                kind,
+            )
+            .map(move |(_id, response)| {
+                drop(reservation);
+                response.map(Payload::into_text)
+            });
+        (id, response)
+    }
+
+    /// Like [`Connection::request`], but the server may send parts of the response
+    /// (`{id}|PART|{chunk}` frames) before the final one (`{id}|END|{payload}`).
+    ///
+    /// Returns stream of the parts and the final response. The stream ends
+    /// when the request is resolved.
+    #[allow(dead_code)]
+    pub(crate) fn request_streaming(
+        &self,
+        message: &str,
+    ) -> (
+        impl Stream<Item = String>,
+        impl Future<Output = Result<String, RequestError>>,
+    ) {
+        let (sender, receiver) = mpsc::unbounded();
+        let kind = RequestKind {
+            chunks: Some(sender),
+            ..RequestKind::default()
+        };
+        let response = self
+            .start_request_as(
+                Payload::Text(message.to_owned()),
+                Priority::default(),
+                None,
+                kind,
+            )
+            .map(|(_id, response)| response.map(Payload::into_text));
+        (receiver, response)
+    }
+
+    /// Sends the request, or waits for a free slot first (see [`AdmissionPolicy`]).
+    fn start_request(
+        &self,
+        payload: Payload,
+        priority: Priority,
+    ) -> LocalBoxFuture<'static, Result<Payload, RequestError>> {
+        self.start_request_as(payload, priority, None, RequestKind::default())
+            .map(|(_id, response)| response)
+            .boxed_local()
+    }
+
+    /// Like [`Connection::start_request`], but reuses `id` unless it is in use.
+    /// Resolves with the id of the request as well (`None` if it has not been made).
+    fn start_request_as(
+        &self,
+        payload: Payload,
+        priority: Priority,
+        id: Option<u64>,
+        kind: RequestKind,
+    ) -> LocalBoxFuture<'static, (Option<u64>, Result<Payload, RequestError>)> {
+        let data = self.data.lock().unwrap();
+        // Requests waiting already go first
+        let full = data.config.max_in_flight.is_some_and(|max_in_flight| {
+            !data.admission_queue.is_empty() || data.requests.len() >= max_in_flight
+        });
+        let policy = data.config.admission_policy;
+        drop(data);
+
+        if !full {
+            // Requests which could not be sent are replayed after reconnection
+            let (id, _sent, response) = self.send_request_with(payload, priority, id, kind);
+            return response
+                .map(move |response| (Some(id), response))
+                .boxed_local();
+        }
+        match policy {
+            AdmissionPolicy::FailFast => {
+                future::ready((None, Err(RequestError::TooManyRequests))).boxed_local()
+            }
+            AdmissionPolicy::Wait => {
+                let admission = AdmissionFuture::new(&self.data, priority);
+                let connection = self.clone();
+                async move {
+                    admission.await;
+                    let (id, _sent, response) =
+                        connection.send_request_with(payload, priority, id, kind);
+                    (Some(id), response.await)
+                }
+                .boxed_local()
+            }
+        }
+    }
+
+    /// Registers request under a new id and sends it.
+    /// Requests made while the websocket is not open are queued (see [`QueuePolicy`]).
+    fn send_request(&self, payload: Payload) -> (u64, Result<(), WebSocketError>, ResponseFuture) {
+        self.send_request_with(payload, Priority::default(), None, RequestKind::default())
+    }
+
+    /// Like [`Connection::send_request`], reusing `id` unless it is in use.
+    fn send_request_with(
+        &self,
+        payload: Payload,
+        priority: Priority,
+        id: Option<u64>,
+        kind: RequestKind,
+    ) -> (u64, Result<(), WebSocketError>, ResponseFuture) {
+        let state = ResponseFutureState::new();
+
+        let mut guard = self.data.lock().unwrap();
+        let data = &mut *guard;
+
+        let id = match id {
+            Some(id) if !data.requests.contains_key(&id) => {
+                data.reserved_ids.remove(&id);
+                id
+            }
+            _ => data
+                .next_free_id
+                .allocate(|id| data.requests.contains_key(&id) || data.reserved_ids.contains(&id)),
+        };
+
+        if data.state.is_terminal() {
+            // Resolve right away instead of writing to a dead websocket
+            let error = match data.state {
+                ConnectionState::Failed => RequestError::ConnectionLost,
+                _ => RequestError::Closed,
+            };
+            return (id, Ok(()), ResponseFuture::failed(id, error));
+        }
+        if data.shutdown.is_some() {
+            return (
+                id,
+                Ok(()),
+                ResponseFuture::failed(id, RequestError::ShuttingDown),
+            );
+        }
+
+        let mut dropped = None;
+        let sent = if data.state == ConnectionState::Open {
+            send_payload(
+                id,
+                &payload,
+                None,
+                &*data.codec,
+                data.config.max_frame_size,
+                &*data.websocket,
+                &data.tap,
+            )
+        } else {
+            if data.outgoing.len() >= data.config.queue_limit {
+                match data.config.queue_policy {
+                    QueuePolicy::RejectNew => {
+                        return (
+                            id,
+                            Ok(()),
+                            ResponseFuture::failed(id, RequestError::QueueFull),
+                        );
+                    }
+                    QueuePolicy::DropOldest => {
+                        dropped = data
+                            .outgoing
+                            .pop_front()
+                            .and_then(|oldest| data.remove_request(oldest));
+                    }
+                }
+            }
+            data.outgoing.push_back(id);
+            Ok(())
+        };
+
+        if sent.is_err() {
+            data.stats.send_failures += 1;
+        }
+        data.stats.requests += 1;
+        let sent = match sent {
+            Ok(()) => Ok(()),
+            // Requests which could not be written are replayed after reconnection
+            Err(RequestError::Send(error)) if !kind.no_replay => Err(error),
+            // ... but the ones which cannot be encoded would fail again
+            Err(error) => return (id, Ok(()), ResponseFuture::failed(id, error)),
+        };
+        data.requests.insert(
+            id,
+            RequestEntry {
+                payload,
+                future_states: vec![state.clone()],
+                sent_at: (data.state == ConnectionState::Open && sent.is_ok()).then(now),
+                replays: 0,
+                plain: kind.is_plain(),
+                chunks: kind.chunks,
+                created_at: now(),
+                dedup_key: kind.dedup_key,
+                raw: kind.raw,
+                priority,
+            },
+        );
+
+        let response = ResponseFuture {
+            state,
+            id,
+            connection: Arc::downgrade(&self.data),
+        };
+        release(guard);
+        if let Some(entry) = dropped {
+            entry.set_error(|| RequestError::QueueFull);
+        }
+        (id, sent, response)
+    }
+
+    /// Sends again pending requests which failed to be sent, in order of their ids.
+    /// Returns the number of requests sent successfully.
+    ///
+    /// Does nothing unless the connection is open - requests are sent on open anyway.
+    #[allow(dead_code)]
+    pub(crate) fn flush_unsent(&self) -> usize {
+        let data = &mut *self.data.lock().unwrap();
+        if data.state != ConnectionState::Open {
+            return 0;
+        }
+        let mut unsent: Vec<_> = data
+            .requests
+            .iter_mut()
+            .filter(|(_, entry)| entry.sent_at.is_none())
+            .collect();
+        unsent.sort_unstable_by_key(|(id, _)| **id);
+        let mut flushed = 0;
+        for (id, entry) in unsent {
+            entry.sent_at = send_payload(
+                *id,
+                &entry.payload,
+                None,
+                &*data.codec,
+                data.config.max_frame_size,
+                &*data.websocket,
+                &data.tap,
+            )
+            .is_ok()
+            .then(now);
+            if entry.sent_at.is_some() {
+                flushed += 1;
+            } else {
+                data.stats.send_failures += 1;
+            }
+        }
+        flushed
+    }
+
+    /// Sends several requests in a single frame (see [`Codec::encode_batch`])
+    /// and resolves with their responses in order of `messages`.
+    ///
+    /// Each request is pending on its own, so after reconnection
+    /// only requests without response are replayed (one by one, like other requests).
+    /// Nothing is sent if `messages` is empty. If the frame would be longer than
+    /// [`ConnectionConfig::max_frame_size`], the requests are sent one by one instead.
+    #[allow(dead_code)]
+    pub(crate) fn request_batch(
+        &self,
+        messages: Vec<String>,
+    ) -> impl Future<Output = Vec<Result<String, RequestError>>> {
+        let mut guard = self.data.lock().unwrap();
+        let data = &mut *guard;
+
+        let fits = data.config.max_in_flight.map_or(true, |max_in_flight| {
+            data.admission_queue.is_empty() && data.requests.len() + messages.len() <= max_in_flight
+        });
+        let frame = (!messages.is_empty()
+            && data.state == ConnectionState::Open
+            && data.shutdown.is_none()
+            && fits)
+            .then(|| {
+                let mut ids = Vec::with_capacity(messages.len());
+                for _ in &messages {
+                    let id = data.next_free_id.allocate(|id| {
+                        data.requests.contains_key(&id)
+                            || data.reserved_ids.contains(&id)
+                            || ids.contains(&id)
+                    });
+                    ids.push(id);
+                }
+                let requests = ids
+                    .iter()
+                    .zip(&messages)
+                    .map(|(id, message)| (*id, message.as_str()))
+                    .collect::<Vec<_>>();
+                (ids, data.codec.encode_batch(&requests))
+            })
+            // Oversized batches cannot be chunked, as chunks belong to a single request
+            .filter(|(_, frame)| {
+                data.config
+                    .max_frame_size
+                    .map_or(true, |max_frame_size| frame.len() <= max_frame_size)
+            });
+        let Some((ids, frame)) = frame else {
+            drop(guard);
+            // Requests are queued, admitted, chunked or failed one by one then
+            let responses = messages.iter().map(|message| self.request(message));
+            return future::join_all(responses.collect::<Vec<_>>()).left_future();
+        };
+
+        let batch = ids
+            .into_iter()
+            .zip(messages)
+            .map(|(id, message)| {
+                let state = ResponseFutureState::new();
+                data.requests.insert(
+                    id,
+                    RequestEntry {
+                        payload: Payload::Text(message),
+                        future_states: vec![state.clone()],
+                        sent_at: None,
+                        replays: 0,
+                        chunks: None,
+                        created_at: now(),
+                        dedup_key: None,
+                        raw: false,
+                        plain: true,
+                        priority: Priority::default(),
+                    },
+                );
+                (id, state)
+            })
+            .collect::<Vec<_>>();
+        let sent = send_message(frame, &*data.websocket, &data.tap).is_ok();
+
+        data.stats.requests += batch.len() as u64;
+        if !sent {
+            data.stats.send_failures += 1;
+        }
+        let responses = batch
+            .into_iter()
+            .map(|(id, state)| {
+                // Requests which could not be sent are replayed after reconnection
+                data.requests.get_mut(&id).unwrap().sent_at = sent.then(now);
+                ResponseFuture {
+                    state,
+                    id,
+                    connection: Arc::downgrade(&self.data),
+                }
+                .map(|response| response.map(Payload::into_text))
+            })
+            .collect::<Vec<_>>();
+        future::join_all(responses).right_future()
+    }
+
+    /// Like [`Connection::request`], but if a request with identical `message` is pending,
+    /// waits for its response instead of sending the message again.
+    /// Only requests made by [`Connection::request`] (or by this method) are joined,
+    /// not streamed, raw or traced ones.
+    #[allow(dead_code)]
+    pub(crate) fn request_deduped(
+        &self,
+        message: &str,
+    ) -> impl Future<Output = Result<String, RequestError>> {
+        let mut data = self.data.lock().unwrap();
+        let pending = data.requests.iter_mut().find(|(_, entry)| {
+            entry.plain && matches!(&entry.payload, Payload::Text(pending) if pending == message)
+        });
+
+        let future = if let Some((id, entry)) = pending {
+            let state = ResponseFutureState::new();
+            entry.future_states.push(state.clone());
+            ResponseFuture {
+                state,
+                id: *id,
+                connection: Arc::downgrade(&self.data),
+            }
+            .boxed_local()
+        } else {
+            drop(data);
+            self.start_request(Payload::Text(message.to_owned()), Priority::default())
+        };
+        future.map(|response| response.map(Payload::into_text))
+    }
+
+    /// Like [`Connection::request`], but resolves with the whole response frame
+    /// (including its id and status, also for server's errors), e.g. for debugging the protocol.
+    #[allow(dead_code)]
+    pub(crate) fn request_raw(
+        &self,
+        message: &str,
+    ) -> impl Future<Output = Result<String, RequestError>> {
+        let kind = RequestKind {
+            raw: true,
+            ..RequestKind::default()
+        };
+        self.start_request_as(
+            Payload::Text(message.to_owned()),
+            Priority::default(),
+            None,
+            kind,
+        )
+        .map(|(_id, response)| response.map(Payload::into_text))
+    }
+
+    /// Like [`Connection::request_deduped`], but waits for the pending request with
+    /// the same `key` (e.g. the requested resource) whatever its message is.
+    /// The key is forgotten once the request is answered or fails.
+    #[allow(dead_code)]
+    pub(crate) fn request_dedup(
+        &self,
+        key: &str,
+        message: &str,
+    ) -> impl Future<Output = Result<String, RequestError>> {
+        let mut data = self.data.lock().unwrap();
+        let pending = data
+            .requests
+            .iter_mut()
+            .find(|(_, entry)| entry.dedup_key.as_deref() == Some(key));
+
+        let response = if let Some((id, entry)) = pending {
+            let state = ResponseFutureState::new();
+            entry.future_states.push(state.clone());
+            ResponseFuture {
+                state,
+                id: *id,
+                connection: Arc::downgrade(&self.data),
+            }
+            .boxed_local()
+        } else {
+            drop(data);
+            let kind = RequestKind {
+                dedup_key: Some(key.to_owned()),
+                ..RequestKind::default()
+            };
+            self.start_request_as(
+                Payload::Text(message.to_owned()),
+                Priority::default(),
+                None,
+                kind,
+            )
+            .map(|(_id, response)| response)
+            .boxed_local()
+        };
+        response.map(|response| response.map(Payload::into_text))
+    }
+
+    /// Whether any request is waiting for its response.
+    #[allow(dead_code)]
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.data.lock().unwrap().requests.is_empty()
+    }
+
+    /// Returns ids of requests waiting for their responses.
+    #[allow(dead_code)]
+    pub(crate) fn in_flight_ids(&self) -> Vec<u64> {
+        self.data.lock().unwrap().requests.keys().copied().collect()
+    }
+
+    /// Returns stream of all received frames (including responses to requests),
+    /// as they arrived. Useful for inspecting the protocol.
+    #[allow(dead_code)]
+    pub(crate) fn raw_frames(&self) -> impl Stream<Item = String> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.data.lock().unwrap().raw_frame_senders.push(sender);
+        receiver
+    }
+
+    /// Returns stream of all frames sent and received from now on
+    /// (including frames observed while nobody was subscribed, up to [`ConnectionConfig::tap_buffer`]).
+    ///
+    /// Frames are observed only after the first call, so the tap costs nothing until used.
+    pub(crate) fn tap(&self) -> impl Stream<Item = FrameEvent> {
+        self.data.lock().unwrap().tap.subscribe()
+    }
+
+    /// Logs all frames sent and received from now on to the console, see [`Connection::tap`].
+    #[allow(dead_code)]
+    pub(crate) fn enable_console_tap(&self) {
+        let mut tap = self.tap();
+        platform::spawn_local(async move {
+            while let Some(event) = tap.next().await {
+                platform::log(&event.to_string());
+            }
+        });
+    }
+
+    /// Returns stream of notifications pushed by the server (`*|{notification}` frames),
+    /// e.g. for `orders.stream(...)`.
+    ///
+    /// Every subscriber gets all notifications received after it has subscribed.
+    /// Notifications received while nobody is subscribed are dropped
+    /// (see [`Connection::dropped_notifications`]).
+    #[allow(dead_code)]
+    pub(crate) fn notifications(&self) -> impl Stream<Item = String> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.data.lock().unwrap().notification_senders.push(sender);
+        receiver
+    }
+
+    /// Sets callback invoked with frames which are not responses to pending requests:
+    /// notifications and responses to unknown (e.g. cancelled) requests.
+    ///
+    /// The callback receives whole frames, as they arrived. It is a callback-based
+    /// alternative to [`Connection::notifications`].
+    #[allow(dead_code)]
+    pub(crate) fn set_push_handler(&self, handler: PushHandler) {
+        self.data.lock().unwrap().push_handler = Some(handler);
+    }
+
+    /// Describes requests waiting for responses (including queued ones), oldest first,
+    /// e.g. for a live table of requests.
+    #[allow(dead_code)]
+    pub(crate) fn pending(&self) -> Vec<PendingRequestInfo> {
+        let data = self.data.lock().unwrap();
+        let now = now();
+        let mut pending: Vec<_> = data
+            .requests
+            .iter()
+            .map(|(id, entry)| PendingRequestInfo {
+                id: *id,
+                message: entry.payload.clone(),
+                age_ms: now - entry.created_at,
+                sent_ms_ago: entry.sent_at.map(|sent_at| now - sent_at),
+            })
+            .collect();
+        pending.sort_by(|a, b| b.age_ms.total_cmp(&a.age_ms));
+        pending
+    }
+
+    /// Number of notifications dropped because nobody was subscribed.
+    #[allow(dead_code)]
+    pub(crate) fn dropped_notifications(&self) -> u64 {
+        self.data.lock().unwrap().stats.dropped_notifications
+    }
+
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        let data = self.data.lock().unwrap();
+        let mut stats = data.stats;
+        let queued = data.outgoing.iter().filter_map(|id| data.requests.get(id));
+        for priority in queued.map(|entry| entry.priority).chain(
+            data.admission_queue
+                .iter()
+                .map(|admission| admission.priority),
+        ) {
+            stats.queued_by_priority[priority as usize] += 1;
+        }
+        stats
+    }
+
+    /// Renders [`Connection::stats`], e.g. for a debug panel.
+    pub(crate) fn view_stats<Ms>(&self) -> Node<Ms> {
+        let stats = self.stats();
+        let buckets = LATENCY_BUCKETS_MS
+            .iter()
+            .map(|bound| format!("< {bound} ms"))
+            .chain([format!(
+                "≥ {} ms",
+                LATENCY_BUCKETS_MS[LATENCY_BUCKETS_MS.len() - 1]
+            )]);
+        div![
+            C!["connection-stats"],
+            div![format!(
+                "Requests: {}, responses: {}, retries: {}, reconnects: {}, send failures: {}",
+                stats.requests,
+                stats.responses,
+                stats.retries,
+                stats.reconnects,
+                stats.send_failures
+            )],
+            div![format!(
+                "Ignored frames: {} malformed, {} orphan responses, {} notifications, {} chunked",
+                stats.malformed_frames,
+                stats.orphan_responses,
+                stats.dropped_notifications,
+                stats.discarded_chunked
+            )],
+            div![format!(
+                "Queued: {} high, {} normal, {} low",
+                stats.queued_by_priority[Priority::High as usize],
+                stats.queued_by_priority[Priority::Normal as usize],
+                stats.queued_by_priority[Priority::Low as usize]
+            )],
+            buckets
+                .zip(stats.latency_histogram)
+                .map(|(bucket, count)| div![format!("{bucket}: {count}")]),
+        ]
+    }
+
+    /// Sets encoder used for pending requests replayed after reconnection
+    /// (e.g. to include a new session token), instead of resending them verbatim.
+    #[allow(dead_code)]
+    pub(crate) fn set_replay_encoder(&self, encoder: RequestEncoder) {
+        self.data.lock().unwrap().replay_encoder = Some(encoder);
+    }
+
+    /// Sets hook called each time the websocket is opened (including the first time),
+    /// before pending requests are replayed. It may e.g. authenticate
+    /// with [`ConnectionData::send_frame`] or prune requests with
+    /// [`ConnectionData::retain_requests`].
+    #[allow(dead_code)]
+    pub(crate) fn with_reconnect_hook(self, hook: ReconnectHook) -> Self {
+        self.data.lock().unwrap().reconnect_hook = Some(hook);
+        self
+    }
+
+    /// Registers `hook` called each time the websocket is opened (including the first time),
+    /// e.g. to resubscribe to server's topics. It runs after pending requests are replayed
+    /// and may make requests itself.
+    ///
+    /// The hook is unregistered when the returned handle is dropped.
+    #[allow(dead_code)]
+    pub(crate) fn on_open(&self, hook: LifecycleHook) -> HookHandle {
+        let mut data = self.data.lock().unwrap();
+        let id = data.hooks.next_id();
+        data.hooks.on_open.push((id, hook));
+        self.hook_handle(HookKind::Open, id)
+    }
+
+    /// Registers `hook` called each time the websocket is closed, e.g. to clear
+    /// optimistic UI. It is called for failed attempts to open the websocket as well.
+    ///
+    /// The hook is unregistered when the returned handle is dropped.
+    #[allow(dead_code)]
+    pub(crate) fn on_close(&self, hook: LifecycleHook) -> HookHandle {
+        let mut data = self.data.lock().unwrap();
+        let id = data.hooks.next_id();
+        data.hooks.on_close.push((id, hook));
+        self.hook_handle(HookKind::Close, id)
+    }
+
+    /// Registers `hook` called with number of the attempt each time reconnection
+    /// is scheduled (after the connection has been lost).
+    ///
+    /// The hook is unregistered when the returned handle is dropped.
+    #[allow(dead_code)]
+    pub(crate) fn on_reconnect_scheduled(&self, hook: ReconnectScheduledHook) -> HookHandle {
+        let mut data = self.data.lock().unwrap();
+        let id = data.hooks.next_id();
+        data.hooks.on_reconnect_scheduled.push((id, hook));
+        self.hook_handle(HookKind::ReconnectScheduled, id)
+    }
+
+    fn hook_handle(&self, kind: HookKind, id: u64) -> HookHandle {
+        HookHandle {
+            kind,
+            id,
+            connection: Arc::downgrade(&self.data),
+        }
+    }
+
+    /// Reconnects right away, without waiting for the backoff delay.
+    pub(crate) fn reconnect_now(&self, orders: &mut impl Orders<Msg>) {
+        let mut data = self.data.lock().unwrap();
+        data.reconnector = None;
+        data.set_state(ConnectionState::Reconnecting);
+        data.reconnect_attempt = 0;
+        drop(data);
+        orders.send_msg(Msg::Reconnect);
+    }
+
+    /// Reconnects after reconnecting has been given up ([`ConnectionState::Failed`]),
+    /// counting failed attempts from zero again. Does nothing in other states.
+    #[allow(dead_code)]
+    pub(crate) fn retry(&self, orders: &mut impl Orders<Msg>) {
+        if self.state() == ConnectionState::Failed {
+            self.reconnect_now(orders);
+        }
+    }
+
+    /// Subprotocol chosen by the server from [`ConnectionConfig::protocols`],
+    /// `None` until the websocket is opened (or if no protocols were offered).
+    #[allow(dead_code)]
+    pub(crate) fn negotiated_protocol(&self) -> Option<String> {
+        let protocol = self.data.lock().unwrap().websocket.protocol();
+        (!protocol.is_empty()).then_some(protocol)
+    }
+
+    pub(crate) fn state(&self) -> ConnectionState {
+        self.data.lock().unwrap().state
+    }
+
+    /// Stream of the following changes of [`Connection::state`],
+    /// e.g. for showing "reconnecting..." to the user.
+    pub(crate) fn state_stream(&self) -> impl Stream<Item = ConnectionState> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.data.lock().unwrap().state_senders.push(sender);
+        receiver
+    }
+
+    /// Closes the connection deliberately (e.g. on logout), so it is not reconnected.
+    ///
+    /// Pending requests fail with [`RequestError::Closed`], as well as all requests
+    /// made until the connection is opened again with [`Connection::open`].
+    pub(crate) fn close(&self) {
+        self.close_with(|| RequestError::Closed);
+    }
+
+    /// Stops reconnecting (e.g. when the user has chosen to work offline),
+    /// closing the connection like [`Connection::close`]. Does nothing unless reconnecting.
+    #[allow(dead_code)]
+    pub(crate) fn stop_reconnecting(&self) {
+        if self.state() == ConnectionState::Reconnecting {
+            self.close();
+        }
+    }
+
+    fn close_with(&self, error: impl Fn() -> RequestError) {
+        let mut data = self.data.lock().unwrap();
+        data.set_state(ConnectionState::Closed);
+        data.reconnector = None;
+        data.heartbeat = None;
+        data.stabilizer = None;
+        // Normal closure
+        let _ = data.websocket.close(Some(1000), None);
+
+        data.outgoing.clear();
+        data.outgoing_notifications.clear();
+        let requests = data.take_requests();
+        let pings = std::mem::take(&mut data.pings);
+        release(data);
+        for entry in requests.into_values().chain(pings.into_values()) {
+            entry.set_error(&error);
+        }
+    }
+
+    /// Switches the connection to another server (e.g. on failover) right away.
+    ///
+    /// Pending requests are replayed on the new websocket, unless `drop_pending` is set -
+    /// then they fail with [`RequestError::Cancelled`]. Does nothing if `url` is the current one.
+    /// A closed connection is not opened, but [`Connection::open`] uses the new url.
+    #[allow(dead_code)]
+    pub(crate) fn set_url(&self, url: &str, drop_pending: bool, orders: &mut impl Orders<Msg>) {
+        let mut data = self.data.lock().unwrap();
+        if data.url == url {
+            return;
+        }
+        url.clone_into(&mut data.url);
+        if data.state.is_terminal() {
+            return;
+        }
+        data.reconnector = None;
+        data.heartbeat = None;
+        data.stabilizer = None;
+        // Normal closure
+        let _ = data.websocket.close(Some(1000), None);
+        data.set_state(ConnectionState::Reconnecting);
+        data.next_epoch();
+
+        let pings = std::mem::take(&mut data.pings);
+        let requests = if drop_pending {
+            data.outgoing.clear();
+            data.take_requests()
+        } else {
+            HashMap::new()
+        };
+        release(data);
+        self.reopen_transport(orders);
+        for entry in pings.into_values() {
+            entry.set_error(|| RequestError::ConnectionLost);
+        }
+        for entry in requests.into_values() {
+            entry.set_error(|| RequestError::Cancelled);
+        }
+    }
+
+    /// Opens the connection again after [`Connection::close`].
+    #[allow(dead_code)]
+    pub(crate) fn open(&self, orders: &mut impl Orders<Msg>) {
+        let mut data = self.data.lock().unwrap();
+        if !data.state.is_terminal() {
+            return;
+        }
+        data.set_state(ConnectionState::Connecting);
+        data.reconnect_attempt = 0;
+        data.shutdown = None;
+        data.next_epoch();
+        drop(data);
+        self.reopen_transport(orders);
+    }
+
+    /// Resolves when no request is pending, i.e. all have been resolved or failed
+    /// (right away if none is pending now).
+    pub(crate) fn flush(&self) -> impl Future<Output = ()> {
+        DrainFuture {
+            connection: Arc::downgrade(&self.data),
+        }
+    }
+
+    /// Closes the connection gracefully (e.g. on logout): new requests fail with
+    /// [`RequestError::ShuttingDown`] right away, while pending ones have `grace` time
+    /// to complete. Then the connection is closed like by [`Connection::close`].
+    ///
+    /// The shutdown proceeds even if the returned future is not awaited.
+    /// Calling it again while shutting down (or once closed) returns the same outcome.
+    #[allow(dead_code)]
+    pub(crate) fn shutdown(&self, grace: Duration) -> impl Future<Output = ShutdownReport> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(shutdown) = &data.shutdown {
+            return shutdown.clone();
+        }
+        data.reconnector = None;
+        data.heartbeat = None;
+        data.stabilizer = None;
+        let pending = data.requests.len();
+
+        let connection = self.clone();
+        let shutdown = async move {
+            let drained = connection.flush();
+            let ms = u32::try_from(grace.as_millis()).unwrap_or(u32::MAX);
+            future::select(Box::pin(drained), Box::pin(platform::timeout(ms))).await;
+            let abandoned = connection.data.lock().unwrap().requests.len();
+            connection.close();
+            ShutdownReport {
+                completed: pending.saturating_sub(abandoned),
+                abandoned,
+            }
+        }
+        .boxed_local()
+        .shared();
+        data.shutdown = Some(shutdown.clone());
+        drop(data);
+
+        platform::spawn_local(shutdown.clone().map(drop));
+        shutdown
+    }
+
+    /// Sends a one-way message (`-|{message}` frame) that does not expect any response.
+    ///
+    /// Unlike [`Connection::request`], nothing is registered for the message,
+    /// so it is not replayed after reconnection. Messages queued with [`NotifyPolicy::Queue`]
+    /// are sent once the websocket is opened (after requests).
+    pub(crate) fn notify(&self, message: &str, policy: NotifyPolicy) -> Result<(), RequestError> {
+        let mut data = self.data.lock().unwrap();
+        match (data.state, policy) {
+            (ConnectionState::Closed, _) => Err(RequestError::Closed),
+            (ConnectionState::Failed, _) => Err(RequestError::ConnectionLost),
+            (ConnectionState::Open, _) | (_, NotifyPolicy::SendNow) => {
+                let frame = data.codec.encode_notification(message);
+                send_message(frame, &*data.websocket, &data.tap).map_err(RequestError::Send)
+            }
+            (_, NotifyPolicy::Queue) => {
+                if data.outgoing_notifications.len() >= data.config.queue_limit {
+                    match data.config.queue_policy {
+                        QueuePolicy::RejectNew => return Err(RequestError::QueueFull),
+                        QueuePolicy::DropOldest => {
+                            data.outgoing_notifications.pop_front();
+                        }
+                    }
+                }
+                data.outgoing_notifications.push_back(message.to_owned());
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends the frame as is, without any id.
+    ///
+    /// Meant for protocol control frames (e.g. subscribe commands) which do not
+    /// follow the `{id}|{content}` framing. Replies can be observed
+    /// with [`Connection::raw_frames`].
+    #[allow(dead_code)]
+    pub(crate) fn send_raw(&self, frame: &str) -> Result<(), WebSocketError> {
+        let data = self.data.lock().unwrap();
+        send_message(frame, &*data.websocket, &data.tap)
+    }
+}
+
+//------------------------------------------------------------------------------
+// Connection pool
+//------------------------------------------------------------------------------
+
+/// Message of a [`ConnectionPool`], tagged with the name of the connection.
+pub(crate) type PoolMsg = (String, Msg);
+
+/// Connections to several endpoints, addressed by name.
+///
+/// Each connection keeps its own backoff, requests and configuration.
+#[derive(Default)]
+pub(crate) struct ConnectionPool {
+    connections: HashMap<String, Connection>,
+}
+
+impl ConnectionPool {
+    /// Opens connections with default configuration to all `(name, url)` endpoints.
+    pub(crate) fn new(endpoints: &[(&str, &str)], orders: &mut impl Orders<PoolMsg>) -> Self {
+        let mut pool = Self::default();
+        for (name, url) in endpoints {
+            pool.insert(name, Connection::builder(url), orders);
+        }
+        pool
+    }
+
+    /// Opens connection built by `builder`, e.g. with custom configuration.
+    pub(crate) fn insert(
+        &mut self,
+        name: &str,
+        builder: ConnectionBuilder,
+        orders: &mut impl Orders<PoolMsg>,
+    ) {
+        assert!(
+            !self.connections.contains_key(name),
+            "Connection named {name} is already in the pool"
+        );
+        let tag = name.to_owned();
+        let connection = builder.build(&mut orders.proxy(move |msg| (tag, msg)));
+        self.connections.insert(name.to_owned(), connection);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Connection> {
+        self.connections.get(name)
+    }
+
+    /// Passes message to the connection it is tagged with.
+    pub(crate) fn update(&mut self, (name, msg): PoolMsg, orders: &mut impl Orders<PoolMsg>) {
+        match self.connections.get_mut(&name) {
+            Some(connection) => {
+                Connection::update(msg, connection, &mut orders.proxy(move |msg| (name, msg)));
+            }
+            None => platform::error(&format!("Message for unknown connection: {name}")),
+        }
+    }
+
+    /// Closes all connections, see [`Connection::close`].
+    #[allow(dead_code)]
+    pub(crate) fn close_all(&self) {
+        self.connections.values().for_each(Connection::close);
+    }
+
+    /// Returns state of each connection, by name.
+    #[allow(dead_code)]
+    pub(crate) fn states(&self) -> BTreeMap<&str, ConnectionState> {
+        self.connections
+            .iter()
+            .map(|(name, connection)| (name.as_str(), connection.state()))
+            .collect()
+    }
+}
+
+//------------------------------------------------------------------------------
+// Operations on raw websockets
+//------------------------------------------------------------------------------
+
+fn create_transport(
+    url: &str,
+    protocols: &[String],
+    factory: Option<&TransportFactory>,
+    orders: &mut impl Orders<Msg>,
+) -> Box<dyn Transport> {
+    match factory {
+        Some(factory) => factory(url, protocols),
+        None => Box::new(create_websocket(url, protocols, orders)),
+    }
+}
+
+fn create_websocket(url: &str, protocols: &[String], orders: &mut impl Orders<Msg>) -> WebSocket {
+    let msg_sender = orders.msg_sender();
+    let protocols = protocols.iter().map(String::as_str).collect::<Vec<_>>();
+
+    WebSocket::builder(url, orders)
+        .protocols(&protocols)
+        .on_open(|| Msg::Opened)
+        .on_message(move |msg| decode_message(msg, msg_sender))
+        .on_close(|_| Msg::Closed)
+        .on_error(|| Msg::Failed)
+        .build_and_open()
+        .unwrap()
+}
+
+fn decode_message(message: WebSocketMessage, msg_sender: Rc<dyn Fn(Option<Msg>)>) {
+    if message.contains_text() {
+        msg_sender(Some(Msg::Received(message.text().unwrap())));
+    } else {
+        // Reading binary data is asynchronous
+        platform::spawn_local(async move {
+            match message.bytes().await {
+                Ok(bytes) => msg_sender(Some(Msg::ReceivedBytes(bytes))),
+                Err(error) => platform::error(&format!("Failed to read binary frame: {error:?}")),
+            }
+        });
+    }
+}
+
+/// Releases the lock, waking tasks waiting for a free slot (or for pending requests
+/// to complete) if any request has been removed meanwhile.
+fn release(mut data: MutexGuard<ConnectionData>) {
+    let slot_wakers = if std::mem::take(&mut data.slots_freed) {
+        std::mem::take(&mut data.slot_wakers)
+    } else {
+        Vec::new()
+    };
+    // Woken tasks may issue another request right away, so do not hold the lock.
+    drop(data);
+    slot_wakers.into_iter().for_each(Waker::wake);
+}
+
+/// Releases the lock, then fails requests removed by the reconnect hook and `exhausted` ones.
+fn release_and_fail(mut data: MutexGuard<ConnectionData>, exhausted: Vec<RequestEntry>) {
+    let cancelled = std::mem::take(&mut data.cancelled_requests);
+    release(data);
+    for entry in cancelled {
+        entry.set_error(|| RequestError::Cancelled);
+    }
+    for entry in exhausted {
+        entry.set_error(|| RequestError::MaxRetriesExceeded);
+    }
+}
+
+/// Returns current time in milliseconds.
+fn now() -> f64 {
+    platform::now().unwrap_or(0.0)
+}
+
+/// Decoded textual frame received from the server.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Frame {
+    /// Final response to a request: its payload or error message from the server
+    Response {
+        id: u64,
+        result: Result<String, String>,
+    },
+    /// Part of a streamed response, see [`Connection::request_streaming`]
+    Part { id: u64, chunk: String },
+    /// `seq`-th (starting at 1) of `total` parts of an oversized frame
+    Chunk {
+        id: u64,
+        seq: usize,
+        total: usize,
+        part: String,
+    },
+    /// Follows all parts of an oversized frame, which is handled as a whole then
+    ChunkEnd { id: u64 },
+    /// Pushed by the server, see [`Connection::notifications`]
+    Notification(String),
+    /// Reply to a message sent by [`Connection::notify`] which nobody waits for
+    NoReply,
+    /// The server has accepted credentials
+    AuthOk,
+    /// The server has rejected credentials
+    AuthErr(String),
+}
+
+/// Textual frame of [`Framing`], borrowing from the received frame.
+#[derive(Debug, PartialEq, Eq)]
+enum PipeFrame<'a> {
+    /// `{id}|{content}`
+    Response { id: u64, content: &'a str },
+    /// `*|{content}` or a frame without any id
+    Notification(&'a str),
+    /// `-|{content}`, i.e. reply to a frame sent by `Connection::notify()`
+    NoReply,
+    /// `AUTH_OK`
+    AuthOk,
+    /// `AUTH_ERR|{reason}`
+    AuthErr(&'a str),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum FrameError {
+    Empty,
+    /// The id is neither a valid `u64` (in the configured radix) nor a special id
+    InvalidId(String),
+    /// The frame is not a valid envelope of the codec (e.g. [`JsonEnvelopeCodec`])
+    InvalidEnvelope(String),
+    /// Sequence number or count of parts of a chunk frame is missing or invalid
+    InvalidChunk(String),
+    /// Binary frame of given length is shorter than the id prefix
+    TooShort(usize),
+    /// Textual frame received by a binary-only codec, see [`MsgPackCodec::binary_only`]
+    UnexpectedText,
+}
+
+/// Response to a binary request, see [`Codec::decode_binary`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BinaryFrame {
+    pub(crate) id: u64,
+    /// Payload or error message from the server
+    pub(crate) result: Result<Vec<u8>, String>,
+}
+
+/// Returns range of ids of requests made in the given epoch: `(epoch << 32) | counter`.
+fn epoch_ids(epoch: u32) -> std::ops::Range<u64> {
+    let start = u64::from(epoch) << 32;
+    start..start + (1 << 32)
+}
+
+/// Sends frame of a request, encoding textual payload with `encoder` (if set).
+fn send_payload(
+    id: u64,
+    payload: &Payload,
+    encoder: Option<&RequestEncoder>,
+    codec: &dyn Codec,
+    max_frame_size: Option<usize>,
+    websocket: &dyn Transport,
+    tap: &Tap,
+) -> Result<(), RequestError> {
+    match payload {
+        Payload::Text(message) => {
+            let request = match encoder {
+                Some(encoder) => encoder(id, message),
+                None => codec.encode_request(id, message),
+            };
+            match max_frame_size {
+                Some(max_frame_size) if request.len() > max_frame_size => {
+                    send_chunked(id, &request, max_frame_size, codec, websocket, tap)?
+                }
+                _ => send_message(request, websocket, tap)?,
+            }
+        }
+        Payload::Bytes(bytes) => {
+            send_bytes(&codec.encode_binary_request(id, bytes)?, websocket, tap)?
+        }
+    }
+    Ok(())
+}
+
+/// Sends oversized `frame` of the request with `id` as chunk frames of at most
+/// `max_frame_size` bytes (plus escaping by the codec), followed by the end frame.
+fn send_chunked(
+    id: u64,
+    frame: &str,
+    max_frame_size: usize,
+    codec: &dyn Codec,
+    websocket: &dyn Transport,
+    tap: &Tap,
+) -> Result<(), WebSocketError> {
+    // The number of parts is not known yet, but it cannot have more digits than this
+    let header_length = codec.encode_chunk(id, frame.len(), frame.len(), "").len();
+    if header_length >= max_frame_size {
+        return Err(WebSocketError::TextError(
+            "max_frame_size is too small to fit chunk headers",
+        ));
+    }
+    let part_length = max_frame_size - header_length;
+    let mut parts = Vec::new();
+    let mut rest = frame;
+    while !rest.is_empty() {
+        let mut end = part_length.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            // A single character is longer than the part
+            end = rest.chars().next().map_or(0, char::len_utf8);
+        }
+        let (part, tail) = rest.split_at(end);
+        parts.push(part);
+        rest = tail;
+    }
+    for (i, part) in parts.iter().enumerate() {
+        send_message(
+            codec.encode_chunk(id, i + 1, parts.len(), part),
+            websocket,
+            tap,
+        )?;
+    }
+    send_message(codec.encode_chunk_end(id), websocket, tap)
+}
+
+fn send_message(
+    message: impl AsRef<str>,
+    websocket: &dyn Transport,
+    tap: &Tap,
+) -> Result<(), WebSocketError> {
+    tap.text(FrameDirection::Out, message.as_ref());
+    websocket.send_text(message.as_ref())
+}
+
+fn send_bytes(frame: &[u8], websocket: &dyn Transport, tap: &Tap) -> Result<(), WebSocketError> {
+    tap.binary(FrameDirection::Out, frame);
+    websocket.send_bytes(frame)
+}
+
+//------------------------------------------------------------------------------
+// Tracking requests
+//------------------------------------------------------------------------------
+
+/// Content of a request or response, either textual or binary.
+#[derive(Clone, Debug)]
+pub(crate) enum Payload {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Payload {
+    fn is_bytes(&self) -> bool {
+        matches!(self, Payload::Bytes(_))
+    }
+
+    fn into_text(self) -> String {
+        match self {
+            Payload::Text(text) => text,
+            Payload::Bytes(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Payload::Text(text) => text.into_bytes(),
+            Payload::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+struct RequestEntry {
+    /// Request's payload without framing, so it can be encoded again on replay.
+    /// Its type is also the type of the expected response.
+    payload: Payload,
+    /// States of all futures waiting for the response
+    future_states: Vec<Arc<Mutex<ResponseFutureState>>>,
+    /// Time (in milliseconds) the request was last written to the websocket,
+    /// `None` if it has not been written yet or writing has failed.
+    /// Queued requests and requests which failed to be sent are resent
+    /// on the next open or by [`Connection::flush_unsent`].
+    sent_at: Option<f64>,
+    /// Number of times the request has been replayed after reconnection
+    replays: u32,
+    /// Receives parts of a streamed response (`{id}|PART|{chunk}` frames),
+    /// see [`Connection::request_streaming`]
+    chunks: Option<mpsc::UnboundedSender<String>>,
+    /// Time of the request's creation (in milliseconds), for latency stats
+    created_at: f64,
+    /// Key of the request made by [`Connection::request_dedup`]
+    dedup_key: Option<String>,
+    /// Whether the request resolves with the whole frame, see [`Connection::request_raw`]
+    raw: bool,
+    /// Whether the request has been made with [`RequestKind::default`],
+    /// so [`Connection::request_deduped`] can wait for its response
+    plain: bool,
+    priority: Priority,
+}
+
+/// Settings of a request which differ between `request_*` methods of [`Connection`],
+/// applied to its [`RequestEntry`] once it is admitted.
+#[derive(Default)]
+struct RequestKind {
+    chunks: Option<mpsc::UnboundedSender<String>>,
+    dedup_key: Option<String>,
+    raw: bool,
+    /// Fail with [`RequestError::Send`] instead of keeping the request for replay
+    /// if it cannot be sent, see [`Connection::request_traced`]
+    no_replay: bool,
+}
+
+impl RequestKind {
+    fn is_plain(&self) -> bool {
+        self.chunks.is_none() && self.dedup_key.is_none() && !self.raw && !self.no_replay
+    }
+}
+
+impl RequestEntry {
+    fn set_response(self, message: Payload) {
+        if self.payload.is_bytes() != message.is_bytes() {
+            platform::error(&format!(
+                "Response type does not match request: {message:?}"
+            ));
+        }
+        self.complete(|| Ok(message.clone()));
+    }
+
+    /// Fails the request. `error` is called for each future waiting for the response.
+    fn set_error(self, error: impl Fn() -> RequestError) {
+        self.complete(|| Err(error()));
+    }
+
+    fn complete(self, result: impl Fn() -> Result<Payload, RequestError>) {
+        for future_state in self.future_states {
+            let mut state = future_state.lock().unwrap();
+
+            state.response_message = Some(result());
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Resolves with response to a request.
+///
+/// Dropping the future before the response arrives cancels the request
+/// (unless other futures wait for the same request).
+struct ResponseFuture {
+    state: Arc<Mutex<ResponseFutureState>>,
+    id: u64,
+    connection: Weak<Mutex<ConnectionData>>,
+}
+
+struct ResponseFutureState {
+    response_message: Option<Result<Payload, RequestError>>,
+    waker: Option<Waker>,
+}
+
+impl ResponseFuture {
+    /// Creates future resolved with `error` right away.
+    fn failed(id: u64, error: RequestError) -> Self {
+        let state = ResponseFutureState::new();
+        state.lock().unwrap().response_message = Some(Err(error));
+        Self {
+            state,
+            id,
+            connection: Weak::new(),
+        }
+    }
+}
+
+impl ResponseFutureState {
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn new() -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            response_message: None,
+            waker: None,
+        }))
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Payload, RequestError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(message) = state.response_message.take() {
+            Poll::Ready(message)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        let Some(data) = self.connection.upgrade() else {
+            return;
+        };
+        let mut data = data.lock().unwrap();
+        if data.pings.remove(&self.id).is_some() {
+            // Nobody waits for the pong
+            return;
+        }
+        let Some(entry) = data.requests.get_mut(&self.id) else {
+            // The response has already arrived (or the request has been cancelled)
+            return;
+        };
+        entry
+            .future_states
+            .retain(|state| !Arc::ptr_eq(state, &self.state));
+        if entry.future_states.is_empty() {
+            // Do not send the request when the websocket opens, nor replay it after reconnection
+            let id = self.id;
+            data.outgoing.retain(|&queued| queued != id);
+            let sent = data
+                .remove_request(id)
+                .is_some_and(|entry| entry.sent_at.is_some());
+            if sent {
+                // Best effort - fails when the websocket is not open
+                let cancel = data.codec.encode_request(id, CANCEL_CONTENT);
+                let _ = send_message(cancel, &*data.websocket, &data.tap);
+            }
+            release(data);
+        }
+    }
+}
+
+/// Whether a frame observed by [`Connection::tap`] has been sent or received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FrameDirection {
+    In,
+    Out,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FrameKind {
+    Text,
+    Binary,
+}
+
+/// Frame observed by [`Connection::tap`].
+#[derive(Clone, Debug)]
+pub(crate) struct FrameEvent {
+    pub(crate) direction: FrameDirection,
+    /// Time of sending or receiving (in milliseconds, see `Performance.now()`)
+    pub(crate) at: f64,
+    /// The frame's text or hexadecimal bytes, truncated to [`ConnectionConfig::tap_preview_length`]
+    pub(crate) payload_preview: String,
+    pub(crate) kind: FrameKind,
+}
+
+impl fmt::Display for FrameEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            FrameDirection::In => "<-",
+            FrameDirection::Out => "->",
+        };
+        let kind = match self.kind {
+            FrameKind::Text => "text",
+            FrameKind::Binary => "binary",
+        };
+        write!(
+            f,
+            "[{:.0} ms] {arrow} {kind} {}",
+            self.at, self.payload_preview
+        )
+    }
+}
+
+/// Feeds subscribers of [`Connection::tap`], buffering frames while there are none.
+///
+/// Frames are not observed at all until the first subscription.
+struct Tap {
+    enabled: Cell<bool>,
+    senders: RefCell<Vec<mpsc::UnboundedSender<FrameEvent>>>,
+    buffer: RefCell<VecDeque<FrameEvent>>,
+    preview_length: usize,
+    capacity: usize,
+}
+
+impl Tap {
+    fn new(config: &ConnectionConfig) -> Self {
+        Self {
+            enabled: Cell::new(false),
+            senders: RefCell::new(Vec::new()),
+            buffer: RefCell::new(VecDeque::new()),
+            preview_length: config.tap_preview_length,
+            capacity: config.tap_buffer,
+        }
+    }
+
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<FrameEvent> {
+        self.enabled.set(true);
+        let (sender, receiver) = mpsc::unbounded();
+        for event in self.buffer.borrow_mut().drain(..) {
+            let _ = sender.unbounded_send(event);
+        }
+        self.senders.borrow_mut().push(sender);
+        receiver
+    }
+
+    fn text(&self, direction: FrameDirection, text: &str) {
+        if !self.enabled.get() {
+            return;
+        }
+        let mut preview: String = text.chars().take(self.preview_length).collect();
+        if preview.len() < text.len() {
+            preview.push('…');
+        }
+        self.record(direction, FrameKind::Text, preview);
+    }
+
+    fn binary(&self, direction: FrameDirection, bytes: &[u8]) {
+        if !self.enabled.get() {
+            return;
+        }
+        let shown = bytes.len().min(self.preview_length);
+        let mut preview = String::with_capacity(2 * shown + '…'.len_utf8());
+        for byte in &bytes[..shown] {
+            let _ = write!(preview, "{byte:02x}");
+        }
+        if bytes.len() > shown {
+            preview.push('…');
+        }
+        self.record(direction, FrameKind::Binary, preview);
+    }
+
+    fn record(&self, direction: FrameDirection, kind: FrameKind, payload_preview: String) {
+        let event = FrameEvent {
+            direction,
+            at: now(),
+            payload_preview,
+            kind,
+        };
+        let mut senders = self.senders.borrow_mut();
+        senders.retain(|sender| sender.unbounded_send(event.clone()).is_ok());
+        if senders.is_empty() && self.capacity > 0 {
+            let mut buffer = self.buffer.borrow_mut();
+            if buffer.len() == self.capacity {
+                buffer.pop_front();
+            }
+            buffer.push_back(event);
+        }
+    }
+}
+
+/// Snapshot of a pending request, see [`Connection::pending`].
+#[derive(Clone, Debug)]
+#[allow(dead_code)]
+pub(crate) struct PendingRequestInfo {
+    pub(crate) id: u64,
+    /// Request's payload, as passed to [`Connection::request`] & co.
+    pub(crate) message: Payload,
+    /// Time since the request was made
+    pub(crate) age_ms: f64,
+    /// Time since the request was last written to the websocket,
+    /// `None` while it is queued (or writing has failed)
+    pub(crate) sent_ms_ago: Option<f64>,
+}
+
+/// Outcome of [`Connection::shutdown`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ShutdownReport {
+    /// Requests which completed (or were cancelled) within the grace time
+    #[allow(dead_code)]
+    pub(crate) completed: usize,
+    /// Requests failed with [`RequestError::Closed`] after the grace time
+    #[allow(dead_code)]
+    pub(crate) abandoned: usize,
+}
+
+/// Resolves when no request is pending.
+struct DrainFuture {
+    connection: Weak<Mutex<ConnectionData>>,
+}
+
+impl Future for DrainFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(data) = self.connection.upgrade() else {
+            return Poll::Ready(());
+        };
+        let mut data = data.lock().unwrap();
+        if data.requests.is_empty() {
+            Poll::Ready(())
+        } else {
+            data.slot_wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Request waiting for a free slot.
+struct Admission {
+    ticket: u64,
+    priority: Priority,
+    created_at: f64,
+}
+
+/// Resolves when fewer than [`ConnectionConfig::max_in_flight`] requests are pending
+/// and all more urgent requests (and equally urgent ones which started waiting earlier)
+/// have been admitted.
+///
+/// The request joins the queue when the future is first polled (keeping its place
+/// among requests made earlier), so futures which are never awaited do not hold up others.
+struct AdmissionFuture {
+    ticket: u64,
+    priority: Priority,
+    queued: bool,
+    connection: Weak<Mutex<ConnectionData>>,
+    admitted: bool,
+}
+
+impl AdmissionFuture {
+    fn new(connection: &Arc<Mutex<ConnectionData>>, priority: Priority) -> Self {
+        let mut data = connection.lock().unwrap();
+        let ticket = data.next_ticket;
+        data.next_ticket += 1;
+        Self {
+            ticket,
+            priority,
+            queued: false,
+            connection: Arc::downgrade(connection),
+            admitted: false,
+        }
+    }
+
+    /// Wakes waiting requests, so the next one in the queue checks for a free slot.
+    fn wake_next(mut data: MutexGuard<ConnectionData>) {
+        let slot_wakers = std::mem::take(&mut data.slot_wakers);
+        // Woken task(s) may issue another request right away, so do not hold the lock.
+        drop(data);
+        slot_wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl Future for AdmissionFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(connection) = self.connection.upgrade() else {
+            return Poll::Ready(());
+        };
+        let mut data = connection.lock().unwrap();
+        if !self.queued {
+            self.queued = true;
+            let admission = Admission {
+                ticket: self.ticket,
+                priority: self.priority,
+                created_at: now(),
+            };
+            data.admission_queue.push_back(admission);
+        }
+        let free = data
+            .config
+            .max_in_flight
+            .map_or(true, |max_in_flight| data.requests.len() < max_in_flight);
+        if free && data.next_admission() == Some(self.ticket) {
+            data.admission_queue
+                .retain(|admission| admission.ticket != self.ticket);
+            self.admitted = true;
+            Self::wake_next(data);
+            Poll::Ready(())
+        } else {
+            data.slot_wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for AdmissionFuture {
+    fn drop(&mut self) {
+        if self.admitted || !self.queued {
+            return;
+        }
+        let Some(connection) = self.connection.upgrade() else {
+            return;
+        };
+        let mut data = connection.lock().unwrap();
+        data.admission_queue
+            .retain(|admission| admission.ticket != self.ticket);
+        Self::wake_next(data);
+    }
+}
+
+/// Releases id of [`Connection::request_traced`] if the request has not been made
+/// (e.g. it has been dropped while waiting for a free slot).
+struct IdReservation {
+    id: u64,
+    connection: Weak<Mutex<ConnectionData>>,
+}
+
+impl Drop for IdReservation {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.upgrade() {
+            connection.lock().unwrap().reserved_ids.remove(&self.id);
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// Lifecycle hooks
+//------------------------------------------------------------------------------
+
+/// Hooks registered with [`Connection::on_open`] & co., with ids of their handles.
+#[derive(Default)]
+struct ConnectionHooks {
+    on_open: Vec<(u64, LifecycleHook)>,
+    on_close: Vec<(u64, LifecycleHook)>,
+    on_reconnect_scheduled: Vec<(u64, ReconnectScheduledHook)>,
+    next_id: u64,
+}
+
+impl ConnectionHooks {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn remove(&mut self, kind: HookKind, id: u64) {
+        match kind {
+            HookKind::Open => self.on_open.retain(|(hook_id, _)| *hook_id != id),
+            HookKind::Close => self.on_close.retain(|(hook_id, _)| *hook_id != id),
+            HookKind::ReconnectScheduled => self
+                .on_reconnect_scheduled
+                .retain(|(hook_id, _)| *hook_id != id),
+        }
+    }
+}
+
+/// What [`Connection::handle`] asks of orders.
+enum Effect {
+    Send(Msg),
+    Perform(LocalBoxFuture<'static, ()>),
+    /// Schedules `Msg::Reconnect` after `delay` milliseconds
+    Reconnect {
+        delay: u32,
+    },
+    /// Starts sending `Msg::Heartbeat` every `interval` milliseconds
+    Heartbeat {
+        interval: u32,
+    },
+    /// Schedules `Msg::Stable` after `after` milliseconds
+    Stabilize {
+        after: u32,
+    },
+    ReopenTransport,
+}
+
+/// Runs `hooks` with `call` once the current update has finished.
+///
+/// Hooks may use the connection (e.g. make requests), so they must not run
+/// while its lock is held.
+fn run_hooks<H: 'static>(
+    hooks: Vec<(u64, H)>,
+    effects: &mut Vec<Effect>,
+    call: impl Fn(&H) + 'static,
+) {
+    if hooks.is_empty() {
+        return;
+    }
+    effects.push(Effect::Perform(
+        async move {
+            for (_, hook) in &hooks {
+                call(hook);
+            }
+        }
+        .boxed_local(),
+    ));
+}
+
+/// Calls `handler` (if set) with an orphan response once the current update has finished,
+/// see [`ConnectionConfig::on_orphan_response`].
+fn report_orphan(
+    handler: Option<OrphanHandler>,
+    id: u64,
+    content: Payload,
+    effects: &mut Vec<Effect>,
+) {
+    if let Some(handler) = handler {
+        effects.push(Effect::Perform(
+            async move { handler(id, content) }.boxed_local(),
+        ));
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum HookKind {
+    Open,
+    Close,
+    ReconnectScheduled,
+}
+
+/// Unregisters its hook when dropped, see [`Connection::on_open`].
+#[must_use = "The hook is unregistered when the handle is dropped"]
+pub(crate) struct HookHandle {
+    kind: HookKind,
+    id: u64,
+    connection: Weak<Mutex<ConnectionData>>,
+}
+
+impl HookHandle {
+    /// Keeps the hook registered for the lifetime of the connection.
+    #[allow(dead_code)]
+    pub(crate) fn detach(mut self) {
+        self.connection = Weak::new();
+    }
+}
+
+impl Drop for HookHandle {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.upgrade() {
+            connection.lock().unwrap().hooks.remove(self.kind, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Context;
+
+    use futures::task::{noop_waker_ref, ArcWake};
+
+    use super::*;
+    use crate::mock_orders::MockOrders;
+    use crate::platform;
+    use crate::transport::{MockTransport, SentFrame};
+
+    struct Harness {
+        connection: Connection,
+        transport: MockTransport,
+        orders: MockOrders<Msg>,
+    }
+
+    impl Harness {
+        fn new(config: ConnectionConfig) -> Self {
+            Self::with(|builder| builder.config(config))
+        }
+
+        /// Connection configured by `configure`, over a mock transport.
+        fn with(configure: impl FnOnce(ConnectionBuilder) -> ConnectionBuilder) -> Self {
+            let transport = MockTransport::new();
+            let mut orders = MockOrders::new();
+            let connection = configure(Connection::builder("ws://test"))
+                .transport(transport.factory())
+                .build(&mut orders);
+            Self {
+                connection,
+                transport,
+                orders,
+            }
+        }
+
+        /// Connection with jitter-less backoff, opened by the server.
+        fn open() -> Self {
+            let mut harness = Self::new(ConnectionConfig {
+                backoff: BackoffPolicy {
+                    jitter: false,
+                    ..BackoffPolicy::default()
+                },
+                ..ConnectionConfig::default()
+            });
+            harness.update(Msg::Opened);
+            harness
+        }
+
+        /// Passes `msg` and all messages it leads to to `Connection::update`.
+        fn update(&mut self, msg: Msg) {
+            let connection = &mut self.connection;
+            Connection::update(msg, connection, &mut self.orders);
+            self.orders
+                .settle(|msg, orders| Connection::update(msg, connection, orders));
+        }
+
+        fn receive(&mut self, frame: &str) {
+            self.update(Msg::Received(frame.to_owned()));
+        }
+
+        fn advance_time(&mut self, ms: f64) {
+            platform::advance_time(ms);
+            let connection = &mut self.connection;
+            self.orders
+                .settle(|msg, orders| Connection::update(msg, connection, orders));
+        }
+
+        /// Ids and bodies of request frames sent since the last call.
+        fn sent_requests(&self) -> Vec<(u64, String)> {
+            self.transport
+                .take_sent()
+                .into_iter()
+                .filter_map(|frame| match frame {
+                    SentFrame::Text(text) => {
+                        let (id, body) = text.split_once('|')?;
+                        Some((id.parse().ok()?, body.to_owned()))
+                    }
+                    SentFrame::Bytes(_) => None,
+                })
+                .collect()
+        }
+
+        /// Binary frames sent since the last call.
+        fn sent_bytes(&self) -> Vec<Vec<u8>> {
+            self.transport
+                .take_sent()
+                .into_iter()
+                .filter_map(|frame| match frame {
+                    SentFrame::Bytes(bytes) => Some(bytes),
+                    SentFrame::Text(_) => None,
+                })
+                .collect()
+        }
+    }
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        future.poll_unpin(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    /// Records whether it has been woken.
+    #[derive(Default)]
+    struct WakeFlag(AtomicBool);
+
+    impl ArcWake for WakeFlag {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl WakeFlag {
+        fn poll<F: Future + Unpin>(self: &Arc<Self>, future: &mut F) -> Poll<F::Output> {
+            self.0.store(false, Ordering::SeqCst);
+            let waker = futures::task::waker(Arc::clone(self));
+            future.poll_unpin(&mut Context::from_waker(&waker))
+        }
+
+        fn woken(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn request_is_answered() {
+        let mut harness = Harness::open();
+        let mut response = Box::pin(harness.connection.request("hello"));
+        assert!(poll(&mut response).is_pending());
+
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "hello");
+        harness.receive(&format!("{id}|OK|world"));
+        assert_eq!(
+            poll(&mut response).map(Result::unwrap),
+            Poll::Ready("world".into())
+        );
+        assert!(!harness.connection.has_pending());
+    }
+
+    #[test]
+    fn server_error_fails_request() {
+        let mut harness = Harness::open();
+        let mut response = Box::pin(harness.connection.request("hello"));
+        let [(id, _)] = harness.sent_requests().try_into().unwrap();
+
+        harness.receive(&format!("{id}|ERR|nope"));
+        assert!(matches!(
+            poll(&mut response),
+            Poll::Ready(Err(RequestError::Server(message))) if message == "nope"
+        ));
+    }
+
+    #[test]
+    fn requests_wait_for_open() {
+        let mut harness = Harness::new(ConnectionConfig::default());
+        let mut response = Box::pin(harness.connection.request("early"));
+        assert!(poll(&mut response).is_pending());
+        assert!(harness.sent_requests().is_empty());
+
+        harness.update(Msg::Opened);
+        assert_eq!(harness.connection.state(), ConnectionState::Open);
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "early");
+        harness.receive(&format!("{id}|OK|late"));
+        assert_eq!(
+            poll(&mut response).map(Result::unwrap),
+            Poll::Ready("late".into())
+        );
+    }
+
+    #[test]
+    fn reconnects_with_backoff_and_replays_requests() {
+        let mut harness = Harness::open();
+        let mut response = Box::pin(harness.connection.request("replayed"));
+        harness.sent_requests();
+
+        harness.update(Msg::Closed);
+        assert_eq!(harness.connection.state(), ConnectionState::Reconnecting);
+        harness.advance_time(999.0);
+        assert_eq!(harness.transport.connects(), 1);
+        harness.advance_time(1.0);
+        assert_eq!(harness.transport.connects(), 2);
+
+        harness.update(Msg::Opened);
+        assert_eq!(harness.connection.state(), ConnectionState::Open);
+        // The replayed request keeps its id
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "replayed");
+        harness.receive(&format!("{id}|OK|done"));
+        assert_eq!(
+            poll(&mut response).map(Result::unwrap),
+            Poll::Ready("done".into())
+        );
+        assert_eq!(harness.connection.stats().reconnects, 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let mut harness = Harness::new(ConnectionConfig {
+            backoff: BackoffPolicy {
+                max_retries: Some(0),
+                ..BackoffPolicy::default()
+            },
+            ..ConnectionConfig::default()
+        });
+        harness.update(Msg::Opened);
+        let mut response = Box::pin(harness.connection.request("lost"));
+
+        harness.update(Msg::Closed);
+        assert_eq!(harness.connection.state(), ConnectionState::Failed);
+        assert!(matches!(
+            poll(&mut response),
+            Poll::Ready(Err(RequestError::ConnectionLost))
+        ));
+    }
+
+    #[test]
+    fn heartbeat_pings_and_detects_dead_connection() {
+        let mut harness = Harness::open();
+
+        harness.advance_time(30_000.0);
+        assert_eq!(harness.transport.sent_text(), ["PING"]);
+        harness.receive("PONG");
+
+        // Nothing received after the pong
+        harness.advance_time(90_000.0);
+        assert_eq!(harness.transport.closed(), Some(None));
+        assert_eq!(harness.connection.state(), ConnectionState::Reconnecting);
+    }
+
+    #[test]
+    fn transport_is_created_without_lock() {
+        let transport = MockTransport::new();
+        let connection: Rc<RefCell<Option<Connection>>> = Rc::default();
+        let factory = transport.factory();
+        let checked_factory: TransportFactory = Rc::new({
+            let connection = Rc::clone(&connection);
+            move |url, protocols| {
+                if let Some(connection) = &*connection.borrow() {
+                    assert!(connection.data.try_lock().is_ok());
+                }
+                factory(url, protocols)
+            }
+        });
+        let mut harness = Harness::open();
+        harness.connection = Connection::builder("ws://test")
+            .transport(checked_factory)
+            .build(&mut harness.orders);
+        *connection.borrow_mut() = Some(harness.connection.clone());
+
+        harness.update(Msg::Opened);
+        harness.update(Msg::Closed);
+        harness.advance_time(1000.0);
+        harness
+            .connection
+            .set_url("ws://other", false, &mut harness.orders);
+        assert_eq!(transport.connects(), 3);
+        connection.take();
+    }
+
+    #[test]
+    fn notifications_are_delivered() {
+        let mut harness = Harness::open();
+        let mut notifications = harness.connection.notifications();
+
+        harness.receive("*|news");
+        assert_eq!(
+            poll(&mut notifications.next()),
+            Poll::Ready(Some("news".to_owned()))
+        );
+    }
+
+    #[test]
+    fn failing_transport_keeps_request_queued() {
+        let mut harness = Harness::open();
+        harness.transport.set_failing(true);
+        let mut response = Box::pin(harness.connection.request("retry me"));
+        assert!(harness.sent_requests().is_empty());
+        assert_eq!(harness.connection.stats().send_failures, 1);
+
+        harness.transport.set_failing(false);
+        harness.update(Msg::Closed);
+        harness.advance_time(1000.0);
+        harness.update(Msg::Opened);
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "retry me");
+        harness.receive(&format!("{id}|OK"));
+        assert!(poll(&mut response).is_ready());
+    }
+
+    #[test]
+    fn framing_rejects_ambiguous_settings() {
+        assert_eq!(Framing::new('|', 1), Err(InvalidFraming::Radix(1)));
+        assert_eq!(Framing::new('|', 37), Err(InvalidFraming::Radix(37)));
+        for delimiter in ['7', 'z', 'Z', '*', '-', '_', '/'] {
+            assert_eq!(
+                Framing::new(delimiter, 36),
+                Err(InvalidFraming::Delimiter(delimiter))
+            );
+        }
+        let framing = Framing::new(':', 36).unwrap();
+        assert_eq!(framing.encode_request(71, "body"), "1z:body");
+        assert_eq!(
+            framing.decode("1z:OK:body"),
+            Ok(Frame::Response {
+                id: 71,
+                result: Ok("body".to_owned())
+            })
+        );
+    }
+
+    #[test]
+    fn msgpack_codec_round_trips_binary_frames() {
+        let codec = MsgPackCodec::new(Framing::default());
+        let frame = codec.encode_binary_request(7, b"\x00\xffbody").unwrap();
+        assert_eq!(
+            codec.decode_binary(&frame),
+            Ok(BinaryFrame {
+                id: 7,
+                result: Ok(b"\x00\xffbody".to_vec()),
+            })
+        );
+
+        let mut error = Vec::new();
+        rmp::encode::write_map_len(&mut error, 2).unwrap();
+        rmp::encode::write_str(&mut error, "id").unwrap();
+        rmp::encode::write_uint(&mut error, 8).unwrap();
+        rmp::encode::write_str(&mut error, "error").unwrap();
+        rmp::encode::write_str(&mut error, "nope").unwrap();
+        assert_eq!(
+            codec.decode_binary(&error),
+            Ok(BinaryFrame {
+                id: 8,
+                result: Err("nope".to_owned()),
+            })
+        );
+
+        assert!(matches!(
+            codec.decode_binary(&frame[..frame.len() - 1]),
+            Err(FrameError::InvalidEnvelope(_))
+        ));
+        assert_eq!(
+            codec.decode("1|OK|text"),
+            Framing::default().decode("1|OK|text")
+        );
+    }
+
+    #[test]
+    fn bytes_requests_are_answered_through_msgpack_codec() {
+        let codec = MsgPackCodec::new(Framing::default());
+        let mut harness = Harness::with(|builder| builder.codec(Rc::new(codec)));
+        harness.update(Msg::Opened);
+        let mut response = Box::pin(harness.connection.request_bytes(b"ping"));
+
+        let [frame] = harness.sent_bytes().try_into().unwrap();
+        let BinaryFrame { id, result } = codec.decode_binary(&frame).unwrap();
+        assert_eq!(result, Ok(b"ping".to_vec()));
+        harness.update(Msg::ReceivedBytes(
+            codec.encode_binary_request(id, b"pong").unwrap(),
+        ));
+        assert_eq!(
+            poll(&mut response).map(Result::unwrap),
+            Poll::Ready(b"pong".to_vec())
+        );
+    }
+
+    #[test]
+    fn binary_only_codec_rejects_text_frames() {
+        let codec = MsgPackCodec::new(Framing::default()).binary_only();
+        let mut harness = Harness::with(|builder| builder.codec(Rc::new(codec)));
+        harness.update(Msg::Opened);
+        let mut response = Box::pin(harness.connection.request_bytes(b"ping"));
+        let [frame] = harness.sent_bytes().try_into().unwrap();
+        let id = codec.decode_binary(&frame).unwrap().id;
+
+        harness.receive(&format!("{id}|OK|pong"));
+        assert_eq!(harness.connection.stats().malformed_frames, 1);
+        assert!(poll(&mut response).is_pending());
+        harness.update(Msg::ReceivedBytes(vec![0xc1]));
+        assert_eq!(harness.connection.stats().malformed_frames, 2);
+    }
+
+    #[test]
+    fn requests_removed_by_reconnect_hook_free_their_slots() {
+        let mut harness = Harness::new(ConnectionConfig {
+            max_in_flight: Some(1),
+            ..ConnectionConfig::default()
+        });
+        harness.connection =
+            harness
+                .connection
+                .clone()
+                .with_reconnect_hook(Rc::new(|data: &mut ConnectionData| {
+                    data.retain_requests(
+                        |_, payload| !matches!(payload, Payload::Text(text) if text == "stale"),
+                    );
+                }));
+        let mut stale = Box::pin(harness.connection.request("stale"));
+        let mut waiting = Box::pin(harness.connection.request("waiting"));
+        let flag = Arc::new(WakeFlag::default());
+        assert!(poll(&mut stale).is_pending());
+        assert!(flag.poll(&mut waiting).is_pending());
+
+        harness.update(Msg::Opened);
+        assert!(matches!(
+            poll(&mut stale),
+            Poll::Ready(Err(RequestError::Cancelled))
+        ));
+        assert!(flag.woken());
+        assert!(flag.poll(&mut waiting).is_pending());
+        let [(_, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "waiting");
+    }
+
+    #[test]
+    fn all_kinds_of_requests_are_admitted_in_order() {
+        let mut harness = Harness::new(ConnectionConfig {
+            max_in_flight: Some(1),
+            ..ConnectionConfig::default()
+        });
+        harness.update(Msg::Opened);
+        let connection = harness.connection.clone();
+        let mut first = Box::pin(connection.request("first"));
+        assert!(poll(&mut first).is_pending());
+        let mut raw = Box::pin(connection.request_raw("raw").fuse());
+        let (traced_id, traced) = connection.request_traced("traced");
+        let mut traced = Box::pin(traced.fuse());
+        let (_parts, streamed) = connection.request_streaming("streamed");
+        let mut streamed = Box::pin(streamed.fuse());
+        let mut deduped = Box::pin(connection.request_dedup("key", "deduped").fuse());
+        // Waiting requests join the queue when polled, but keep their order
+        let _ = poll(&mut deduped);
+        let _ = poll(&mut streamed);
+        let _ = poll(&mut traced);
+        let _ = poll(&mut raw);
+
+        let [(mut id, _)] = harness.sent_requests().try_into().unwrap();
+        let mut admitted = Vec::new();
+        for _ in 0..4 {
+            assert!(harness.sent_requests().is_empty());
+            harness.receive(&format!("{id}|OK|done"));
+            // Polled in reverse order, but admitted in order the requests were made
+            // (fused, as answered ones are polled again)
+            let _ = poll(&mut deduped);
+            let _ = poll(&mut streamed);
+            let _ = poll(&mut traced);
+            let _ = poll(&mut raw);
+            let [(next, body)] = harness.sent_requests().try_into().unwrap();
+            if body == "traced" {
+                assert_eq!(next, traced_id);
+            }
+            admitted.push(body);
+            id = next;
+        }
+        assert_eq!(admitted, ["raw", "traced", "streamed", "deduped"]);
+        assert!(poll(&mut first).is_ready());
+    }
+
+    #[test]
+    fn traced_request_fails_when_it_cannot_be_sent() {
+        let harness = Harness::open();
+        harness.transport.set_failing(true);
+        let (_id, response) = harness.connection.request_traced("lost");
+        assert!(matches!(
+            poll(&mut Box::pin(response)),
+            Poll::Ready(Err(RequestError::Send(_)))
+        ));
+        assert!(!harness.connection.has_pending());
+    }
+
+    #[test]
+    fn dropped_request_is_cancelled_only_if_sent() {
+        let mut harness = Harness::new(ConnectionConfig::default());
+        let mut queued = Box::pin(harness.connection.request("queued"));
+        assert!(poll(&mut queued).is_pending());
+        drop(queued);
+        harness.update(Msg::Opened);
+        assert!(harness.transport.take_sent().is_empty());
+
+        let mut sent = Box::pin(harness.connection.request("sent"));
+        assert!(poll(&mut sent).is_pending());
+        let [(id, _)] = harness.sent_requests().try_into().unwrap();
+        drop(sent);
+        assert_eq!(
+            harness.transport.take_sent(),
+            [SentFrame::Text(format!("{id}|{CANCEL_CONTENT}"))]
+        );
+        assert!(!harness.connection.has_pending());
+    }
+
+    #[test]
+    fn empty_batch_is_not_sent() {
+        let harness = Harness::open();
+        let mut responses = Box::pin(harness.connection.request_batch(Vec::new()));
+        assert!(matches!(poll(&mut responses), Poll::Ready(responses) if responses.is_empty()));
+        assert!(harness.transport.take_sent().is_empty());
+        assert_eq!(harness.connection.stats().requests, 0);
+    }
+
+    #[test]
+    fn orphan_responses_are_reported_after_the_update() {
+        let orphans = Rc::new(RefCell::new(Vec::new()));
+        let mut harness = Harness::with(|builder| {
+            let orphans = Rc::clone(&orphans);
+            builder.config(ConnectionConfig {
+                on_orphan_response: Some(Rc::new(move |id, content| {
+                    orphans.borrow_mut().push((id, content.into_text()));
+                })),
+                ..ConnectionConfig::default()
+            })
+        });
+        harness.update(Msg::Opened);
+        let mut response = Box::pin(harness.connection.request("twice"));
+        assert!(poll(&mut response).is_pending());
+        let [(id, _)] = harness.sent_requests().try_into().unwrap();
+
+        harness.receive(&format!("{id}|OK|first"));
+        Connection::update(
+            Msg::Received(format!("{id}|OK|second")),
+            &mut harness.connection,
+            &mut harness.orders,
+        );
+        // Deferred until the update's commands run
+        assert!(orphans.borrow().is_empty());
+        platform::run_until_stalled();
+        assert_eq!(*orphans.borrow(), [(id, "second".to_owned())]);
+
+        let codec = Framing::default();
+        let mut bytes = Box::pin(harness.connection.request_bytes(b"cancelled"));
+        assert!(poll(&mut bytes).is_pending());
+        let [frame] = harness.sent_bytes().try_into().unwrap();
+        let BinaryFrame { id, .. } = codec.decode_binary(&frame).unwrap();
+        drop(bytes);
+        harness.update(Msg::ReceivedBytes(
+            codec.encode_binary_request(id, b"late").unwrap(),
+        ));
+        assert_eq!(orphans.borrow()[1], (id, "late".to_owned()));
+        assert_eq!(harness.connection.stats().orphan_responses, 2);
+    }
+
+    #[test]
+    fn raw_frames_include_responses() {
+        let mut harness = Harness::open();
+        let mut raw_frames = harness.connection.raw_frames();
+        let mut response = Box::pin(harness.connection.request("hello"));
+        let [(id, _)] = harness.sent_requests().try_into().unwrap();
+
+        harness.receive(&format!("{id}|OK|world"));
+        harness.receive("*|news");
+        harness.receive("PONG");
+        assert_eq!(
+            poll(&mut raw_frames.next()),
+            Poll::Ready(Some(format!("{id}|OK|world")))
+        );
+        assert_eq!(
+            poll(&mut raw_frames.next()),
+            Poll::Ready(Some("*|news".to_owned()))
+        );
+        assert_eq!(
+            poll(&mut raw_frames.next()),
+            Poll::Ready(Some("PONG".to_owned()))
+        );
+        assert!(poll(&mut raw_frames.next()).is_pending());
+        assert_eq!(
+            poll(&mut response).map(Result::unwrap),
+            Poll::Ready("world".into())
+        );
+    }
+
+    #[test]
+    fn woken_task_can_make_requests_right_away() {
+        thread_local! {
+            static CONNECTION: RefCell<Option<Connection>> = const { RefCell::new(None) };
+            static RESPONSES: RefCell<Vec<LocalBoxFuture<'static, Result<String, RequestError>>>> =
+                const { RefCell::new(Vec::new()) };
+        }
+
+        /// Makes another request as soon as it is woken, like a synchronous executor would.
+        struct Reentrant;
+
+        impl ArcWake for Reentrant {
+            fn wake_by_ref(_arc_self: &Arc<Self>) {
+                CONNECTION.with(|connection| {
+                    let connection = connection.borrow();
+                    let connection = connection.as_ref().unwrap();
+                    assert!(connection.data.try_lock().is_ok());
+                    let mut next = connection.request("next").boxed_local();
+                    assert!(poll(&mut next).is_pending());
+                    // Dropping it would cancel it
+                    RESPONSES.with(|responses| responses.borrow_mut().push(next));
+                });
+            }
+        }
+
+        let mut harness = Harness::open();
+        CONNECTION.with(|connection| *connection.borrow_mut() = Some(harness.connection.clone()));
+        let mut response = Box::pin(harness.connection.request("first"));
+        let waker = futures::task::waker(Arc::new(Reentrant));
+        assert!(response
+            .poll_unpin(&mut Context::from_waker(&waker))
+            .is_pending());
+        let [(id, _)] = harness.sent_requests().try_into().unwrap();
+
+        harness.receive(&format!("{id}|OK"));
+        let [(_, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "next");
+        assert!(poll(&mut response).is_ready());
+        RESPONSES.with(|responses| responses.take());
+        CONNECTION.with(|connection| connection.take());
+    }
+
+    #[test]
+    fn deduped_requests_share_the_response() {
+        let mut harness = Harness::open();
+        let mut first = Box::pin(harness.connection.request_deduped("same"));
+        let mut second = Box::pin(harness.connection.request_deduped("same"));
+        assert!(poll(&mut first).is_pending());
+        assert!(poll(&mut second).is_pending());
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "same");
+
+        harness.receive(&format!("{id}|OK|shared"));
+        assert_eq!(
+            poll(&mut first).map(Result::unwrap),
+            Poll::Ready("shared".into())
+        );
+        assert_eq!(
+            poll(&mut second).map(Result::unwrap),
+            Poll::Ready("shared".into())
+        );
+        assert_eq!(harness.connection.stats().requests, 1);
+    }
+
+    #[test]
+    fn json_requests_round_trip() {
+        let mut harness = Harness::open();
+        let mut sum = Box::pin(
+            harness
+                .connection
+                .request_json::<_, serde_json::Value>(&serde_json::json!({ "add": [1, 2] })),
+        );
+        let mut malformed = Box::pin(harness.connection.request_json::<_, Vec<u32>>(&[1, 2]));
+        assert!(poll(&mut sum).is_pending());
+        assert!(poll(&mut malformed).is_pending());
+        let [(sum_id, sum_body), (malformed_id, malformed_body)] =
+            harness.sent_requests().try_into().unwrap();
+        assert_eq!(sum_body, r#"{"add":[1,2]}"#);
+        assert_eq!(malformed_body, "[1,2]");
+
+        harness.receive(&format!(r#"{sum_id}|OK|{{"sum":3}}"#));
+        harness.receive(&format!("{malformed_id}|OK|[1,"));
+        assert_eq!(
+            poll(&mut sum).map(Result::unwrap),
+            Poll::Ready(serde_json::json!({ "sum": 3 }))
+        );
+        assert!(matches!(
+            poll(&mut malformed),
+            Poll::Ready(Err(RequestError::Decode(_)))
+        ));
+    }
+
+    #[test]
+    fn notifications_are_broadcast_or_counted_as_dropped() {
+        let mut harness = Harness::open();
+        harness.receive("*|nobody listens");
+        assert_eq!(harness.connection.stats().dropped_notifications, 1);
+
+        let mut first = harness.connection.notifications();
+        let mut second = harness.connection.notifications();
+        harness.receive("*|news");
+        assert_eq!(
+            poll(&mut first.next()),
+            Poll::Ready(Some("news".to_owned()))
+        );
+        assert_eq!(
+            poll(&mut second.next()),
+            Poll::Ready(Some("news".to_owned()))
+        );
+
+        drop(first);
+        drop(second);
+        harness.receive("*|too late");
+        assert_eq!(harness.connection.stats().dropped_notifications, 2);
+    }
+
+    #[test]
+    fn backpressured_burst_completes() {
+        let mut harness = Harness::new(ConnectionConfig {
+            max_in_flight: Some(2),
+            ..ConnectionConfig::default()
+        });
+        harness.update(Msg::Opened);
+        let mut responses = ["a", "b", "c"]
+            .map(|message| Box::pin(harness.connection.request_backpressured(message)));
+        let flag = Arc::new(WakeFlag::default());
+        for response in &mut responses {
+            assert!(flag.poll(response).is_pending());
+        }
+        let [(first, _), (second, _)] = harness.sent_requests().try_into().unwrap();
+
+        harness.receive(&format!("{first}|OK|1"));
+        assert!(flag.woken());
+        for response in &mut responses[1..] {
+            assert!(flag.poll(response).is_pending());
+        }
+        let [(third, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "c");
+        harness.receive(&format!("{second}|OK|2"));
+        harness.receive(&format!("{third}|OK|3"));
+        let responses = responses.map(|mut response| poll(&mut response).map(Result::unwrap));
+        assert_eq!(
+            responses,
+            ["1", "2", "3"].map(|response| Poll::Ready(response.to_owned()))
+        );
+    }
+
+    #[test]
+    fn malformed_frames_are_parsed_without_panicking() {
+        let framing = Framing::default();
+        assert_eq!(framing.decode(""), Err(FrameError::Empty));
+        assert_eq!(
+            framing.decode("no separator"),
+            Ok(Frame::Notification("no separator".to_owned()))
+        );
+        assert_eq!(
+            framing.decode("abc|OK"),
+            Err(FrameError::InvalidId("abc".to_owned()))
+        );
+        assert_eq!(
+            framing.decode("18446744073709551616|OK"),
+            Err(FrameError::InvalidId("18446744073709551616".to_owned()))
+        );
+
+        let mut harness = Harness::open();
+        platform::take_logs();
+        harness.receive("");
+        harness.receive("abc|OK");
+        harness.receive("7|OK|unknown");
+        let stats = harness.connection.stats();
+        assert_eq!((stats.malformed_frames, stats.orphan_responses), (2, 1));
+        assert_eq!(
+            platform::take_logs(),
+            [
+                "error: Malformed frame (Empty): ",
+                "error: Malformed frame (InvalidId(\"abc\")): abc|OK",
+                "debug: Response to unknown request 7",
+            ]
+        );
+    }
+
+    #[test]
+    fn late_response_from_previous_epoch_is_ignored() {
+        let mut harness = Harness::open();
+        let mut old = Box::pin(harness.connection.request("old"));
+        let [(old_id, _)] = harness.sent_requests().try_into().unwrap();
+        harness.receive(&format!("{old_id}|OK"));
+        assert!(poll(&mut old).is_ready());
+
+        harness.update(Msg::Closed);
+        harness.advance_time(1000.0);
+        harness.update(Msg::Opened);
+        let mut new = Box::pin(harness.connection.request("new"));
+        let [(new_id, _)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!((old_id >> 32, new_id >> 32), (0, 1));
+
+        // E.g. a duplicate delivered after reconnection
+        platform::take_logs();
+        harness.receive(&format!("{old_id}|OK|stale"));
+        assert!(poll(&mut new).is_pending());
+        assert_eq!(
+            platform::take_logs(),
+            [format!(
+                "warn: Ignoring response to request {old_id} of a previous connection"
+            )]
+        );
+        harness.receive(&format!("{new_id}|OK|fresh"));
+        assert_eq!(
+            poll(&mut new).map(Result::unwrap),
+            Poll::Ready("fresh".into())
+        );
+    }
+
+    #[test]
+    fn json_envelope_codec_frames_requests_and_responses() {
+        let codec = JsonEnvelopeCodec;
+        assert_eq!(codec.encode_request(1, "hi"), r#"{"body":"hi","id":1}"#);
+        assert_eq!(
+            codec.decode(r#"{"id":1,"body":{"a":1}}"#),
+            Ok(Frame::Response {
+                id: 1,
+                result: Ok(r#"{"a":1}"#.to_owned())
+            })
+        );
+        assert_eq!(
+            codec.decode(r#"{"id":1,"error":"nope"}"#),
+            Ok(Frame::Response {
+                id: 1,
+                result: Err("nope".to_owned())
+            })
+        );
+        assert_eq!(
+            codec.decode(r#"{"body":"news"}"#),
+            Ok(Frame::Notification("news".to_owned()))
+        );
+        assert_eq!(
+            codec.decode(r#"{"id":"x","body":""}"#),
+            Err(FrameError::InvalidId(r#""x""#.to_owned()))
+        );
+        assert!(matches!(
+            codec.decode("1|OK"),
+            Err(FrameError::InvalidEnvelope(_))
+        ));
+
+        let mut harness = Harness::with(|builder| builder.codec(Rc::new(codec)));
+        harness.update(Msg::Opened);
+        let mut response = Box::pin(harness.connection.request("hello"));
+        assert!(poll(&mut response).is_pending());
+        let [frame] = harness.transport.sent_text().try_into().unwrap();
+        let request: serde_json::Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(request["body"], "hello");
+        harness.receive(&format!(r#"{{"id":{},"body":"world"}}"#, request["id"]));
+        assert_eq!(
+            poll(&mut response).map(Result::unwrap),
+            Poll::Ready("world".into())
+        );
+    }
+
+    #[test]
+    fn backoff_resets_only_after_stable_connection() {
+        let mut harness = Harness::open();
+        harness.update(Msg::Closed);
+        harness.advance_time(1000.0);
+        assert_eq!(harness.transport.connects(), 2);
+
+        // Dropped right after opening, delays keep growing
+        harness.update(Msg::Opened);
+        harness.update(Msg::Closed);
+        harness.advance_time(1999.0);
+        assert_eq!(harness.transport.connects(), 2);
+        harness.advance_time(1.0);
+        assert_eq!(harness.transport.connects(), 3);
+
+        harness.update(Msg::Opened);
+        harness.advance_time(10_000.0);
+        harness.update(Msg::Closed);
+        harness.advance_time(1000.0);
+        assert_eq!(harness.transport.connects(), 4);
+    }
+
+    #[test]
+    fn unpolled_backpressured_request_does_not_block_others() {
+        for max_in_flight in [None, Some(2)] {
+            let mut harness = Harness::new(ConnectionConfig {
+                max_in_flight,
+                admission_policy: AdmissionPolicy::FailFast,
+                ..ConnectionConfig::default()
+            });
+            harness.update(Msg::Opened);
+            let backpressured = harness.connection.request_backpressured("later");
+            let mut response = Box::pin(harness.connection.request("now"));
+            assert!(poll(&mut response).is_pending());
+            let [(id, body)] = harness.sent_requests().try_into().unwrap();
+            assert_eq!(body, "now");
+
+            harness.receive(&format!("{id}|OK|done"));
+            assert_eq!(
+                poll(&mut response).map(Result::unwrap),
+                Poll::Ready("done".to_owned())
+            );
+            drop(backpressured);
+        }
+    }
+
+    #[test]
+    fn bytes_requests_which_cannot_be_encoded_fail() {
+        /// Codec refusing binary requests, like [`MsgPackCodec`] does with oversized bodies.
+        struct RefusingCodec;
+
+        impl Codec for RefusingCodec {
+            fn encode_request(&self, id: u64, body: &str) -> String {
+                Framing::default().encode_request(id, body)
+            }
+
+            fn encode_notification(&self, body: &str) -> String {
+                Framing::default().encode_notification(body)
+            }
+
+            fn encode_batch(&self, requests: &[(u64, &str)]) -> String {
+                Framing::default().encode_batch(requests)
+            }
+
+            fn encode_chunk(&self, id: u64, seq: usize, total: usize, part: &str) -> String {
+                Framing::default().encode_chunk(id, seq, total, part)
+            }
+
+            fn encode_chunk_end(&self, id: u64) -> String {
+                Framing::default().encode_chunk_end(id)
+            }
+
+            fn decode(&self, frame: &str) -> Result<Frame, FrameError> {
+                Framing::default().decode(frame)
+            }
+
+            fn encode_binary_request(
+                &self,
+                _id: u64,
+                _body: &[u8],
+            ) -> Result<Vec<u8>, RequestError> {
+                let error = std::io::Error::new(std::io::ErrorKind::Other, "too long");
+                Err(RequestError::MsgPackEncode(
+                    rmp_serde::encode::Error::InvalidValueWrite(
+                        rmp::encode::ValueWriteError::InvalidDataWrite(error),
+                    ),
+                ))
+            }
+        }
+
+        let mut harness = Harness::with(|builder| builder.codec(Rc::new(RefusingCodec)));
+        harness.update(Msg::Opened);
+        let mut response = Box::pin(harness.connection.request_bytes(b"body"));
+        assert!(matches!(
+            poll(&mut response),
+            Poll::Ready(Err(RequestError::MsgPackEncode(_)))
+        ));
+        assert!(harness.sent_bytes().is_empty());
+        // Not kept for replay
+        assert!(harness.connection.pending().is_empty());
+    }
+
+    #[test]
+    fn deduped_requests_do_not_join_special_requests() {
+        let mut harness = Harness::open();
+        let mut raw = Box::pin(harness.connection.request_raw("same"));
+        let (_parts, streamed) = harness.connection.request_streaming("same");
+        let mut streamed = Box::pin(streamed);
+        let (_id, traced) = harness.connection.request_traced("same");
+        let mut traced = Box::pin(traced);
+        assert!(poll(&mut raw).is_pending());
+        assert!(poll(&mut streamed).is_pending());
+        assert!(poll(&mut traced).is_pending());
+        assert_eq!(harness.sent_requests().len(), 3);
+
+        let mut deduped = Box::pin(harness.connection.request_deduped("same"));
+        assert!(poll(&mut deduped).is_pending());
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "same");
+        harness.receive(&format!("{id}|OK|own"));
+        assert_eq!(
+            poll(&mut deduped).map(Result::unwrap),
+            Poll::Ready("own".into())
+        );
+    }
+
+    #[test]
+    fn oversized_batches_are_sent_one_by_one() {
+        let mut harness = Harness::new(ConnectionConfig {
+            max_frame_size: Some(24),
+            ..ConnectionConfig::default()
+        });
+        harness.update(Msg::Opened);
+        let small = harness
+            .connection
+            .request_batch(vec!["a".into(), "b".into()]);
+        let [frame] = harness.transport.sent_text().try_into().unwrap();
+        assert!(frame.starts_with(BATCH_PREFIX));
+
+        let messages = ["first message", "second message"].map(String::from);
+        let large = harness.connection.request_batch(messages.to_vec());
+        let mut large = Box::pin(large);
+        assert!(poll(&mut large).is_pending());
+        let sent = harness.sent_requests();
+        assert_eq!(
+            sent.iter()
+                .map(|(_, body)| body.as_str())
+                .collect::<Vec<_>>(),
+            messages
+        );
+        for (id, body) in sent {
+            harness.receive(&format!("{id}|OK|{body}"));
+        }
+        let responses = poll(&mut large).map(|responses| {
+            responses
+                .into_iter()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(responses, Poll::Ready(messages.to_vec()));
+        drop(small);
+    }
+
+    #[test]
+    fn tap_observes_frames_once_subscribed() {
+        let mut harness = Harness::new(ConnectionConfig {
+            tap_preview_length: 2,
+            ..ConnectionConfig::default()
+        });
+        harness.update(Msg::Opened);
+        let mut unobserved = Box::pin(harness.connection.request("unobserved"));
+        assert!(poll(&mut unobserved).is_pending());
+
+        let mut tap = harness.connection.tap();
+        let mut observed = Box::pin(harness.connection.request("observed"));
+        assert!(poll(&mut observed).is_pending());
+        harness.update(Msg::ReceivedBytes(vec![0x00, 0xab, 0xff]));
+        let events = std::iter::from_fn(|| tap.next().now_or_never().flatten())
+            .map(|event| (event.direction, event.kind, event.payload_preview))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                (FrameDirection::Out, FrameKind::Text, "1|…".to_owned()),
+                (FrameDirection::In, FrameKind::Binary, "00ab…".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ids_of_waiting_traced_requests_are_not_handed_out_again() {
+        let mut harness = Harness::new(ConnectionConfig {
+            max_in_flight: Some(1),
+            ..ConnectionConfig::default()
+        });
+        harness.update(Msg::Opened);
+        let mut first = Box::pin(harness.connection.request("first"));
+        assert!(poll(&mut first).is_pending());
+        let (traced_id, traced) = harness.connection.request_traced("traced");
+        let mut traced = Box::pin(traced);
+        assert!(poll(&mut traced).is_pending());
+
+        // E.g. ids have wrapped around
+        harness.connection.data.lock().unwrap().next_free_id = NextId::new(epoch_ids(0));
+        let (other_id, other) = harness.connection.request_traced("other");
+        assert_ne!(other_id, traced_id);
+        drop(other);
+        assert!(!harness
+            .connection
+            .data
+            .lock()
+            .unwrap()
+            .reserved_ids
+            .contains(&other_id));
+
+        let [(first_id, _)] = harness.sent_requests().try_into().unwrap();
+        harness.receive(&format!("{first_id}|OK|done"));
+        assert!(poll(&mut traced).is_pending());
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!((id, body.as_str()), (traced_id, "traced"));
+        assert!(harness
+            .connection
+            .data
+            .lock()
+            .unwrap()
+            .reserved_ids
+            .is_empty());
+    }
+
+    #[test]
+    fn requests_are_retried_only_when_allowed() {
+        let mut harness = Harness::new(ConnectionConfig {
+            max_in_flight: Some(1),
+            admission_policy: AdmissionPolicy::FailFast,
+            backoff: BackoffPolicy {
+                jitter: false,
+                ..BackoffPolicy::default()
+            },
+            ..ConnectionConfig::default()
+        });
+        harness.update(Msg::Opened);
+        let mut busy = Box::pin(harness.connection.request("busy"));
+        let [(busy_id, _)] = harness.sent_requests().try_into().unwrap();
+
+        // Not sent at all, so retried even if not idempotent
+        let options = RequestOptions {
+            retries: 1,
+            ..RequestOptions::default()
+        };
+        let mut retried = Box::pin(harness.connection.request_with("retried", options));
+        assert!(poll(&mut retried).is_pending());
+        assert_eq!(harness.connection.stats().retries, 1);
+        harness.receive(&format!("{busy_id}|OK|done"));
+        assert!(poll(&mut busy).is_ready());
+        harness.advance_time(1000.0);
+        assert!(poll(&mut retried).is_pending());
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "retried");
+        harness.receive(&format!("{id}|OK|finally"));
+        assert_eq!(
+            poll(&mut retried).map(Result::unwrap),
+            Poll::Ready("finally".into())
+        );
+
+        // The connection is lost for good, so requests fail with `ConnectionLost`
+        let mut harness = Harness::new(ConnectionConfig {
+            backoff: BackoffPolicy {
+                jitter: false,
+                max_retries: Some(0),
+                ..BackoffPolicy::default()
+            },
+            ..ConnectionConfig::default()
+        });
+        harness.update(Msg::Opened);
+        harness.update(Msg::Closed);
+        let transient = RequestOptions {
+            retries: 2,
+            retry_on: RetryOn::Transient,
+            ..RequestOptions::default()
+        };
+        let mut not_idempotent = Box::pin(harness.connection.request_with("once", transient));
+        assert!(matches!(
+            poll(&mut not_idempotent),
+            Poll::Ready(Err(RequestError::ConnectionLost))
+        ));
+        assert_eq!(harness.connection.stats().retries, 0);
+
+        let idempotent = RequestOptions {
+            idempotent: true,
+            ..transient
+        };
+        let mut retried = Box::pin(harness.connection.request_with("twice", idempotent));
+        assert!(poll(&mut retried).is_pending());
+        harness.advance_time(1000.0);
+        assert!(poll(&mut retried).is_pending());
+        harness.advance_time(2000.0);
+        // The last error once the retries are used up
+        assert!(matches!(
+            poll(&mut retried),
+            Poll::Ready(Err(RequestError::ConnectionLost))
+        ));
+        assert_eq!(harness.connection.stats().retries, 2);
+    }
+
+    #[test]
+    fn oversized_requests_and_responses_are_chunked() {
+        let mut harness = Harness::new(ConnectionConfig {
+            max_frame_size: Some(24),
+            ..ConnectionConfig::default()
+        });
+        harness.update(Msg::Opened);
+        let mut small = Box::pin(harness.connection.request("small"));
+        assert_eq!(
+            harness.transport.take_sent(),
+            [SentFrame::Text("0|small".into())]
+        );
+
+        let message = "a message too long for a single frame";
+        let mut large = Box::pin(harness.connection.request(message));
+        assert!(poll(&mut large).is_pending());
+        let mut frames = harness.transport.sent_text();
+        assert_eq!(frames.pop().as_deref(), Some("1|CHUNK_END"));
+        assert!(frames.len() > 1);
+        let total = frames.len();
+        let mut request = String::new();
+        for (i, frame) in frames.iter().enumerate() {
+            assert!(frame.len() <= 24, "{frame} is too long");
+            let header = format!("1|CHUNK|{}/{total}|", i + 1);
+            request.push_str(frame.strip_prefix(&header).unwrap());
+        }
+        assert_eq!(request, format!("1|{message}"));
+
+        // Parts of the response frame may arrive in any order
+        harness.receive("1|CHUNK|2/2|rld");
+        harness.receive("0|OK|tiny");
+        harness.receive("1|CHUNK|1/2|1|OK|wo");
+        assert!(poll(&mut large).is_pending());
+        harness.receive("1|CHUNK_END");
+        assert_eq!(
+            poll(&mut large).map(Result::unwrap),
+            Poll::Ready("world".into())
+        );
+        assert_eq!(
+            poll(&mut small).map(Result::unwrap),
+            Poll::Ready("tiny".into())
+        );
+    }
+
+    #[test]
+    fn shutdown_waits_for_pending_requests_once() {
+        let mut harness = Harness::open();
+        let mut completed = Box::pin(harness.connection.request("completed"));
+        let mut abandoned = Box::pin(harness.connection.request("abandoned"));
+        let [(id, _), _] = harness.sent_requests().try_into().unwrap();
+
+        let mut shutdown = Box::pin(harness.connection.shutdown(Duration::from_millis(500)));
+        // Calling it again does not shorten the grace time
+        let mut again = Box::pin(harness.connection.shutdown(Duration::ZERO));
+        harness.advance_time(0.0);
+        assert!(poll(&mut shutdown).is_pending());
+        assert!(poll(&mut again).is_pending());
+        assert!(matches!(
+            poll(&mut Box::pin(harness.connection.request("late"))),
+            Poll::Ready(Err(RequestError::ShuttingDown))
+        ));
+        harness.receive(&format!("{id}|OK|done"));
+        assert_eq!(
+            poll(&mut completed).map(Result::unwrap),
+            Poll::Ready("done".into())
+        );
+
+        harness.advance_time(500.0);
+        assert_eq!(harness.connection.state(), ConnectionState::Closed);
+        assert_eq!(harness.transport.closed(), Some(Some(1000)));
+        assert!(matches!(
+            poll(&mut abandoned),
+            Poll::Ready(Err(RequestError::Closed))
+        ));
+        for shutdown in [&mut shutdown, &mut again] {
+            let Poll::Ready(report) = poll(shutdown) else {
+                panic!("shutdown has not finished");
+            };
+            assert_eq!((report.completed, report.abandoned), (1, 1));
+        }
+        let Poll::Ready(report) = poll(&mut Box::pin(harness.connection.shutdown(Duration::ZERO)))
+        else {
+            panic!("the finished shutdown is not reused");
+        };
+        assert_eq!((report.completed, report.abandoned), (1, 1));
+    }
+
+    #[test]
+    fn streamed_response_yields_parts_before_the_end() {
+        let mut harness = Harness::open();
+        let (mut parts, response) = harness.connection.request_streaming("job");
+        let mut response = Box::pin(response);
+        assert!(poll(&mut response).is_pending());
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "job");
+
+        harness.receive(&format!("{id}|PART|10%"));
+        harness.receive(&format!("{id}|PART|50%"));
+        assert_eq!(poll(&mut parts.next()), Poll::Ready(Some("10%".into())));
+        assert_eq!(poll(&mut parts.next()), Poll::Ready(Some("50%".into())));
+        assert!(poll(&mut parts.next()).is_pending());
+        assert!(poll(&mut response).is_pending());
+
+        harness.receive(&format!("{id}|END|done"));
+        assert_eq!(
+            poll(&mut response).map(Result::unwrap),
+            Poll::Ready("done".into())
+        );
+        assert_eq!(poll(&mut parts.next()), Poll::Ready(None));
+
+        // A part arriving after the end is rejected, not passed on
+        let orphans = harness.connection.stats().orphan_responses;
+        harness.receive(&format!("{id}|PART|late"));
+        assert_eq!(harness.connection.stats().orphan_responses, orphans + 1);
+        assert_eq!(poll(&mut parts.next()), Poll::Ready(None));
+
+        // So is a part of a request which is not streamed
+        let mut plain = Box::pin(harness.connection.request("plain"));
+        let [(id, _)] = harness.sent_requests().try_into().unwrap();
+        harness.receive(&format!("{id}|PART|unexpected"));
+        assert_eq!(harness.connection.stats().malformed_frames, 1);
+        assert!(poll(&mut plain).is_pending());
+    }
+
+    #[test]
+    fn ping_measures_round_trip_or_fails_on_disconnect() {
+        let mut harness = Harness::open();
+        let mut ping = Box::pin(harness.connection.ping());
+        let [(id, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, PING_CONTENT);
+        assert!(id >= PING_ID_START);
+        harness.advance_time(25.0);
+        harness.receive(&format!("{id}|OK|PONG"));
+        assert_eq!(poll(&mut ping).map(Result::unwrap), Poll::Ready(25.0));
+
+        let mut ping = Box::pin(harness.connection.ping());
+        assert!(poll(&mut ping).is_pending());
+        harness.update(Msg::Closed);
+        assert!(matches!(
+            poll(&mut ping),
+            Poll::Ready(Err(RequestError::ConnectionLost))
+        ));
+    }
+
+    #[test]
+    fn urgent_requests_are_sent_first() {
+        let mut harness = Harness::new(ConnectionConfig {
+            promote_low_after: Duration::from_secs(10),
+            ..ConnectionConfig::default()
+        });
+        let request = |harness: &Harness, message: &str, priority| {
+            let options = RequestOptions {
+                priority,
+                ..RequestOptions::default()
+            };
+            let mut response = Box::pin(harness.connection.request_with(message, options));
+            assert!(poll(&mut response).is_pending());
+            response
+        };
+        let sent = |harness: &Harness| {
+            harness
+                .sent_requests()
+                .into_iter()
+                .map(|(_, body)| body)
+                .collect::<Vec<_>>()
+        };
+
+        let _low = request(&harness, "low", Priority::Low);
+        let _normal = request(&harness, "normal", Priority::Normal);
+        let _high = request(&harness, "high", Priority::High);
+        let _second_high = request(&harness, "second high", Priority::High);
+        assert_eq!(harness.connection.stats().queued_by_priority, [1, 1, 2]);
+        harness.update(Msg::Opened);
+        assert_eq!(sent(&harness), ["high", "second high", "normal", "low"]);
+
+        // Replayed requests (sent after the queued ones) are sorted as well,
+        // the low priority one has waited long enough to be handled like a normal one
+        harness.update(Msg::Closed);
+        harness.advance_time(10_000.0);
+        let _late = request(&harness, "late", Priority::Normal);
+        harness.update(Msg::Opened);
+        assert_eq!(
+            sent(&harness),
+            ["late", "high", "second high", "low", "normal"]
+        );
+    }
+
+    #[test]
+    fn lifecycle_hooks_may_make_requests() {
+        let mut harness = Harness::open();
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let requests = Rc::new(RefCell::new(Vec::new()));
+
+        let connection = harness.connection.clone();
+        let (on_open_calls, on_open_requests) = (Rc::clone(&calls), Rc::clone(&requests));
+        let on_open = harness.connection.on_open(Rc::new(move || {
+            on_open_calls.borrow_mut().push("open".to_owned());
+            let mut response = Box::pin(connection.request("resubscribe"));
+            assert!(poll(&mut response).is_pending());
+            on_open_requests.borrow_mut().push(response);
+        }));
+        let on_close_calls = Rc::clone(&calls);
+        let on_close = harness.connection.on_close(Rc::new(move || {
+            on_close_calls.borrow_mut().push("close".to_owned());
+        }));
+        let scheduled_calls = Rc::clone(&calls);
+        let _scheduled = harness
+            .connection
+            .on_reconnect_scheduled(Rc::new(move |attempt| {
+                scheduled_calls
+                    .borrow_mut()
+                    .push(format!("scheduled {attempt}"));
+            }));
+
+        harness.update(Msg::Closed);
+        harness.advance_time(1000.0);
+        harness.update(Msg::Opened);
+        assert_eq!(*calls.borrow(), ["close", "scheduled 1", "open"]);
+        let [(_, body)] = harness.sent_requests().try_into().unwrap();
+        assert_eq!(body, "resubscribe");
+        assert_eq!(requests.borrow().len(), 1);
+
+        // Unregistered hooks are not called anymore
+        drop((on_open, on_close));
+        calls.borrow_mut().clear();
+        harness.update(Msg::Closed);
+        harness.advance_time(2000.0);
+        harness.update(Msg::Opened);
+        // The connection has not been stable, so it keeps backing off
+        assert_eq!(*calls.borrow(), ["scheduled 2"]);
+    }
+
+    #[test]
+    fn set_url_switches_servers_keeping_or_dropping_requests() {
+        let mut harness = Harness::open();
+        let mut kept = Box::pin(harness.connection.request("kept"));
+        let [(id, _)] = harness.sent_requests().try_into().unwrap();
+        let mut states = harness.connection.state_stream();
+
+        let connection = &harness.connection;
+        connection.set_url("ws://test", false, &mut harness.orders);
+        assert_eq!(harness.transport.connects(), 1);
+
+        connection.set_url("ws://other", false, &mut harness.orders);
+        assert_eq!(harness.transport.connects(), 2);
+        assert_eq!(harness.transport.url(), "ws://other");
+        assert_eq!(connection.state(), ConnectionState::Reconnecting);
+        harness.update(Msg::Opened);
+        // Replayed with the same id
+        assert_eq!(harness.sent_requests(), [(id, "kept".to_owned())]);
+        harness.receive(&format!("{id}|OK|moved"));
+        assert_eq!(
+            poll(&mut kept).map(Result::unwrap),
+            Poll::Ready("moved".into())
+        );
+
+        let mut dropped = Box::pin(harness.connection.request("dropped"));
+        harness.sent_requests();
+        let connection = &harness.connection;
+        connection.set_url("ws://third", true, &mut harness.orders);
+        assert!(matches!(
+            poll(&mut dropped),
+            Poll::Ready(Err(RequestError::Cancelled))
+        ));
+        harness.update(Msg::Opened);
+        assert!(harness.sent_requests().is_empty());
+
+        assert_eq!(
+            std::iter::from_fn(|| states.next().now_or_never().flatten()).collect::<Vec<_>>(),
+            [
+                ConnectionState::Reconnecting,
+                ConnectionState::Open,
+                ConnectionState::Reconnecting,
+                ConnectionState::Open,
+            ]
+        );
+    }
+}
@@ -1,80 +1,190 @@
+use std::collections::BTreeMap;
 use std::rc::Rc;
-use std::task::Waker;
 
-use connection::Connection;
-use measurer::Measurer;
+use connection::{Connection, ConnectionPool, ConnectionState, NotifyPolicy};
+use futures::StreamExt;
+use measurer::{Measurer, MeasurerRegistry, WrapMode};
 use seed::prelude::*;
 
-use seed::div;
+use seed::{attrs, button, div, input, li, ul, C};
 
 mod connection;
 mod measurer;
+#[cfg(test)]
+mod mock_orders;
+mod next_id;
+mod platform;
+mod transport;
+
+const MAIN_MEASURER: &str = "main";
+const SIDEBAR_MEASURER: &str = "sidebar";
+const COMPARED_TEXT: &str = "The quick brown fox jumps over the lazy dog";
+/// Used unless another server is given with `?ws=` query parameter
+const DEFAULT_WEBSOCKET_URL: &str = "wss://ws.postman-echo.com/raw";
+const ECHO_CONNECTION: &str = "echo";
 
 struct Model {
-    connection: Connection,
-    measurer: Measurer,
+    connections: ConnectionPool,
+    connection_state: ConnectionState,
+    measurers: MeasurerRegistry,
     counter: u64,
+    /// Widths of `COMPARED_TEXT` measured by each measurer
+    widths: BTreeMap<&'static str, f64>,
+    /// Message typed into the request playground
+    draft: String,
+    /// Requests sent from the playground and their responses
+    log: Vec<String>,
 }
 
 enum Msg {
     AddRenderable,
-    Measurer(measurer::Msg),
-    Connection(connection::Msg),
-    Wake(Vec<Waker>),
+    CompareFonts,
+    Compared(&'static str, f64),
+    Measurer(&'static str, measurer::Msg),
+    Connection(String, connection::Msg),
+    ConnectionStateChanged(ConnectionState),
+    DraftChanged(String),
+    SendDraft,
+    Logged(String),
 }
 
-fn init(_url: Url, orders: &mut impl Orders<Msg>) -> Model {
+impl Model {
+    fn connection(&self) -> &Connection {
+        self.connections.get(ECHO_CONNECTION).unwrap()
+    }
+}
+
+fn init(url: Url, orders: &mut impl Orders<Msg>) -> Model {
     let msg_sender = orders.msg_sender();
-    let connection = Connection::new("wss://ws.postman-echo.com/raw", &mut orders.proxy(Msg::Connection));
-    let measurer = Measurer::new(Rc::new({
-        let outer = Rc::clone(&msg_sender);
-        move |msg| outer(Some(Msg::Measurer(msg)))
-    }));
+    let websocket_url = url.search().get("ws").and_then(|values| values.first()).map_or(DEFAULT_WEBSOCKET_URL, String::as_str);
+    let connections = ConnectionPool::new(
+        &[(ECHO_CONNECTION, websocket_url)],
+        &mut orders.proxy(|(name, msg)| Msg::Connection(name, msg)),
+    );
+    let connection = connections.get(ECHO_CONNECTION).unwrap();
+    orders.stream(connection.state_stream().map(Msg::ConnectionStateChanged));
+    let mut measurers = MeasurerRegistry::new();
+    for (name, classes) in [(MAIN_MEASURER, "main-column"), (SIDEBAR_MEASURER, "sidebar")] {
+        let measurer = Measurer::new(Rc::new({
+            let outer = Rc::clone(&msg_sender);
+            move |msg| outer(Some(Msg::Measurer(name, msg)))
+        }));
+        measurers.insert(measurer.with_name(name), classes);
+    }
 
     Model {
         counter: 0,
-        connection,
-        measurer,
+        connection_state: connection.state(),
+        connections,
+        measurers,
+        widths: BTreeMap::new(),
+        draft: String::new(),
+        log: Vec::new(),
     }
 }
 
 fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
     match msg {
         Msg::AddRenderable => {
-            let measurer = model.measurer.clone();
-            let connection = model.connection.clone();
+            let measurer = model.measurers.get(MAIN_MEASURER).unwrap().clone();
+            let connection = model.connection().clone();
             let id = model.counter;
             model.counter = model.counter.wrapping_add(1);
+            if let Err(error) = connection.notify(&format!("Adding renderable {id}"), NotifyPolicy::Queue) {
+                seed::error!("Notification failed:", error);
+            }
 
             orders.perform_cmd(async move {
                 for i in 0..4 {
                     let mr = measurer.clone();
                     let connection = connection.clone();
                     wasm_bindgen_futures::spawn_local(async move {
-                        let text = connection.request(&format!("Message {id}/{i}")).await;
+                        let text = match connection.request(&format!("Message {id}/{i}")).await {
+                            Ok(text) => text,
+                            Err(error) => {
+                                seed::error!("Request failed:", error);
+                                return;
+                            }
+                        };
                         seed::log!("Got content: ", text);
                         let r = format!("Renderable: {text}");
-                        let ms = mr.measure(r).await;
+                        let ms = match mr.measure(r).await {
+                            Ok(ms) => ms,
+                            Err(error) => {
+                                seed::error!("Measurement failed:", error);
+                                return;
+                            }
+                        };
                         let _r = ms.get();
                         seed::log!("Measured: ", text);
                     });
                 }
             });
         }
-        Msg::Connection(msg) => Connection::update(msg, &mut model.connection, &mut orders.proxy(Msg::Connection)),
-        Msg::Measurer(msg) => model.measurer.update(msg, orders, Msg::Measurer),
-        Msg::Wake(wakers) => {
-            for w in wakers {
-                w.wake();
+        Msg::CompareFonts => {
+            for name in [MAIN_MEASURER, SIDEBAR_MEASURER] {
+                let measurer = model.measurers.get(name).unwrap().clone();
+                orders.perform_cmd(async move {
+                    // Shrink to fit the text, so the width depends on the font only
+                    let ms = measurer
+                        .build(COMPARED_TEXT.to_owned())
+                        .max_width(1000.0)
+                        .wrap(WrapMode::NoWrap)
+                        .await
+                        .ok()?;
+                    Some(Msg::Compared(name, ms.size().width))
+                });
             }
         }
+        Msg::Compared(name, width) => {
+            model.widths.insert(name, width);
+        }
+        Msg::Connection(name, msg) => model
+            .connections
+            .update((name, msg), &mut orders.proxy(|(name, msg)| Msg::Connection(name, msg))),
+        Msg::Measurer(name, msg) => model.measurers.update(name, msg, orders, Msg::Measurer),
+        Msg::ConnectionStateChanged(state) => model.connection_state = state,
+        Msg::DraftChanged(draft) => model.draft = draft,
+        Msg::SendDraft => {
+            let message = std::mem::take(&mut model.draft);
+            model.log.push(format!("> {message}"));
+            let response = model.connection().request(&message);
+            orders.perform_cmd(async move {
+                Msg::Logged(match response.await {
+                    Ok(text) => format!("< {text}"),
+                    Err(error) => format!("! {error}"),
+                })
+            });
+        }
+        Msg::Logged(line) => model.log.push(line),
     }
 }
 
 fn view(model: &Model) -> Node<Msg> {
+    let status = match model.connection_state {
+        ConnectionState::Connecting => "Connecting…",
+        ConnectionState::Authenticating => "Authenticating…",
+        ConnectionState::Open => "Connected",
+        ConnectionState::Reconnecting => "Reconnecting…",
+        ConnectionState::Closed => "Disconnected",
+        ConnectionState::Failed => "Connection lost",
+    };
     div![
+        div![C!["status"], status],
+        model.connection().view_stats(),
         div!["Add measurements", ev(Ev::Click, |_| Msg::AddRenderable)],
-        model.measurer.view().map_msg(Msg::Measurer)
+        div!["Compare fonts", ev(Ev::Click, |_| Msg::CompareFonts)],
+        model.widths.iter().map(|(name, width)| div![format!("{name}: {width}px")]),
+        div![
+            input![attrs! {At::Value => model.draft}, input_ev(Ev::Input, Msg::DraftChanged)],
+            button!["Send", ev(Ev::Click, |_| Msg::SendDraft)],
+        ],
+        ul![model.log.iter().map(|line| li![line])],
+        model
+            .measurers
+            .view()
+            .into_iter()
+            .map(|node| node.map_msg(|(name, msg)| Msg::Measurer(name, msg)))
     ]
 }
 
@@ -1,15 +1,36 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
     fmt,
-    future::Future,
+    future::{Future, IntoFuture},
+    hash::Hash,
+    num::NonZeroUsize,
     pin::Pin,
     rc::{Rc, Weak},
     task::{Context, Poll, Waker},
+    time::Duration,
 };
 
-use seed::div;
+use futures::channel::mpsc;
+use futures::future::{self, Either, LocalBoxFuture};
+use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
 use seed::prelude::*;
-use web_sys::{Element, HtmlElement};
+use seed::{div, raw, style, Style, C};
+use web_sys::{DomRect, Element, HtmlElement};
+
+use crate::next_id::NextId;
+use crate::platform::{self, now, TaskHandle};
+
+const LEAK_CHECK_INTERVAL_MS: u32 = 1000;
+/// Number of animation frames to wait for Seed's render before resolving measurements anyway
+const FALLBACK_FRAMES: u32 = 3;
+
+/// Maps measurer's message to application message type and sends to update.
+type MsgSender = Rc<dyn Fn(Msg)>;
+
+/// Callback of a measurement requested by `Measurer::measure_then()`.
+type OnComplete = Box<dyn FnOnce(Measurement)>;
 
 /// Allows for rendering DOM in an invisible space and taking measurements on it then.
 #[derive(Clone)]
@@ -22,175 +43,2081 @@ struct MeasurerData {
     measurements: Vec<WeakMeasurement>,
     /// Futures' states of measurements that have not been rendered and woken up yet
     futures: Vec<Weak<RefCell<FutureState>>>,
+    /// Futures' states of measurements requested by `measure_then()`.
+    /// Nobody else keeps them alive until their callbacks are invoked.
+    callback_futures: Vec<Rc<RefCell<FutureState>>>,
+    /// Measurements invalidated by `invalidate_all()` that wait to be measured again
+    remeasurements: Vec<WeakMeasurement>,
+    /// Incremented by every `invalidate_all()` and `Measurement::invalidate()` call
+    generation: u64,
     /// Maps message to application message type and sends to update.
     /// Use it only within `async` blocks.
-    msg_sender: Rc<dyn Fn(Msg)>,
+    msg_sender: MsgSender,
+    /// Number of render passes requested by `Msg::WaitForRender`
+    pass: u64,
+    /// Value of `pass` seen by the last call to `view()` or `view_into()`
+    viewed_pass: u64,
+    /// Value of `pass` resolved by the last `Msg::Measured`
+    measured_pass: u64,
+    /// Value of `pass` resolved by the last `Msg::MeasuredFallback`
+    fallback_pass: u64,
+    /// Allocates ids of measurements, skipping ids of alive ones
+    next_measurement_id: NextId,
+    config: MeasurerConfig,
+    /// Whether `document.fonts.ready` has resolved (always set when not waiting for fonts)
+    fonts_ready: Cell<bool>,
+    /// Periodically checks for leaked futures while any future is pending
+    leak_checker: Option<TaskHandle>,
+    /// Element which `view()`'s wrapper is moved into after rendering
+    portal_target: Option<Element>,
+    /// Wrapper rendered by `view()`
+    wrapper: ElRef<HtmlElement>,
+    backend: Rc<dyn MeasurerBackend>,
+    /// Prefix of the measurer's log messages
+    name: &'static str,
+    /// Subscribers of `completions()`
+    completion_senders: Vec<mpsc::UnboundedSender<Measurement>>,
+    /// Whether measurements are handed to the backend by `drive_once()`, see `new_headless()`
+    headless: bool,
+    /// Futures' states of measurements waiting for `drive_once()`
+    deferred: Vec<Weak<RefCell<FutureState>>>,
+    /// Measurements being rendered by `view_into()`, kept to reuse the allocation
+    render_buffer: Vec<Measurement>,
 }
 
+/// Configuration of a [`Measurer`].
+#[derive(Clone, Debug)]
+pub(crate) struct MeasurerConfig {
+    /// Do not complete measurements until `document.fonts.ready` resolves,
+    /// so text is not measured with fallback fonts.
+    pub(crate) wait_for_fonts: bool,
+    /// Put marks and measures of measurements' lifecycle to the browser's Performance timeline.
+    pub(crate) perf_marks: bool,
+    /// Maximal length of DOM snapshots included in diagnostic messages.
+    pub(crate) snapshot_length: usize,
+    /// Warn about measurements pending for longer than this (they have probably leaked).
+    pub(crate) leak_threshold: Duration,
+    /// Warn when a requested render has not happened within this time
+    /// (e.g. the tab is hidden or the application skips rendering).
+    pub(crate) render_watchdog: Option<Duration>,
+    /// Maximal number of measurements rendered for the first time in a single render pass,
+    /// more urgent ones ([`MeasureOptions::priority`]) first. Unlimited if `None`.
+    pub(crate) max_new_per_pass: Option<NonZeroUsize>,
+}
+
+/// Identifies a measurement within its [`Measurer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct MeasurementId(u64);
+
 /// Stores reference to rendered DOM element.
 ///
 /// When the DOM element is not needed no more (referencing Measurement has been dropped),
 /// it is not rendered any more.
 ///
 /// Cloning this struct is cheap as it stores [`Rc<_>`] under the hood.
+/// Clones are equal (and hash the same), other measurements are not,
+/// even if they measure the same content.
 #[derive(Clone, Debug)]
 pub(crate) struct Measurement(Rc<MeasurementData>);
 
+impl PartialEq for Measurement {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Measurement {}
+
+impl Hash for Measurement {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state);
+    }
+}
+
 struct WeakMeasurement(Weak<MeasurementData>);
 
 struct MeasurementData {
-    text: String,
+    id: MeasurementId,
+    content: Content,
     div: ElRef<HtmlElement>,
     /// This is only to prove that some node was rendered, but without el_ref attached
     rendered: RefCell<bool>,
+    options: MeasureOptions,
+    /// Measurer's generation in which this measurement was last measured
+    generation: RefCell<u64>,
+    /// Set by `Measurer::invalidate_all()` until the measurement is measured again
+    stale: RefCell<bool>,
+    /// Wakers of `Measurement::next_update()` futures
+    update_wakers: RefCell<Vec<Waker>>,
+    /// Size provided by a backend that does not render (see `MeasurerBackend::renders()`)
+    backend_size: RefCell<Option<(f64, f64)>>,
+    /// Measurer which renders the measurement
+    measurer: Weak<RefCell<MeasurerData>>,
+}
+
+/// What is rendered inside measurement's container.
+#[derive(Debug)]
+enum Content {
+    /// Plain text
+    Text(String),
+    /// Raw HTML markup, inserted as is
+    Html(String),
+    /// Node sanitized by `sanitize_for_measurement()`
+    Node(Box<Node<()>>),
+    /// Node built on each render, see `Measurer::measure_view()`
+    View(NodeView),
+    /// Element rendered by the application, see `Measurer::attach()`
+    Attached(ElRef<HtmlElement>),
+}
+
+impl Content {
+    fn as_str(&self) -> &str {
+        match self {
+            Content::Text(text) | Content::Html(text) => text,
+            // Nodes have no textual representation
+            Content::Node(_) | Content::View(_) | Content::Attached(_) => "",
+        }
+    }
+}
+
+/// Builds node of a measurement, see `Measurer::measure_view()`.
+struct NodeView(Box<dyn Fn() -> Node<()>>);
+
+impl fmt::Debug for NodeView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NodeView")
+    }
+}
+
+/// Options of a single measurement.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MeasureOptions {
+    /// Measure again after every [`Measurer::invalidate_all`].
+    /// Fresh geometry can be awaited with [`Measurement::next_update`].
+    pub(crate) auto_remeasure: bool,
+    /// Class of the measured node
+    pub(crate) class: Option<String>,
+    /// Width (in pixels) of the measured node, e.g. to measure height of wrapped text
+    pub(crate) width: Option<f64>,
+    /// Maximal width (in pixels) of the measured node, which shrinks to fit its content
+    pub(crate) max_width: Option<f64>,
+    /// How the measured text wraps
+    pub(crate) wrap: WrapMode,
+    /// Priority of the measurement (higher is more urgent)
+    pub(crate) priority: i32,
+    /// Style of the measured node, overriding styles set by other options
+    pub(crate) style: Option<Style>,
+}
+
+/// Wrapping of measured text, maps to `white-space` and `overflow-wrap` CSS properties.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub(crate) enum WrapMode {
+    /// Inherited from the measurer's container
+    #[default]
+    Normal,
+    /// Breaks long words (e.g. URLs) only if they do not fit in a line
+    BreakWord,
+    /// Breaks anywhere, also when computing minimal content width
+    Anywhere,
+    /// Does not wrap at all
+    NoWrap,
+    /// Preserves whitespace and line breaks, does not wrap
+    Pre,
+}
+
+impl MeasureOptions {
+    /// Preset for measuring chat messages: breaks long words and shrinks to fit `max_width`.
+    #[allow(dead_code)]
+    pub(crate) fn chat_bubble(max_width: f64) -> Self {
+        Self {
+            max_width: Some(max_width),
+            wrap: WrapMode::BreakWord,
+            ..Self::default()
+        }
+    }
+}
+
+impl WrapMode {
+    fn style(self) -> Style {
+        match self {
+            WrapMode::Normal => Style::empty(),
+            WrapMode::BreakWord => style! {St::OverflowWrap => "break-word"},
+            WrapMode::Anywhere => style! {St::OverflowWrap => "anywhere"},
+            WrapMode::NoWrap => style! {St::WhiteSpace => "nowrap"},
+            WrapMode::Pre => style! {St::WhiteSpace => "pre"},
+        }
+    }
+}
+
+/// Builder of a single measurement created by [`Measurer::build`].
+///
+/// Awaiting it renders the measurement.
+pub(crate) struct MeasureRequest {
+    measurer: Measurer,
+    text: String,
+    options: MeasureOptions,
+    timeout: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub(crate) enum MeasureError {
+    /// The measurement was not rendered before its timeout.
+    Timeout,
+    /// The measurement was requested with options that cannot be used together.
+    InvalidOptions(&'static str),
+    /// The measurement has not been rendered (yet).
+    NotRendered,
+    /// The measurement has been cancelled by [`Measurer::clear`].
+    Cancelled,
+}
+
+impl fmt::Display for MeasureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MeasureError::Timeout => write!(f, "measurement not rendered in time"),
+            MeasureError::InvalidOptions(reason) => write!(f, "invalid measure options: {reason}"),
+            MeasureError::NotRendered => write!(f, "measurement not rendered"),
+            MeasureError::Cancelled => write!(f, "measurement cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for MeasureError {}
+
+/// Size of the measured node.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct MeasuredSize {
+    pub(crate) width: f64,
+    pub(crate) height: f64,
+    /// Measurer's generation in which the size was measured.
+    /// Sizes of different generations may not be comparable (e.g. fonts have changed).
+    pub(crate) generation: u64,
 }
 
 struct FutureState {
     measurement: Measurement,
     waker: Option<Waker>,
+    /// Callback registered by `Measurer::measure_then()`
+    on_complete: Option<OnComplete>,
+    /// Time of the future's creation (not recorded for backends that do not render)
+    created_at: Option<f64>,
+    /// Whether the future has already been reported as leaked
+    leak_reported: bool,
+    /// Set by `Measurer::clear()`
+    cancelled: bool,
 }
 
+/// Resolves when the measurement is rendered.
+///
+/// The future's state holds a clone of its [`Measurement`], so the hidden node is rendered
+/// as long as either the future or any clone of the measurement is alive.
+/// When the future is dropped before completion and it was the last owner of the measurement,
+/// the measurer is signalled to render again, so the orphaned node disappears promptly.
+/// If the caller still holds the measurement, the node stays rendered until it is dropped.
 pub(crate) struct MeasureFuture {
     state: Rc<RefCell<FutureState>>,
+    /// Not set for backends that do not render
+    msg_sender: Option<MsgSender>,
+}
+
+/// Pending measurement of [`Measurer::measure_handle`] which can be inspected
+/// and cancelled while it is awaited.
+///
+/// Awaiting it yields the [`Measurement`] like [`Measurer::measure`] does.
+pub(crate) struct MeasurementHandle {
+    future: MeasureFuture,
+    /// Whether the measurer has been asked to render (on the first poll)
+    render_requested: bool,
+}
+
+struct NextUpdateFuture {
+    measurement: Measurement,
+    generation: u64,
 }
 
 #[derive(Debug)]
 pub enum Msg {
     WaitForRender,
     Measured,
+    /// Seed has not rendered the pass within `FALLBACK_FRAMES` animation frames
+    /// (e.g. because of `orders.skip()` elsewhere), so measurements are resolved anyway.
+    MeasuredFallback(u64),
+    /// `MeasurerConfig::render_watchdog` has elapsed since the pass was requested.
+    RenderTimeout(u64),
+    /// Pending measure future has been dropped and its measurement is no longer needed.
+    Cancelled,
+    CheckLeaks,
     MeasuredElementMessage,
 }
 
 impl Measurer {
-    pub(crate) fn new(msg_sender: Rc<dyn Fn(Msg)>) -> Self {
+    pub(crate) fn new(msg_sender: MsgSender) -> Self {
+        Self::with_config(msg_sender, MeasurerConfig::default())
+    }
+
+    pub(crate) fn with_config(msg_sender: MsgSender, config: MeasurerConfig) -> Self {
+        Self::create(msg_sender, config, Rc::new(DomBackend))
+    }
+
+    /// Creates measurer which renders measurements into `document.body` by itself,
+    /// so it does not need to be mounted nor updated (see [`PortalBackend`]).
+    #[allow(dead_code)]
+    pub(crate) fn new_portaled() -> Self {
+        Self::with_backend(PortalBackend::new(None))
+    }
+
+    /// Like [`Measurer::new_portaled`], but renders measurements into `target`,
+    /// e.g. the shadow root of a web component, so styles scoped to it apply.
+    #[allow(dead_code)]
+    pub(crate) fn new_portaled_into(target: web_sys::Node) -> Self {
+        Self::with_backend(PortalBackend::new(Some(target)))
+    }
+
+    /// Creates measurer for environments without DOM nor Seed's render loop (e.g. tests
+    /// or SSR), driven by [`Measurer::drive_once`] instead of [`Measurer::update`].
+    ///
+    /// Nothing is rendered: measurements are handed to `backend` (e.g. [`FakeBackend`])
+    /// only when `drive_once` is called, so the caller decides when they complete.
+    /// Measurements provide their size ([`Measurement::size`]) then, but no rendered nodes.
+    /// Attached elements ([`Measurer::attach`]) need a DOM.
+    #[allow(dead_code)]
+    pub(crate) fn new_headless(backend: impl MeasurerBackend + 'static) -> Self {
+        let measurer = Self::with_backend(backend);
+        measurer.data.borrow_mut().headless = true;
+        measurer
+    }
+
+    /// Creates measurer resolving measurements with given backend instead of DOM,
+    /// e.g. [`FakeBackend`] in tests. Such measurer does not need to be mounted nor updated.
+    pub(crate) fn with_backend(backend: impl MeasurerBackend + 'static) -> Self {
+        Self::create(
+            Rc::new(|_: Msg| {}),
+            MeasurerConfig::default(),
+            Rc::new(backend),
+        )
+    }
+
+    fn create(
+        msg_sender: MsgSender,
+        config: MeasurerConfig,
+        backend: Rc<dyn MeasurerBackend>,
+    ) -> Self {
+        let wait_for_fonts = config.wait_for_fonts;
         let data = MeasurerData {
             futures: Vec::new(),
+            callback_futures: Vec::new(),
+            remeasurements: Vec::new(),
+            generation: 0,
             measurements: Vec::new(),
             msg_sender,
+            pass: 0,
+            viewed_pass: 0,
+            measured_pass: 0,
+            fallback_pass: 0,
+            next_measurement_id: NextId::new(0..u64::MAX),
+            config,
+            fonts_ready: Cell::new(!wait_for_fonts),
+            leak_checker: None,
+            portal_target: None,
+            wrapper: ElRef::new(),
+            backend,
+            name: "measurer",
+            completion_senders: Vec::new(),
+            headless: false,
+            deferred: Vec::new(),
+            render_buffer: Vec::new(),
         };
-        Self {
+        let measurer = Self {
             data: Rc::new(RefCell::new(data)),
+        };
+        if wait_for_fonts {
+            measurer.wait_for_fonts();
         }
+        measurer
+    }
+
+    /// Sets `fonts_ready` when `document.fonts.ready` resolves
+    /// and then renders measurements that were waiting for it.
+    fn wait_for_fonts(&self) {
+        let data = Rc::downgrade(&self.data);
+        let fonts = seed::document().fonts();
+        let ready = if fonts.is_undefined() {
+            Err(JsValue::UNDEFINED)
+        } else {
+            fonts.ready()
+        };
+
+        platform::spawn_local(async move {
+            match ready {
+                Ok(promise) => {
+                    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+                }
+                Err(_) => {
+                    let name = data.upgrade().map_or("measurer", |data| data.borrow().name);
+                    platform::error(&format!(
+                        "[{name}] FontFaceSet API is unavailable, measuring without waiting for fonts"
+                    ));
+                }
+            }
+            if let Some(data) = data.upgrade() {
+                let guard = data.borrow();
+                guard.fonts_ready.set(true);
+                let flush_needed = !guard.futures.is_empty();
+                let msg_sender = Rc::clone(&guard.msg_sender);
+                drop(guard);
+
+                // Flush measurements collected before fonts were ready
+                if flush_needed {
+                    msg_sender(Msg::WaitForRender);
+                }
+            }
+        });
     }
 
     /// Gets node to display hiddenly and returns displayed element asynchronously
     /// for measurements.
-    pub(crate) fn measure(&self, text: String) -> impl Future<Output = Measurement> {
-        let measurement = Measurement::new(text);
+    ///
+    /// Fails with [`MeasureError::Cancelled`] if the measurement is cancelled
+    /// (e.g. by [`Measurer::clear`]), as do all other ways of measuring.
+    pub(crate) fn measure(
+        &self,
+        text: String,
+    ) -> impl Future<Output = Result<Measurement, MeasureError>> {
+        self.measure_with(text, MeasureOptions::default())
+    }
+
+    /// Starts building a measurement of `text` with custom options.
+    ///
+    /// ```ignore
+    /// let measurement = measurer.build(text).width(240.0).await?;
+    /// ```
+    pub(crate) fn build(&self, text: String) -> MeasureRequest {
+        MeasureRequest {
+            measurer: self.clone(),
+            text,
+            options: MeasureOptions::default(),
+            timeout: None,
+        }
+    }
+
+    /// Like [`Measurer::measure`], but with custom options.
+    pub(crate) fn measure_with(
+        &self,
+        text: String,
+        options: MeasureOptions,
+    ) -> impl Future<Output = Result<Measurement, MeasureError>> {
+        self.measure_content(Content::Text(text), options)
+    }
+
+    /// Like [`Measurer::measure`], but rendered before less urgent measurements
+    /// when [`MeasurerConfig::max_new_per_pass`] is reached.
+    #[allow(dead_code)]
+    pub(crate) fn measure_with_priority(
+        &self,
+        text: String,
+        priority: i32,
+    ) -> impl Future<Output = Result<Measurement, MeasureError>> {
+        let options = MeasureOptions {
+            priority,
+            ..MeasureOptions::default()
+        };
+        self.measure_content(Content::Text(text), options)
+    }
+
+    /// Like [`Measurer::measure`], but the measured node has given `style`
+    /// (e.g. to measure the text in bold).
+    #[allow(dead_code)]
+    pub(crate) fn measure_styled(
+        &self,
+        text: String,
+        style: Style,
+    ) -> impl Future<Output = Result<Measurement, MeasureError>> {
+        let options = MeasureOptions {
+            style: Some(style),
+            ..MeasureOptions::default()
+        };
+        self.measure_with(text, options)
+    }
+
+    /// Gets raw HTML markup to display hiddenly and returns it asynchronously for measurements.
+    ///
+    /// The markup is inserted into DOM as is, so it must be already sanitized.
+    /// [`Measurement::get`] returns the container of the markup, as it may have
+    /// many top-level elements - use [`Measurement::children_rects`] to measure them.
+    #[allow(dead_code)]
+    pub(crate) fn measure_html(
+        &self,
+        html: String,
+    ) -> impl Future<Output = Result<Measurement, MeasureError>> {
+        self.measure_content(Content::Html(html), MeasureOptions::default())
+    }
+
+    /// Gets node to display hiddenly and returns it asynchronously for measurements.
+    ///
+    /// Nodes from the application's view should be passed through [`sanitize_for_measurement`]
+    /// first. [`Measurement::get`] returns the node itself.
+    #[allow(dead_code)]
+    pub(crate) fn measure_node(
+        &self,
+        node: Node<()>,
+    ) -> impl Future<Output = Result<Measurement, MeasureError>> {
+        self.measure_content(Content::Node(Box::new(node)), MeasureOptions::default())
+    }
+
+    /// Like [`Measurer::measure_node`], but the node is built by `view` each time
+    /// the measurement is rendered, e.g. from state which may change meanwhile.
+    ///
+    /// `view` may use the measurer (e.g. to render its other measurements).
+    #[allow(dead_code)]
+    pub(crate) fn measure_view(
+        &self,
+        view: impl Fn() -> Node<()> + 'static,
+    ) -> impl Future<Output = Result<Measurement, MeasureError>> {
+        let content = Content::View(NodeView(Box::new(view)));
+        self.measure_content(content, MeasureOptions::default())
+    }
+
+    fn measure_content(
+        &self,
+        content: Content,
+        options: MeasureOptions,
+    ) -> impl Future<Output = Result<Measurement, MeasureError>> {
+        let (state, msg_sender) = self.enqueue(content, options, None);
+
+        async move {
+            if let Some(msg_sender) = &msg_sender {
+                msg_sender(Msg::WaitForRender);
+            }
+            MeasureFuture { state, msg_sender }.await
+        }
+    }
+
+    /// Measures an element rendered by the application instead of rendering a hidden node.
+    ///
+    /// Resolves right away if the element is rendered already, otherwise after
+    /// the next render in which it appears. [`Measurement::get`] returns the element itself.
+    #[allow(dead_code)]
+    pub(crate) fn attach(
+        &self,
+        el_ref: ElRef<HtmlElement>,
+    ) -> impl Future<Output = Result<Measurement, MeasureError>> {
+        let rendered = el_ref.get().is_some();
+        let (state, msg_sender) =
+            self.enqueue(Content::Attached(el_ref), MeasureOptions::default(), None);
+        async move {
+            if let (false, Some(msg_sender)) = (rendered, &msg_sender) {
+                msg_sender(Msg::WaitForRender);
+            }
+            MeasureFuture { state, msg_sender }.await
+        }
+    }
+
+    /// Like [`Measurer::measure`], but the returned future can also query and cancel
+    /// the measurement.
+    #[allow(dead_code)]
+    pub(crate) fn measure_handle(&self, text: String) -> MeasurementHandle {
+        let (state, msg_sender) =
+            self.enqueue(Content::Text(text), MeasureOptions::default(), None);
+        MeasurementHandle {
+            future: MeasureFuture { state, msg_sender },
+            render_requested: false,
+        }
+    }
+
+    /// Measures all `texts` and returns measurements in the same order.
+    pub(crate) fn measure_all(
+        &self,
+        texts: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Measurement>, MeasureError>> {
+        future::try_join_all(texts.into_iter().map(|text| self.measure(text)))
+    }
+
+    /// Measures all unique `texts` (each is rendered once) and returns their bounding rectangles.
+    #[allow(dead_code)]
+    pub(crate) fn measure_rects(
+        &self,
+        mut texts: Vec<String>,
+    ) -> impl Future<Output = Result<HashMap<String, DomRect>, MeasureError>> {
+        texts.sort_unstable();
+        texts.dedup();
+        self.measure_all(texts.clone()).map_ok(move |measurements| {
+            texts
+                .into_iter()
+                .zip(measurements)
+                .map(|(text, measurement)| (text, measurement.get().get_bounding_client_rect()))
+                .collect()
+        })
+    }
+
+    /// Like [`Measurer::measure_all`], but gives up on measurements not completed
+    /// within `total_ms` milliseconds (e.g. content that never lays out).
+    ///
+    /// Returns measurements (`None` for timed-out ones) and indices of timed-out texts.
+    /// Timed-out measurements are cancelled. Cancelled measurements count as timed out.
+    #[allow(dead_code)]
+    pub(crate) fn measure_all_deadline(
+        &self,
+        texts: Vec<String>,
+        total_ms: u32,
+    ) -> impl Future<Output = (Vec<Option<Measurement>>, Vec<usize>)> {
+        let slots = Rc::new(RefCell::new(vec![None; texts.len()]));
+        let futures = texts.into_iter().enumerate().map(|(index, text)| {
+            let slots = Rc::clone(&slots);
+            self.measure(text).map(move |measurement| {
+                slots.borrow_mut()[index] = measurement.ok();
+            })
+        });
+        let all = future::join_all(futures);
+        let deadline = platform::timeout(total_ms);
+        let measurer = self.clone();
+
+        async move {
+            if let Either::Right((_, all)) = future::select(Box::pin(all), Box::pin(deadline)).await
+            {
+                // Drop pending futures, so their states can be pruned below
+                drop(all);
+                measurer
+                    .data
+                    .borrow_mut()
+                    .futures
+                    .retain(|state| state.strong_count() > 0);
+            }
+            let slots = slots.take();
+            let timed_out = slots
+                .iter()
+                .enumerate()
+                .filter_map(|(index, slot)| slot.is_none().then_some(index))
+                .collect();
+            (slots, timed_out)
+        }
+    }
+
+    /// Measures all `items` and inserts each measurement into `sink` (under its key)
+    /// as soon as it is rendered. The returned future completes when all items are measured.
+    ///
+    /// Entries are inserted only when complete, so dropping the future before completion
+    /// leaves `sink` with a subset of (valid) measurements and the remaining ones are cancelled.
+    #[allow(dead_code)]
+    pub(crate) fn measure_batch_into<K: Eq + Hash + 'static>(
+        &self,
+        items: Vec<(K, String)>,
+        sink: Rc<RefCell<HashMap<K, Measurement>>>,
+    ) -> impl Future<Output = ()> {
+        let futures = items.into_iter().map(|(key, text)| {
+            let sink = Rc::clone(&sink);
+            self.measure(text).map(move |measurement| {
+                if let Ok(measurement) = measurement {
+                    sink.borrow_mut().insert(key, measurement);
+                }
+            })
+        });
+        futures::future::join_all(futures).map(|_| ())
+    }
+
+    /// Gets node to display hiddenly and calls `on_complete` with the rendered measurement.
+    ///
+    /// This is an alternative to [`Measurer::measure`] usable outside of `async` code,
+    /// e.g. directly in `update`. The callback is invoked after the `Measured` pass
+    /// (not within it), so it may freely use the measurer again.
+    ///
+    /// If the measurement never completes (e.g. it is cancelled or the measurer is dropped),
+    /// the callback is dropped without being invoked.
+    #[allow(dead_code)]
+    pub(crate) fn measure_then(
+        &self,
+        text: String,
+        on_complete: impl FnOnce(Measurement) + 'static,
+    ) {
+        // Backends that do not render may complete the measurement right away
+        let (_state, msg_sender) = self.enqueue(
+            Content::Text(text),
+            MeasureOptions::default(),
+            Some(Box::new(on_complete)),
+        );
+
+        if let Some(msg_sender) = msg_sender {
+            platform::spawn_local(async move {
+                msg_sender(Msg::WaitForRender);
+            });
+        }
+    }
+
+    /// Registers new measurement to be rendered and returns its future's state.
+    /// The state is kept alive by the measurer until `on_complete` (if any) is invoked.
+    ///
+    /// Returned message sender should be used to request rendering (if the backend renders).
+    fn enqueue(
+        &self,
+        content: Content,
+        options: MeasureOptions,
+        on_complete: Option<OnComplete>,
+    ) -> (Rc<RefCell<FutureState>>, Option<MsgSender>) {
+        let mut guard = self.data.borrow_mut();
+        let data = &mut *guard;
+        let id = MeasurementId(data.next_measurement_id.allocate(|id| {
+            data.measurements.iter().any(|weak| {
+                weak.0
+                    .upgrade()
+                    .is_some_and(|measurement| measurement.id.0 == id)
+            })
+        }));
+        // Attached elements are measured in DOM whatever the backend is
+        let renders = guard.backend.renders() || matches!(content, Content::Attached(_));
+        let measurement = Measurement::new(
+            id,
+            content,
+            options,
+            guard.generation,
+            Rc::downgrade(&self.data),
+        );
+        if guard.config.perf_marks {
+            perf_mark(id, "queue");
+        }
+        let has_callback = on_complete.is_some();
         let state = Rc::new(RefCell::new(FutureState {
             measurement: measurement.clone(),
             waker: None,
+            on_complete,
+            created_at: if renders { now() } else { None },
+            leak_reported: false,
+            cancelled: false,
         }));
+        if has_callback {
+            guard.callback_futures.push(Rc::clone(&state));
+        }
+
+        if !renders && guard.headless {
+            guard.deferred.push(Rc::downgrade(&state));
+            return (state, None);
+        }
+        if !renders {
+            let backend = Rc::clone(&guard.backend);
+            drop(guard);
+            let resolver = SizeResolver(Rc::downgrade(&state));
+            backend.enqueue(&measurement, resolver);
+            return (state, None);
+        }
 
-        let mut guard = self.data.borrow_mut();
         let msg_sender = Rc::clone(&guard.msg_sender);
         guard.measurements.push(measurement.downgrade());
         guard.futures.push(Rc::downgrade(&state));
         drop(guard);
 
-        async move {
-            msg_sender(Msg::WaitForRender);
-            MeasureFuture { state }.await
+        (state, Some(msg_sender))
+    }
+
+    /// Marks all alive measurements as stale (e.g. after fonts or theme have changed).
+    ///
+    /// Measurements created with [`MeasureOptions::auto_remeasure`] are measured again
+    /// in the next render pass (reusing their rendered nodes), which can be awaited
+    /// with [`Measurement::next_update`]. Other measurements stay stale forever.
+    #[allow(dead_code)]
+    pub(crate) fn invalidate_all(&self) {
+        let mut guard = self.data.borrow_mut();
+        guard.generation += 1;
+
+        // Keep measurements queued by `Measurement::invalidate()`
+        let data = &mut *guard;
+        for measurement in data
+            .measurements
+            .iter()
+            .filter_map(WeakMeasurement::upgrade)
+        {
+            *measurement.0.stale.borrow_mut() = true;
+            let queued = data
+                .remeasurements
+                .iter()
+                .any(|weak| Weak::ptr_eq(&weak.0, &Rc::downgrade(&measurement.0)));
+            if measurement.0.options.auto_remeasure && !queued {
+                data.remeasurements.push(measurement.downgrade());
+            }
+        }
+
+        if !guard.remeasurements.is_empty() {
+            let msg_sender = Rc::clone(&guard.msg_sender);
+            platform::spawn_local(async move {
+                msg_sender(Msg::WaitForRender);
+            });
+        }
+    }
+
+    /// Measures all alive measurements again, e.g. after the window has been resized,
+    /// so wrapped text changes its size. Resolves when all of them have fresh geometry.
+    ///
+    /// Unlike [`Measurer::invalidate_all`], it does not depend on
+    /// [`MeasureOptions::auto_remeasure`]. Holders of measurements observe fresh geometry
+    /// through their clones, and updated measurements are yielded again by
+    /// [`Measurer::completions`] (and [`Measurer::as_stream`]):
+    ///
+    /// ```ignore
+    /// // In `init`:
+    /// orders.stream(streams::window_event(Ev::Resize, |_| Msg::Resized));
+    /// // In `update`:
+    /// Msg::Resized => {
+    ///     orders.perform_cmd(model.measurer.remeasure_all());
+    /// }
+    /// ```
+    #[allow(dead_code)]
+    pub(crate) fn remeasure_all(&self) -> impl Future<Output = ()> {
+        let measurements = self
+            .data
+            .borrow()
+            .measurements
+            .iter()
+            .filter_map(WeakMeasurement::upgrade)
+            .collect::<Vec<_>>();
+        let updates = measurements
+            .iter()
+            .map(Measurement::invalidate)
+            .collect::<Vec<_>>();
+        future::join_all(updates).map(|_| ())
+    }
+
+    /// Returns ids of measurements pending for longer than `age`.
+    ///
+    /// Long pending measurements usually mean that something went wrong, e.g. the view
+    /// is not mounted or a future has been forgotten by its executor.
+    #[allow(dead_code)]
+    pub(crate) fn pending_older_than(&self, age: Duration) -> Vec<MeasurementId> {
+        let Some(now) = now() else {
+            return Vec::new();
+        };
+        let age = age.as_secs_f64() * 1000.0;
+        self.data
+            .borrow()
+            .futures
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter_map(|state| {
+                let state = state.borrow();
+                let created_at = state.created_at?;
+                (now - created_at >= age).then_some(state.measurement.0.id)
+            })
+            .collect()
+    }
+
+    /// Warns about newly leaked futures and stops checking when none is pending.
+    fn check_leaks(&self) {
+        let mut guard = self.data.borrow_mut();
+        guard.futures.retain(|state| state.strong_count() > 0);
+        if guard.futures.is_empty() {
+            guard.leak_checker = None;
+            return;
+        }
+        let Some(now) = now() else {
+            return;
+        };
+        let threshold = guard.config.leak_threshold.as_secs_f64() * 1000.0;
+
+        let mut leaked = Vec::new();
+        for state in guard.futures.iter().filter_map(Weak::upgrade) {
+            let mut state = state.borrow_mut();
+            let too_old = state
+                .created_at
+                .is_some_and(|created_at| now - created_at >= threshold);
+            if too_old && !state.leak_reported {
+                state.leak_reported = true;
+                let measurement = &state.measurement.0;
+                leaked.push(format!("{} {:?}", measurement.id, measurement.content));
+            }
+        }
+        if !leaked.is_empty() {
+            platform::warn(&format!(
+                "[{}] Measurements pending for more than {:?}: {}",
+                guard.name,
+                guard.config.leak_threshold,
+                leaked.join(", ")
+            ));
+        }
+    }
+
+    /// Removes all marks and measures from the browser's Performance timeline,
+    /// e.g. after a profiling session with [`MeasurerConfig::perf_marks`].
+    #[allow(dead_code)]
+    pub(crate) fn clear_perf_marks(&self) {
+        if let Some(performance) = seed::window().performance() {
+            performance.clear_marks();
+            performance.clear_measures();
+        }
+    }
+
+    /// Moves the hidden measurements rendered by [`Measurer::view`] into `target`
+    /// (e.g. a container appended to `document.body` with baseline styling),
+    /// so they do not inherit styles from where the view is mounted.
+    ///
+    /// The view still has to be mounted - it is moved after each render,
+    /// before measurements complete. As Seed keeps patching the moved nodes,
+    /// the view should be the last child of its parent.
+    #[allow(dead_code)]
+    pub(crate) fn with_portal_target(self, target: Element) -> Self {
+        self.data.borrow_mut().portal_target = Some(target);
+        self
+    }
+
+    /// Abandons all measurements at once, e.g. when leaving a screen.
+    ///
+    /// Pending futures are cancelled: they resolve with [`MeasureError::Cancelled`]
+    /// and callbacks of [`Measurer::measure_then`] are dropped. All hidden nodes (including those
+    /// of completed measurements) disappear in the next render, so measurements
+    /// created so far must not be used anymore.
+    #[allow(dead_code)]
+    pub(crate) fn clear(&self) {
+        let mut guard = self.data.borrow_mut();
+        guard.measurements.clear();
+        guard.remeasurements.clear();
+        guard.callback_futures.clear();
+        let mut futures = std::mem::take(&mut guard.futures);
+        futures.append(&mut guard.deferred);
+        let msg_sender = Rc::clone(&guard.msg_sender);
+        drop(guard);
+
+        for state in futures.iter().filter_map(Weak::upgrade) {
+            let mut state = state.borrow_mut();
+            state.cancelled = true;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
         }
+        // Render again, so the nodes disappear
+        platform::spawn_local(async move {
+            msg_sender(Msg::Cancelled);
+        });
+    }
+
+    /// Returns stream of all measurements completed from now on (as they are woken up),
+    /// e.g. for a debug overlay observing the measurer without owning the futures.
+    /// Measurements measured again (e.g. by [`Measurer::remeasure_all`]) are yielded again.
+    ///
+    /// Measurements yielded by the stream are kept rendered until they are dropped.
+    pub(crate) fn completions(&self) -> impl Stream<Item = Measurement> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.data.borrow_mut().completion_senders.push(sender);
+        receiver
+    }
+
+    /// Same as [`Measurer::completions`], meant to be subscribed by the application
+    /// with `orders.stream_with_handle`, so completed measurements arrive as messages:
+    ///
+    /// ```ignore
+    /// // In `init` (keep the handle in the model, the subscription ends when it is dropped):
+    /// let handle = orders.stream_with_handle(measurer.as_stream().map(Msg::Measured));
+    /// // In `update`:
+    /// Msg::Measured(measurement) => model.sizes.push(measurement.size()),
+    /// ```
+    #[allow(dead_code)]
+    pub(crate) fn as_stream(&self) -> impl Stream<Item = Measurement> + 'static {
+        self.completions()
+    }
+
+    /// Names the measurer, so its log messages can be told apart from other measurers'.
+    pub(crate) fn with_name(self, name: &'static str) -> Self {
+        self.data.borrow_mut().name = name;
+        self
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        self.data.borrow().name
     }
 
+    /// Renders hidden measurements wrapped in a single `div`.
+    ///
+    /// It must be mounted in the application's view, otherwise measurements never complete.
     pub(crate) fn view(&self) -> Node<Msg> {
+        let guard = self.data.borrow();
+        let wrapper = el_ref(&guard.wrapper);
+        let portal_style = guard.portal_target.as_ref().map(|_| {
+            style! {
+                St::Position => "absolute",
+                St::Top => "0",
+                St::Left => "0",
+                St::Visibility => "hidden",
+            }
+        });
+        drop(guard);
+
+        div![wrapper, portal_style, self.view_into()]
+    }
+
+    /// Moves wrapper rendered by `view()` into the portal target (if set).
+    fn move_to_portal(&self) {
+        let guard = self.data.borrow();
+        if let (Some(target), Some(wrapper)) = (&guard.portal_target, guard.wrapper.get()) {
+            let wrapper: web_sys::Node = wrapper.into();
+            if !target.contains(Some(&wrapper)) {
+                let _ = target.append_child(&wrapper);
+            }
+        }
+    }
+
+    /// Renders hidden measurements without a wrapper, so they can be placed
+    /// inside an existing (hidden) container.
+    pub(crate) fn view_into(&self) -> Vec<Node<Msg>> {
         let mut guard = self.data.borrow_mut();
+        guard.viewed_pass = guard.pass;
 
-        // Filter-out disposed measurements
-        let (filtered_measurements, measurements_to_render): (Vec<_>, Vec<_>) = guard
-            .measurements
-            .drain(..)
-            .filter_map(|w| w.upgrade().map(move |m| (w, m)))
-            .unzip();
-        guard.measurements = filtered_measurements;
+        // Filter-out disposed measurements in place
+        let mut to_render = std::mem::take(&mut guard.render_buffer);
+        guard.measurements.retain(|w| {
+            let Some(m) = w.upgrade() else {
+                return false;
+            };
+            to_render.push(m);
+            true
+        });
+
+        // Measurements over the limit wait for the next pass, the most urgent ones go first
+        let mut deferred = HashSet::new();
+        if let Some(max_new) = guard.config.max_new_per_pass {
+            let mut new = to_render
+                .iter()
+                .filter(|m| !m.is_attached() && m.0.div.get().is_none())
+                .collect::<Vec<_>>();
+            if new.len() > max_new.get() {
+                new.sort_by_key(|m| Reverse(m.0.options.priority));
+                deferred.extend(new.drain(max_new.get()..).map(|m| Rc::as_ptr(&m.0)));
+            }
+        }
 
-        // Mark that specific measurement is rendered
-        for m in &measurements_to_render {
-            *m.0.rendered.borrow_mut() = true;
+        // Mark rendered ones. Attached elements are rendered by the application, maybe later.
+        let perf_marks = guard.config.perf_marks;
+        to_render.retain(|m| !m.is_attached() && !deferred.contains(&Rc::as_ptr(&m.0)));
+        for m in &to_render {
+            let newly_rendered = !m.0.rendered.replace(true);
+            if perf_marks && newly_rendered {
+                perf_mark(m.0.id, "render");
+                perf_measure(m.0.id, "queue", "render");
+            }
         }
+        // Rendering must not hold the borrow, e.g. measured nodes may use the measurer
+        drop(guard);
 
-        div![measurements_to_render
+        let nodes = to_render
             .iter()
-            .map(|m| m.view().map_msg(|()| Msg::MeasuredElementMessage)),]
+            .map(|m| m.view().map_msg(|()| Msg::MeasuredElementMessage))
+            .collect();
+        to_render.clear();
+        self.data.borrow_mut().render_buffer = to_render;
+        nodes
     }
 
-    pub(crate) fn update(
+    /// Handles measurer's message within application's `update`.
+    ///
+    /// `wrap_msg` maps measurer's messages to application's messages routed back here.
+    pub(crate) fn update<Ms: 'static>(
         &mut self,
         msg: Msg,
-        orders: &mut impl Orders<crate::Msg>,
-        wrap_msg: fn(Msg) -> crate::Msg,
+        orders: &mut impl Orders<Ms>,
+        wrap_msg: impl Fn(Msg) -> Ms + Clone + 'static,
     ) {
         match msg {
             Msg::WaitForRender => {
+                let mut guard = self.data.borrow_mut();
+                guard.pass += 1;
+                if guard.leak_checker.is_none() {
+                    let wrap_msg = wrap_msg.clone();
+                    guard.leak_checker = Some(platform::stream_with_handle(
+                        orders,
+                        platform::interval(LEAK_CHECK_INTERVAL_MS)
+                            .map(move |()| wrap_msg(Msg::CheckLeaks)),
+                    ));
+                }
+                let pass = guard.pass;
+                let render_watchdog = guard.config.render_watchdog;
+                drop(guard);
+                if let Some(timeout) = render_watchdog {
+                    let ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+                    let wrap_msg = wrap_msg.clone();
+                    orders.perform_cmd(
+                        platform::timeout(ms).map(move |()| wrap_msg(Msg::RenderTimeout(pass))),
+                    );
+                }
+                watch_render(Rc::downgrade(&self.data), pass, FALLBACK_FRAMES);
                 orders.after_next_render(move |_| wrap_msg(Msg::Measured));
                 orders.render();
             }
             Msg::Measured => {
                 let mut guard = self.data.borrow_mut();
-                let mut wakers = Vec::new();
-                let mut filtered_futures = Vec::new();
-                for future_state_weak in guard.futures.drain(..) {
-                    if let Some(future_state_ref) = future_state_weak.upgrade() {
-                        let mut future_state = future_state_ref.borrow_mut();
-                        assert_eq!(
-                            future_state.measurement.0.div.get().is_some(),
-                            *future_state.measurement.0.rendered.borrow(),
-                            "Wrongly rendered text: {:?}", &future_state.measurement.0.text
-                        );
-                        if future_state.measurement.0.div.get().is_some() {
-                            if let Some(waker) = future_state.waker.take() {
-                                wakers.push(waker);
-                            }
-                        } else {
-                            filtered_futures.push(future_state_weak);
-                        }
-                    }
+                if guard.fallback_pass == guard.pass {
+                    // `MeasuredFallback` has already resolved this pass
+                    orders.skip();
+                    return;
                 }
-                let wakeup_needed = !filtered_futures.is_empty();
-                guard.futures = filtered_futures;
+                guard.measured_pass = guard.pass;
                 drop(guard);
-
-                if !wakers.is_empty() {
-                    orders.send_msg(crate::Msg::Wake(wakers));
-                }
-
-                if wakeup_needed {
-                    orders.render();
-                } else {
+                self.resolve(orders, false);
+            }
+            Msg::MeasuredFallback(pass) => {
+                let mut guard = self.data.borrow_mut();
+                if pass != guard.pass || guard.measured_pass == pass {
+                    // Seed has rendered in time or a newer pass is waiting for its own render
                     orders.skip();
+                    return;
+                }
+                guard.fallback_pass = pass;
+                drop(guard);
+                if self.resolve(orders, true) {
+                    // Seed has not rendered pending nodes yet, request another pass
+                    orders.send_msg(wrap_msg(Msg::WaitForRender));
+                }
+            }
+            Msg::RenderTimeout(pass) => {
+                orders.skip();
+                let guard = self.data.borrow();
+                if guard.measured_pass >= pass || guard.fallback_pass >= pass {
+                    return;
                 }
+                let message = format!(
+                    "[{}] Render requested {:?} ago has not happened yet, {} measurements are pending",
+                    guard.name,
+                    guard.config.render_watchdog.unwrap_or_default(),
+                    guard.futures.len(),
+                );
+                platform::warn(&message);
+            }
+            Msg::Cancelled => {
+                self.data
+                    .borrow_mut()
+                    .futures
+                    .retain(|state| state.strong_count() > 0);
+                // Orphaned measurements are filtered-out in the view
+                orders.render();
+            }
+            Msg::CheckLeaks => {
+                self.check_leaks();
+                orders.skip();
             }
             Msg::MeasuredElementMessage => {
                 panic!("Measured elements should not generate messages")
             }
         }
     }
-}
 
-impl Measurement {
-    fn new(text: String) -> Self {
-        Self(Rc::new(MeasurementData {
-            text,
-            div: ElRef::new(),
-            rendered: RefCell::new(false),
-        }))
-    }
+    /// Advances a measurer created by [`Measurer::new_headless`]: hands measurements made
+    /// since the last call to the backend, which may complete them right away
+    /// (invoking their callbacks and waking their futures) or later.
+    ///
+    /// Returns whether some of the handed measurements have not completed yet.
+    #[allow(dead_code)]
+    pub(crate) fn drive_once(&self) -> bool {
+        let mut guard = self.data.borrow_mut();
+        let deferred = std::mem::take(&mut guard.deferred);
+        let backend = Rc::clone(&guard.backend);
+        drop(guard);
 
-    fn downgrade(&self) -> WeakMeasurement {
-        WeakMeasurement(Rc::downgrade(&self.0))
+        let states: Vec<_> = deferred
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|state| !state.borrow().cancelled)
+            .collect();
+        for state in &states {
+            let measurement = state.borrow().measurement.clone();
+            backend.enqueue(&measurement, SizeResolver(Rc::downgrade(state)));
+        }
+        states
+            .iter()
+            .any(|state| !state.borrow().measurement.is_ready())
     }
 
-    fn view(&self) -> Node<()> {
-        div![el_ref(&self.0.div), div![&self.0.text]]
-    }
+    /// Wakes up futures of rendered measurements (after `Msg::Measured` or `Msg::MeasuredFallback`).
+    ///
+    /// Returns whether some measurements still wait to be rendered.
+    /// `fallback` passes may come before Seed renders at all, so they cannot tell
+    /// whether `view()` is mounted.
+    fn resolve<Ms: 'static>(&self, orders: &mut impl Orders<Ms>, fallback: bool) -> bool {
+        let Some(Sweep {
+            wakers,
+            callbacks,
+            resolved,
+            wakeup_needed,
+            mounted,
+        }) = self.sweep()
+        else {
+            // Waiting for fonts, pending measurements are flushed when they are ready.
+            orders.skip();
+            return false;
+        };
 
-    /// Returns rendered node
-    pub(crate) fn get(&self) -> Element {
-        let container = self.0.div.get().expect(
-            "Called `Measurement::get()` before future completion (i.e. node was rendered).",
-        );
-        container.first_element_child().unwrap()
+        for (on_complete, measurement) in callbacks {
+            orders.perform_cmd(async move { on_complete(measurement) });
+        }
+
+        if cfg!(debug_assertions) && wakeup_needed && resolved == 0 && !mounted && !fallback {
+            // Rendering again would not help - nobody renders our nodes.
+            platform::error(&format!(
+                "[{}] Measurer::view() does not appear to be mounted",
+                self.name()
+            ));
+            orders.skip();
+            return false;
+        }
+
+        if !wakers.is_empty() {
+            // Woken futures are polled after this update, like callbacks above
+            orders.perform_cmd(async move {
+                for waker in wakers {
+                    waker.wake();
+                }
+            });
+        }
+
+        if wakeup_needed {
+            orders.render();
+        } else {
+            orders.skip();
+        }
+        wakeup_needed
+    }
+
+    /// Completes rendered measurements, returning wakers and callbacks to invoke
+    /// once the borrow is released. Returns `None` while waiting for fonts.
+    fn sweep(&self) -> Option<Sweep> {
+        self.move_to_portal();
+        let mut guard = self.data.borrow_mut();
+        if !guard.fonts_ready.get() {
+            return None;
+        }
+        let mut wakers = Vec::new();
+        let mut callbacks = Vec::new();
+        let mut completed = Vec::new();
+        let mut resolved = 0;
+        let mut filtered_futures = Vec::new();
+        let perf_marks = guard.config.perf_marks;
+        let snapshot_length = guard.config.snapshot_length;
+        let wrapper = guard.wrapper.clone();
+        for future_state_weak in guard.futures.drain(..) {
+            if let Some(future_state_ref) = future_state_weak.upgrade() {
+                let mut future_state = future_state_ref.borrow_mut();
+                let measurement = &future_state.measurement;
+                if !measurement.is_attached()
+                    && measurement.0.div.get().is_some() != *measurement.0.rendered.borrow()
+                {
+                    // Snapshot the measurement or (if not attached) the whole measurer
+                    let snapshot = measurement
+                        .outer_html()
+                        .ok()
+                        .or_else(|| wrapper.get().map(|wrapper| wrapper.outer_html()))
+                        .map(|html| truncate(html, snapshot_length));
+                    panic!(
+                        "Wrongly rendered content: {:?}, DOM snapshot: {:?}",
+                        &measurement.0.content, snapshot
+                    );
+                }
+                if future_state.measurement.0.div.get().is_some() {
+                    resolved += 1;
+                    if perf_marks {
+                        perf_mark(future_state.measurement.0.id, "resolve");
+                        perf_measure(future_state.measurement.0.id, "render", "resolve");
+                    }
+                    if let Some(waker) = future_state.waker.take() {
+                        wakers.push(waker);
+                    }
+                    if let Some(on_complete) = future_state.on_complete.take() {
+                        callbacks.push((on_complete, future_state.measurement.clone()));
+                    }
+                    completed.push(future_state.measurement.clone());
+                } else {
+                    filtered_futures.push(future_state_weak);
+                }
+            }
+        }
+        let generation = guard.generation;
+        guard.remeasurements.retain(|weak| match weak.upgrade() {
+            Some(measurement)
+                if measurement.0.div.get().is_some()
+                    && (measurement.is_attached() || *measurement.0.rendered.borrow()) =>
+            {
+                resolved += 1;
+                *measurement.0.stale.borrow_mut() = false;
+                *measurement.0.generation.borrow_mut() = generation;
+                wakers.append(&mut measurement.0.update_wakers.borrow_mut());
+                completed.push(measurement);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        });
+        guard.completion_senders.retain(|sender| {
+            completed
+                .iter()
+                .all(|measurement| sender.unbounded_send(measurement.clone()).is_ok())
+        });
+        let wakeup_needed = !filtered_futures.is_empty() || !guard.remeasurements.is_empty();
+        let mounted = guard.viewed_pass == guard.pass;
+        guard.futures = filtered_futures;
+        guard
+            .callback_futures
+            .retain(|state| state.borrow().on_complete.is_some());
+        Some(Sweep {
+            wakers,
+            callbacks,
+            resolved,
+            wakeup_needed,
+            mounted,
+        })
+    }
+}
+
+/// Outcome of [`Measurer::sweep`].
+struct Sweep {
+    wakers: Vec<Waker>,
+    callbacks: Vec<(OnComplete, Measurement)>,
+    /// Number of completed measurements (including remeasured ones)
+    resolved: usize,
+    /// Whether some measurements still wait to be rendered
+    wakeup_needed: bool,
+    /// Whether `view()` has been called since the last requested render pass
+    mounted: bool,
+}
+
+/// Counts animation frames after requesting render `pass` and sends `Msg::MeasuredFallback`
+/// if `Msg::Measured` has not arrived in time.
+fn watch_render(data: Weak<RefCell<MeasurerData>>, pass: u64, frames: u32) {
+    platform::spawn_local(async move {
+        for _ in 0..frames {
+            platform::next_animation_frame().await;
+            let Some(data) = data.upgrade() else {
+                return;
+            };
+            let guard = data.borrow();
+            if guard.measured_pass >= pass || guard.pass != pass {
+                return;
+            }
+        }
+        let Some(data) = data.upgrade() else {
+            return;
+        };
+        let msg_sender = Rc::clone(&data.borrow().msg_sender);
+        msg_sender(Msg::MeasuredFallback(pass));
+    });
+}
+
+/// Prepares a node from the application's view for [`Measurer::measure_node`].
+///
+/// Tag, namespace, attributes, styles, texts and children are preserved (recursively).
+/// Event listeners, element references (`el_ref`), keys and insert handlers are stripped,
+/// so the hidden copy never generates messages nor attaches foreign references.
+#[allow(dead_code)]
+pub(crate) fn sanitize_for_measurement<Ms>(node: Node<Ms>) -> Node<()> {
+    match node {
+        Node::Element(el) => {
+            let mut sanitized = El::empty(el.tag);
+            sanitized.namespace = el.namespace;
+            sanitized.attrs = el.attrs;
+            sanitized.style = el.style;
+            sanitized.children = el
+                .children
+                .into_iter()
+                .map(sanitize_for_measurement)
+                .collect();
+            Node::Element(sanitized)
+        }
+        Node::Text(text) => Node::Text(text),
+        Node::Empty => Node::Empty,
+        Node::NoChange => Node::NoChange,
+    }
+}
+
+/// Cuts `text` to at most `max_chars` characters.
+fn truncate(mut text: String, max_chars: usize) -> String {
+    if let Some((index, _)) = text.char_indices().nth(max_chars) {
+        text.truncate(index);
+        text.push('…');
+    }
+    text
+}
+
+//------------------------------------------------------------------------------
+// Registry
+//------------------------------------------------------------------------------
+
+/// Owns several named measurers, each rendered in its own styling context
+/// (e.g. the sidebar and the main column inherit different fonts).
+#[derive(Default)]
+pub(crate) struct MeasurerRegistry {
+    /// Measurers with classes of their contexts, in the order of rendering
+    entries: Vec<(Measurer, &'static str)>,
+}
+
+impl MeasurerRegistry {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds measurer rendered inside a `div` with given classes.
+    /// Messages are routed to it by its [`Measurer::name`], which must be unique.
+    pub(crate) fn insert(&mut self, measurer: Measurer, classes: &'static str) {
+        let name = measurer.name();
+        assert!(
+            self.get(name).is_none(),
+            "Measurer named {name} is already registered"
+        );
+        self.entries.push((measurer, classes));
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Measurer> {
+        self.entries
+            .iter()
+            .map(|(measurer, _)| measurer)
+            .find(|measurer| measurer.name() == name)
+    }
+
+    /// Passes message to the measurer with given name.
+    pub(crate) fn update<Ms: 'static>(
+        &mut self,
+        name: &'static str,
+        msg: Msg,
+        orders: &mut impl Orders<Ms>,
+        wrap_msg: fn(&'static str, Msg) -> Ms,
+    ) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|(measurer, _)| measurer.name() == name)
+        {
+            Some((measurer, _)) => measurer.update(msg, orders, move |msg| wrap_msg(name, msg)),
+            None => platform::error(&format!("Message for unknown measurer: {name}")),
+        }
+    }
+
+    /// Renders views of all measurers, each wrapped with its context's classes.
+    /// Messages are tagged with the name of the measurer.
+    pub(crate) fn view(&self) -> Vec<Node<(&'static str, Msg)>> {
+        self.entries
+            .iter()
+            .map(|(measurer, classes)| {
+                let name = measurer.name();
+                div![
+                    C![*classes],
+                    measurer.view().map_msg(move |msg| (name, msg))
+                ]
+            })
+            .collect()
+    }
+}
+
+//------------------------------------------------------------------------------
+// Backends
+//------------------------------------------------------------------------------
+
+/// Provides geometry of measurements.
+pub(crate) trait MeasurerBackend {
+    /// Whether measurements are rendered by [`Measurer::view`] and measured in DOM.
+    fn renders(&self) -> bool;
+
+    /// Called for each new measurement when the backend does not render.
+    /// The measurement completes when `resolver` is called.
+    fn enqueue(&self, measurement: &Measurement, resolver: SizeResolver);
+}
+
+/// Completes a measurement with the given size.
+pub(crate) struct SizeResolver(Weak<RefCell<FutureState>>);
+
+/// Default backend measuring nodes rendered in DOM by [`Measurer::view`].
+pub(crate) struct DomBackend;
+
+/// Backend rendering measurements into hidden elements appended to `document.body`
+/// (or to the target node, e.g. a shadow root, so its scoped styles apply),
+/// so the measurer does not have to be mounted in the application's view.
+///
+/// Measurements are rendered like by [`Measurer::view`], so their nodes can be read
+/// ([`Measurement::get`] etc.) and their sizes are read from DOM, not cached.
+/// Hidden elements of dropped measurements are removed when the next measurement is enqueued.
+pub(crate) struct PortalBackend {
+    target: Option<web_sys::Node>,
+    /// Hidden elements holding rendered measurements
+    hosts: RefCell<Vec<(WeakMeasurement, Element)>>,
+}
+
+/// Backend resolving measurements with sizes registered by tests, without touching DOM.
+///
+/// Cloning this struct is cheap and all clones share registered sizes.
+#[derive(Clone, Default)]
+pub(crate) struct FakeBackend(Rc<RefCell<FakeBackendData>>);
+
+#[derive(Default)]
+struct FakeBackendData {
+    sizes: HashMap<String, (f64, f64)>,
+    /// Resolve measurements only in `flush()`
+    manual_flush: bool,
+    /// Measurements waiting for flush or for their sizes to be registered
+    pending: Vec<(String, SizeResolver)>,
+}
+
+impl SizeResolver {
+    pub(crate) fn resolve(self, width: f64, height: f64) {
+        self.complete(Some((width, height)));
+    }
+
+    /// Completes a measurement whose node has been rendered by the backend,
+    /// so its geometry is read from DOM (and stays fresh when the node reflows).
+    pub(crate) fn resolve_rendered(self) {
+        self.complete(None);
+    }
+
+    fn complete(self, size: Option<(f64, f64)>) {
+        let Some(state) = self.0.upgrade() else {
+            // Measurement has been cancelled
+            return;
+        };
+        let mut state = state.borrow_mut();
+        *state.measurement.0.backend_size.borrow_mut() = size;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        let on_complete = state.on_complete.take();
+        let measurement = state.measurement.clone();
+        drop(state);
+
+        if let Some(on_complete) = on_complete {
+            // Nothing keeps the state alive anymore (it is pruned by sweeps only when rendering)
+            if let Some(data) = measurement.0.measurer.upgrade() {
+                data.borrow_mut()
+                    .callback_futures
+                    .retain(|state| state.borrow().on_complete.is_some());
+            }
+            on_complete(measurement);
+        }
+    }
+}
+
+impl MeasurerBackend for DomBackend {
+    fn renders(&self) -> bool {
+        true
+    }
+
+    fn enqueue(&self, _measurement: &Measurement, _resolver: SizeResolver) {
+        unreachable!("DomBackend renders measurements")
+    }
+}
+
+impl MeasurerBackend for PortalBackend {
+    fn renders(&self) -> bool {
+        false
+    }
+
+    fn enqueue(&self, measurement: &Measurement, resolver: SizeResolver) {
+        let mut hosts = self.hosts.borrow_mut();
+        hosts.retain(|(measurement, host)| {
+            let alive = measurement.0.strong_count() > 0;
+            if !alive {
+                host.remove();
+            }
+            alive
+        });
+
+        let document = seed::document();
+        let target = match &self.target {
+            Some(target) => Some(target.clone()),
+            None => document.body().map(Into::into),
+        };
+        let (Some(target), Ok(host), Some(container)) = (
+            target,
+            document.create_element("div"),
+            create_element(&document, &measurement.view()),
+        ) else {
+            platform::error("Cannot render portaled measurement");
+            return;
+        };
+        let _ = host.set_attribute(
+            "style",
+            "position: absolute; top: 0; left: 0; visibility: hidden;",
+        );
+        let _ = host.append_child(&container);
+        let _ = target.append_child(&host);
+        // Read through the el_ref like nodes rendered by `Measurer::view`
+        measurement
+            .0
+            .div
+            .shared_node_ws
+            .clone()
+            .set(container.into());
+        hosts.push((measurement.downgrade(), host));
+        drop(hosts);
+
+        resolver.resolve_rendered();
+    }
+}
+
+impl PortalBackend {
+    fn new(target: Option<web_sys::Node>) -> Self {
+        Self {
+            target,
+            hosts: RefCell::default(),
+        }
+    }
+}
+
+/// Creates DOM of `node` (without event handlers and el_refs), like Seed does
+/// when rendering it for the first time. Returns `None` if `node` is not an element.
+fn create_element(document: &web_sys::Document, node: &Node<()>) -> Option<Element> {
+    let Node::Element(el) = node else {
+        return None;
+    };
+    let element = match &el.namespace {
+        Some(namespace) => document.create_element_ns(Some(namespace.as_str()), el.tag.as_str()),
+        None => document.create_element(el.tag.as_str()),
+    }
+    .ok()?;
+    for (name, value) in &el.attrs.vals {
+        let value = match value {
+            AtValue::Some(value) => value.as_str(),
+            AtValue::None => "",
+            AtValue::Ignored => continue,
+        };
+        let _ = element.set_attribute(name.as_str(), value);
+    }
+    let style = el.style.to_string();
+    if !style.is_empty() {
+        let _ = element.set_attribute("style", &style);
+    }
+    for child in &el.children {
+        match child {
+            Node::Text(text) => {
+                let _ = element.append_with_str_1(&text.text);
+            }
+            child => {
+                if let Some(child) = create_element(document, child) {
+                    let _ = element.append_child(&child);
+                }
+            }
+        }
+    }
+    Some(element)
+}
+
+impl FakeBackend {
+    #[allow(dead_code)]
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Measurements are resolved only by [`FakeBackend::flush`] instead of immediately.
+    #[allow(dead_code)]
+    pub(crate) fn manual_flush(self) -> Self {
+        self.0.borrow_mut().manual_flush = true;
+        self
+    }
+
+    /// Registers size of measurements of `content`.
+    #[allow(dead_code)]
+    pub(crate) fn set_size(&self, content: impl Into<String>, width: f64, height: f64) {
+        let mut guard = self.0.borrow_mut();
+        guard.sizes.insert(content.into(), (width, height));
+        let manual_flush = guard.manual_flush;
+        drop(guard);
+
+        if !manual_flush {
+            self.flush();
+        }
+    }
+
+    /// Resolves pending measurements with registered sizes.
+    pub(crate) fn flush(&self) {
+        let mut guard = self.0.borrow_mut();
+        let data = &mut *guard;
+        let mut resolved = Vec::new();
+        let mut pending = Vec::new();
+        for (content, resolver) in data.pending.drain(..) {
+            match data.sizes.get(&content) {
+                Some(&size) => resolved.push((resolver, size)),
+                None => pending.push((content, resolver)),
+            }
+        }
+        data.pending = pending;
+        drop(guard);
+
+        // Resolve without the borrow, as callbacks may measure again
+        for (resolver, (width, height)) in resolved {
+            resolver.resolve(width, height);
+        }
+    }
+}
+
+impl MeasurerBackend for FakeBackend {
+    fn renders(&self) -> bool {
+        false
+    }
+
+    fn enqueue(&self, measurement: &Measurement, resolver: SizeResolver) {
+        let mut guard = self.0.borrow_mut();
+        let content = measurement.0.content.as_str();
+        guard.pending.push((content.to_owned(), resolver));
+        let manual_flush = guard.manual_flush;
+        drop(guard);
+
+        if !manual_flush {
+            self.flush();
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// Performance timeline
+//------------------------------------------------------------------------------
+
+fn perf_mark(id: MeasurementId, stage: &str) {
+    if let Some(performance) = seed::window().performance() {
+        let _ = performance.mark(&format!("measure:{id}:{stage}"));
+    }
+}
+
+fn perf_measure(id: MeasurementId, from: &str, to: &str) {
+    if let Some(performance) = seed::window().performance() {
+        let _ = performance.measure_with_start_mark_and_end_mark(
+            &format!("measure:{id}:{from}→{to}"),
+            &format!("measure:{id}:{from}"),
+            &format!("measure:{id}:{to}"),
+        );
+    }
+}
+
+impl Measurement {
+    /// Address of the shared data: equal for clones (and measurements upgraded
+    /// from their weak references), different for other alive measurements.
+    ///
+    /// Addresses of dropped measurements may be reused.
+    #[allow(dead_code)]
+    pub(crate) fn id(&self) -> usize {
+        Rc::as_ptr(&self.0) as usize
+    }
+
+    fn new(
+        id: MeasurementId,
+        content: Content,
+        options: MeasureOptions,
+        generation: u64,
+        measurer: Weak<RefCell<MeasurerData>>,
+    ) -> Self {
+        let div = match &content {
+            Content::Attached(el_ref) => el_ref.clone(),
+            _ => ElRef::new(),
+        };
+        Self(Rc::new(MeasurementData {
+            id,
+            content,
+            div,
+            rendered: RefCell::new(false),
+            options,
+            generation: RefCell::new(generation),
+            stale: RefCell::new(false),
+            update_wakers: RefCell::new(Vec::new()),
+            backend_size: RefCell::new(None),
+            measurer,
+        }))
+    }
+
+    /// Whether the node is rendered by the application, see [`Measurer::attach`].
+    fn is_attached(&self) -> bool {
+        matches!(self.0.content, Content::Attached(_))
+    }
+
+    fn downgrade(&self) -> WeakMeasurement {
+        WeakMeasurement(Rc::downgrade(&self.0))
+    }
+
+    fn view(&self) -> Node<()> {
+        let options = &self.0.options;
+        let class = C![options.class.as_deref()];
+        let style = style! {
+            St::Width => options.width.map(|width| format!("{width}px")),
+            St::MaxWidth => options.max_width.map(|width| format!("{width}px")),
+            St::Display => options.max_width.map(|_| "inline-block"),
+        };
+        let wrap = options.wrap.style();
+        let custom = options.style.clone();
+        match &self.0.content {
+            Content::Text(text) => {
+                div![el_ref(&self.0.div), div![class, style, wrap, custom, text]]
+            }
+            Content::Html(html) => {
+                div![el_ref(&self.0.div), class, style, wrap, custom, raw![html]]
+            }
+            Content::Node(node) => {
+                div![
+                    el_ref(&self.0.div),
+                    class,
+                    style,
+                    wrap,
+                    custom,
+                    Node::clone(node)
+                ]
+            }
+            Content::View(view) => {
+                div![el_ref(&self.0.div), class, style, wrap, custom, (view.0)()]
+            }
+            // Rendered by the application
+            Content::Attached(_) => Node::Empty,
+        }
+    }
+
+    fn container(&self) -> HtmlElement {
+        self.rendered_container().expect(
+            "Called `Measurement::get()` before future completion (i.e. node was rendered).",
+        )
+    }
+
+    /// Like `self.0.div.get()`, but also finds containers in shadow trees
+    /// (rendered by [`Measurer::new_portaled_into`]), which `document` does not contain.
+    fn rendered_container(&self) -> Option<HtmlElement> {
+        let node = self.0.div.shared_node_ws.clone_inner()?;
+        if !node.is_connected() {
+            return None;
+        }
+        node.dyn_into().ok()
+    }
+
+    /// Returns rendered node
+    ///
+    /// For HTML measurements it is the container of the markup.
+    pub(crate) fn get(&self) -> Element {
+        let container = self.container();
+        match &self.0.content {
+            Content::Text(_) | Content::Node(_) | Content::View(_) => {
+                container.first_element_child().unwrap()
+            }
+            Content::Html(_) | Content::Attached(_) => container.into(),
+        }
+    }
+
+    /// Returns both the wrapper of the rendered node and the node itself (see [`Measurement::get`]),
+    /// e.g. to compare the wrapper's padding box with the node's content box.
+    #[allow(dead_code)]
+    pub(crate) fn elements(&self) -> (Element, Element) {
+        (self.container().into(), self.get())
+    }
+
+    /// Returns HTML of the rendered container, e.g. for debugging.
+    pub(crate) fn outer_html(&self) -> Result<String, MeasureError> {
+        let container = self.rendered_container().ok_or(MeasureError::NotRendered)?;
+        Ok(container.outer_html())
+    }
+
+    /// Returns bounding rectangles of all top-level rendered elements.
+    #[allow(dead_code)]
+    pub(crate) fn children_rects(&self) -> Vec<DomRect> {
+        let children = self.container().children();
+        (0..children.length())
+            .filter_map(|i| children.item(i))
+            .map(|child| child.get_bounding_client_rect())
+            .collect()
+    }
+
+    /// Returns size of the rendered node stamped with the generation it was measured in.
+    pub(crate) fn size(&self) -> MeasuredSize {
+        let (width, height) = match *self.0.backend_size.borrow() {
+            Some(size) => size,
+            None => {
+                let rect = self.get().get_bounding_client_rect();
+                (rect.width(), rect.height())
+            }
+        };
+        MeasuredSize {
+            width,
+            height,
+            generation: *self.0.generation.borrow(),
+        }
+    }
+
+    /// Returns layout size (`offsetWidth` and `offsetHeight`) of the rendered node.
+    ///
+    /// Unlike [`Measurement::size`], which is based on `getBoundingClientRect()`,
+    /// the values are rounded to integers and not affected by CSS transforms.
+    #[allow(dead_code)]
+    pub(crate) fn offset_size(&self) -> (i32, i32) {
+        if let Some((width, height)) = *self.0.backend_size.borrow() {
+            return (width.round() as i32, height.round() as i32);
+        }
+        let element: HtmlElement = self
+            .get()
+            .dyn_into()
+            .expect("Measured node is not an HTML element");
+        (element.offset_width(), element.offset_height())
+    }
+
+    /// Whether the measurement is rendered (or resolved by a non-rendering backend).
+    fn is_ready(&self) -> bool {
+        self.0.div.get().is_some() || self.0.backend_size.borrow().is_some()
+    }
+
+    /// Whether the measurement was invalidated by [`Measurer::invalidate_all`]
+    /// (or [`Measurement::invalidate`]) and has not been measured again yet.
+    #[allow(dead_code)]
+    pub(crate) fn is_stale(&self) -> bool {
+        *self.0.stale.borrow()
+    }
+
+    /// Re-reads geometry of the rendered node on each of up to `frames` animation frames
+    /// and resolves once it stops changing, e.g. for content which reflows after the first
+    /// render (MathJax, lazy layout). Resolves with the last rectangle if it is still
+    /// changing after `frames` frames.
+    ///
+    /// The node stays rendered as long as the measurement is alive.
+    /// Call it after the measurement's future has completed.
+    #[allow(dead_code)]
+    pub(crate) fn wait_stable(&self, frames: u32) -> impl Future<Output = DomRect> {
+        let measurement = self.clone();
+        async move {
+            if let Some((width, height)) = *measurement.0.backend_size.borrow() {
+                // Resolved by a backend which does not render, so the size cannot change
+                return DomRect::new_with_x_and_y_and_width_and_height(0.0, 0.0, width, height)
+                    .unwrap();
+            }
+            let mut rect = measurement.get().get_bounding_client_rect();
+            for _ in 0..frames {
+                platform::next_animation_frame().await;
+                let next = measurement.get().get_bounding_client_rect();
+                let stable = (rect.x(), rect.y(), rect.width(), rect.height())
+                    == (next.x(), next.y(), next.width(), next.height());
+                rect = next;
+                if stable {
+                    break;
+                }
+            }
+            rect
+        }
+    }
+
+    /// Resolves when the measurement is measured again after [`Measurer::invalidate_all`].
+    ///
+    /// Never resolves for measurements created without [`MeasureOptions::auto_remeasure`].
+    pub(crate) fn next_update(&self) -> impl Future<Output = Measurement> {
+        NextUpdateFuture {
+            measurement: self.clone(),
+            generation: *self.0.generation.borrow(),
+        }
+    }
+
+    /// Marks the measurement as stale and measures it again in the next render pass,
+    /// e.g. after its content's styles have changed. Returned future resolves
+    /// when fresh geometry is available.
+    ///
+    /// The node is not recreated, so all clones of the measurement (including ones held
+    /// by other callers) keep pointing to the same element and observe fresh geometry
+    /// once the future resolves. Meanwhile they are [`Measurement::is_stale`].
+    /// With backends that do not render, the measurement is fresh again right away.
+    pub(crate) fn invalidate(&self) -> impl Future<Output = Measurement> {
+        let future = self.next_update();
+        let Some(data) = self.0.measurer.upgrade() else {
+            // Nobody would measure it again
+            return future;
+        };
+        let mut guard = data.borrow_mut();
+        guard.generation += 1;
+
+        if !guard.backend.renders() {
+            *self.0.generation.borrow_mut() = guard.generation;
+            return future;
+        }
+
+        *self.0.stale.borrow_mut() = true;
+        // Resolved only after the node has been rendered again
+        *self.0.rendered.borrow_mut() = false;
+        let queued = guard
+            .remeasurements
+            .iter()
+            .any(|weak| Weak::ptr_eq(&weak.0, &Rc::downgrade(&self.0)));
+        if !queued {
+            guard.remeasurements.push(self.downgrade());
+        }
+
+        let msg_sender = Rc::clone(&guard.msg_sender);
+        platform::spawn_local(async move {
+            msg_sender(Msg::WaitForRender);
+        });
+        future
+    }
+}
+
+impl MeasureRequest {
+    #[allow(dead_code)]
+    pub(crate) fn class(mut self, class: impl Into<String>) -> Self {
+        self.options.class = Some(class.into());
+        self
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn width(mut self, width: f64) -> Self {
+        self.options.width = Some(width);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn priority(mut self, priority: i32) -> Self {
+        self.options.priority = priority;
+        self
+    }
+
+    pub(crate) fn max_width(mut self, max_width: f64) -> Self {
+        self.options.max_width = Some(max_width);
+        self
+    }
+
+    pub(crate) fn wrap(mut self, wrap: WrapMode) -> Self {
+        self.options.wrap = wrap;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn auto_remeasure(mut self, auto_remeasure: bool) -> Self {
+        self.options.auto_remeasure = auto_remeasure;
+        self
+    }
+
+    /// Fails the measurement with [`MeasureError::Timeout`] if it is not rendered in time.
+    #[allow(dead_code)]
+    pub(crate) fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn validate(&self) -> Result<(), MeasureError> {
+        let positive = |value: Option<f64>| value.map_or(true, |v| v.is_finite() && v > 0.0);
+        let options = &self.options;
+        if !positive(options.width) {
+            Err(MeasureError::InvalidOptions(
+                "width must be a positive number",
+            ))
+        } else if !positive(options.max_width) {
+            Err(MeasureError::InvalidOptions(
+                "max_width must be a positive number",
+            ))
+        } else if options.width.is_some() && options.max_width.is_some() {
+            Err(MeasureError::InvalidOptions(
+                "width and max_width cannot be used together",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl IntoFuture for MeasureRequest {
+    type Output = Result<Measurement, MeasureError>;
+    type IntoFuture = LocalBoxFuture<'static, Self::Output>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        if let Err(error) = self.validate() {
+            return future::ready(Err(error)).boxed_local();
+        }
+
+        let measure = self
+            .measurer
+            .measure_content(Content::Text(self.text), self.options);
+        match self.timeout {
+            None => measure.boxed_local(),
+            Some(timeout) => {
+                let ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+                let timeout = platform::timeout(ms);
+                async move {
+                    match future::select(Box::pin(measure), Box::pin(timeout)).await {
+                        Either::Left((measurement, _)) => measurement,
+                        Either::Right(_) => Err(MeasureError::Timeout),
+                    }
+                }
+                .boxed_local()
+            }
+        }
     }
 }
 
@@ -201,13 +2128,15 @@ impl WeakMeasurement {
 }
 
 impl Future for MeasureFuture {
-    type Output = Measurement;
+    type Output = Result<Measurement, MeasureError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut state = self.state.borrow_mut();
 
-        if state.measurement.0.div.get().is_some() {
-            Poll::Ready(state.measurement.clone())
+        if state.cancelled {
+            Poll::Ready(Err(MeasureError::Cancelled))
+        } else if state.measurement.is_ready() {
+            Poll::Ready(Ok(state.measurement.clone()))
         } else {
             state.waker = Some(cx.waker().clone());
             Poll::Pending
@@ -215,11 +2144,638 @@ impl Future for MeasureFuture {
     }
 }
 
+impl Drop for MeasureFuture {
+    fn drop(&mut self) {
+        let mut state = self.state.borrow_mut();
+        state.waker = None;
+
+        let orphaned = Rc::strong_count(&self.state) == 1
+            && Rc::strong_count(&state.measurement.0) == 1
+            && state.measurement.0.div.get().is_none();
+        if let (true, Some(msg_sender)) = (orphaned, &self.msg_sender) {
+            // The future may be dropped within `update`, so send the message asynchronously.
+            let msg_sender = Rc::clone(msg_sender);
+            platform::spawn_local(async move {
+                msg_sender(Msg::Cancelled);
+            });
+        }
+    }
+}
+
+impl MeasurementHandle {
+    /// Whether the node has been rendered (i.e. awaiting the handle completes right away).
+    #[allow(dead_code)]
+    pub(crate) fn is_rendered(&self) -> bool {
+        self.future.state.borrow().measurement.is_ready()
+    }
+
+    /// Returns the measurement, which cannot be inspected before it is rendered.
+    #[allow(dead_code)]
+    pub(crate) fn measurement(&self) -> Measurement {
+        self.future.state.borrow().measurement.clone()
+    }
+
+    /// Stops rendering the measurement. Awaiting the handle fails with
+    /// [`MeasureError::Cancelled`] then.
+    #[allow(dead_code)]
+    pub(crate) fn cancel(&self) {
+        let mut state = self.future.state.borrow_mut();
+        state.cancelled = true;
+        state.waker = None;
+        let measurement = Rc::downgrade(&state.measurement.0);
+        let measurer = state.measurement.0.measurer.clone();
+        drop(state);
+
+        let Some(data) = measurer.upgrade() else {
+            return;
+        };
+        let mut guard = data.borrow_mut();
+        let future = Rc::downgrade(&self.future.state);
+        guard
+            .measurements
+            .retain(|weak| !Weak::ptr_eq(&weak.0, &measurement));
+        guard
+            .remeasurements
+            .retain(|weak| !Weak::ptr_eq(&weak.0, &measurement));
+        guard.futures.retain(|weak| !Weak::ptr_eq(weak, &future));
+        let msg_sender = Rc::clone(&guard.msg_sender);
+        drop(guard);
+
+        // Render again, so the node disappears
+        platform::spawn_local(async move {
+            msg_sender(Msg::Cancelled);
+        });
+    }
+}
+
+impl Future for MeasurementHandle {
+    type Output = Result<Measurement, MeasureError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.render_requested {
+            self.render_requested = true;
+            if let Some(msg_sender) = &self.future.msg_sender {
+                msg_sender(Msg::WaitForRender);
+            }
+        }
+        Pin::new(&mut self.future).poll(cx)
+    }
+}
+
+impl Future for NextUpdateFuture {
+    type Output = Measurement;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let data = &self.measurement.0;
+
+        if *data.generation.borrow() > self.generation {
+            Poll::Ready(self.measurement.clone())
+        } else {
+            data.update_wakers.borrow_mut().push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Default for MeasurerConfig {
+    fn default() -> Self {
+        Self {
+            wait_for_fonts: false,
+            perf_marks: false,
+            snapshot_length: 1000,
+            leak_threshold: Duration::from_secs(5),
+            render_watchdog: None,
+            max_new_per_pass: None,
+        }
+    }
+}
+
+impl fmt::Display for MeasurementId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl fmt::Debug for MeasurementData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("MeasurementData")
-            .field("text", &self.text)
+            .field("id", &self.id)
+            .field("content", &self.content)
             .field("div", &self.div)
+            .field("generation", &self.generation)
+            .field("stale", &self.stale)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::task::Context;
+
+    use futures::executor::block_on;
+    use futures::task::noop_waker_ref;
+    use seed::{attrs, span};
+
+    use super::*;
+    use crate::mock_orders::MockOrders;
+
+    /// Measurer rendered into nowhere: nodes of its view are never attached to the DOM,
+    /// so its measurements can be observed only while they are pending.
+    struct Harness {
+        measurer: Measurer,
+        orders: MockOrders<Msg>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let orders = MockOrders::new();
+            let msg_sender = orders.msg_sender();
+            Self {
+                measurer: Measurer::new(Rc::new(move |msg| msg_sender(Some(msg)))),
+                orders,
+            }
+        }
+
+        /// Passes queued messages to `Measurer::update`.
+        fn settle(&mut self) {
+            let measurer = &mut self.measurer;
+            self.orders
+                .settle(|msg, orders| measurer.update(msg, orders, |msg| msg));
+        }
+
+        /// Renders the measurer's view like Seed would after an update.
+        fn render(&mut self) -> Vec<Node<Msg>> {
+            self.settle();
+            let nodes = self.measurer.view_into();
+            self.orders.finish_render();
+            self.settle();
+            nodes
+        }
+    }
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        future.poll_unpin(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    fn fake_measurer(backend: &FakeBackend) -> Measurer {
+        let backend = backend.clone();
+        backend.set_size("hello", 30.0, 10.0);
+        Measurer::with_backend(backend)
+    }
+
+    #[test]
+    fn measure_resolves_with_fake_backend() {
+        let measurer = fake_measurer(&FakeBackend::new());
+        let measurement = block_on(measurer.measure("hello".to_owned())).unwrap();
+        assert_eq!(
+            (measurement.size().width, measurement.size().height),
+            (30.0, 10.0)
+        );
+    }
+
+    #[test]
+    fn measure_then_completes_with_synchronous_backend() {
+        let measurer = fake_measurer(&FakeBackend::new());
+        let width = Rc::new(Cell::new(None));
+        measurer.measure_then("hello".to_owned(), {
+            let width = Rc::clone(&width);
+            move |measurement| width.set(Some(measurement.size().width))
+        });
+        assert_eq!(width.get(), Some(30.0));
+        assert!(measurer.data.borrow().callback_futures.is_empty());
+    }
+
+    #[test]
+    fn measure_then_completes_on_flush() {
+        let backend = FakeBackend::new().manual_flush();
+        let measurer = fake_measurer(&backend);
+        let width = Rc::new(Cell::new(None));
+        measurer.measure_then("hello".to_owned(), {
+            let width = Rc::clone(&width);
+            move |measurement| width.set(Some(measurement.size().width))
+        });
+        assert_eq!(width.get(), None);
+        assert_eq!(measurer.data.borrow().callback_futures.len(), 1);
+
+        backend.flush();
+        assert_eq!(width.get(), Some(30.0));
+        assert!(measurer.data.borrow().callback_futures.is_empty());
+    }
+
+    #[test]
+    fn attached_element_rendered_late_is_not_rendered_by_measurer() {
+        let mut harness = Harness::new();
+        let mut measurement = Box::pin(harness.measurer.attach(ElRef::new()));
+        assert!(poll(&mut measurement).is_pending());
+
+        // The application renders the element in one of the following frames
+        for _ in 0..3 {
+            assert!(harness.render().is_empty());
+            assert!(poll(&mut measurement).is_pending());
+        }
+        assert_eq!(harness.measurer.data.borrow().futures.len(), 1);
+    }
+
+    #[test]
+    fn invalidate_all_keeps_individually_invalidated_measurements() {
+        let harness = Harness::new();
+        let measure = |auto_remeasure| {
+            let options = MeasureOptions {
+                auto_remeasure,
+                ..MeasureOptions::default()
+            };
+            let (state, _) =
+                harness
+                    .measurer
+                    .enqueue(Content::Text("hello".to_owned()), options, None);
+            let measurement = state.borrow().measurement.clone();
+            measurement
+        };
+        let invalidated = measure(false);
+        let remeasured = measure(true);
+
+        drop(invalidated.invalidate());
+        harness.measurer.invalidate_all();
+        harness.measurer.invalidate_all();
+        let data = harness.measurer.data.borrow();
+        let queued: Vec<_> = data
+            .remeasurements
+            .iter()
+            .filter_map(WeakMeasurement::upgrade)
+            .collect();
+        assert_eq!(queued, [invalidated.clone(), remeasured.clone()]);
+    }
+
+    #[test]
+    fn clear_fails_pending_futures() {
+        let mut harness = Harness::new();
+        let mut measurement = Box::pin(harness.measurer.measure("hello".to_owned()));
+        let mut attached = Box::pin(harness.measurer.attach(ElRef::new()));
+        let mut handle = harness.measurer.measure_handle("hello".to_owned());
+        assert!(poll(&mut measurement).is_pending());
+        assert!(poll(&mut attached).is_pending());
+        assert!(poll(&mut handle).is_pending());
+
+        harness.measurer.clear();
+        assert!(matches!(
+            poll(&mut measurement),
+            Poll::Ready(Err(MeasureError::Cancelled))
+        ));
+        assert!(matches!(
+            poll(&mut attached),
+            Poll::Ready(Err(MeasureError::Cancelled))
+        ));
+        assert!(matches!(
+            poll(&mut handle),
+            Poll::Ready(Err(MeasureError::Cancelled))
+        ));
+        assert!(harness.render().is_empty());
+    }
+
+    #[test]
+    fn headless_measurer_completes_measurements_when_driven() {
+        let backend = FakeBackend::new().manual_flush();
+        backend.set_size("hello", 30.0, 10.0);
+        let measurer = Measurer::new_headless(backend.clone());
+        let mut measurement = Box::pin(measurer.measure("hello".to_owned()));
+        let width = Rc::new(Cell::new(None));
+        measurer.measure_then("hello".to_owned(), {
+            let width = Rc::clone(&width);
+            move |measurement| width.set(Some(measurement.size().width))
+        });
+        assert!(poll(&mut measurement).is_pending());
+        backend.flush();
+        assert!(poll(&mut measurement).is_pending());
+
+        assert!(measurer.drive_once());
+        assert!(poll(&mut measurement).is_pending());
+        backend.flush();
+        assert_eq!(width.get(), Some(30.0));
+        let Poll::Ready(Ok(measurement)) = poll(&mut measurement) else {
+            panic!("measurement has not completed");
+        };
+        assert_eq!(measurement.size().height, 10.0);
+        assert!(!measurer.drive_once());
+    }
+
+    #[test]
+    fn fallback_pass_does_not_report_unmounted_view() {
+        let mut h = Harness::new();
+        let mut measurement = Box::pin(h.measurer.measure("hello".to_owned()));
+        assert!(poll(&mut measurement).is_pending());
+        h.settle();
+        assert_eq!(h.measurer.data.borrow().pass, 1);
+
+        // Seed does not render, the fallback resolves the pass and requests another one
+        for _ in 0..FALLBACK_FRAMES {
+            platform::animation_frame();
+        }
+        h.settle();
+        assert!(platform::take_logs().is_empty());
+        assert_eq!(h.measurer.data.borrow().fallback_pass, 1);
+        assert_eq!(h.measurer.data.borrow().pass, 2);
+
+        // Seed renders without the measurer's view
+        h.orders.finish_render();
+        h.settle();
+        assert!(platform::take_logs().contains(
+            &"error: [measurer] Measurer::view() does not appear to be mounted".to_owned()
+        ));
+    }
+
+    #[test]
+    fn clones_share_identity() {
+        let measurer = fake_measurer(&FakeBackend::new());
+        let first = block_on(measurer.measure("hello".to_owned())).unwrap();
+        let second = block_on(measurer.measure("hello".to_owned())).unwrap();
+        assert_eq!(first.id(), first.clone().id());
+        assert_ne!(first.id(), second.id());
+        assert_eq!(first, first.clone());
+        assert_ne!(first, second);
+        let unique = HashSet::from([first.clone(), second, first]);
+        assert_eq!(unique.len(), 2);
+    }
+
+    #[test]
+    fn wrap_modes_style_measured_text() {
+        /// Style of the text's node in `view_into()`'s output.
+        fn text_style(node: &Node<Msg>) -> String {
+            let Node::Element(wrapper) = node else {
+                panic!("measurement is not rendered as an element");
+            };
+            let Some(Node::Element(text)) = wrapper.children.first() else {
+                panic!("text is not rendered as an element");
+            };
+            text.style.to_string()
+        }
+
+        let mut h = Harness::new();
+        let text = "x".repeat(200);
+        let mut bubble = Box::pin(
+            h.measurer
+                .measure_with(text.clone(), MeasureOptions::chat_bubble(100.0)),
+        );
+        let mut single_line = Box::pin(h.measurer.measure_with(
+            text,
+            MeasureOptions {
+                wrap: WrapMode::NoWrap,
+                ..MeasureOptions::chat_bubble(100.0)
+            },
+        ));
+        assert!(poll(&mut bubble).is_pending());
+        assert!(poll(&mut single_line).is_pending());
+
+        h.settle();
+        let [bubble, single_line] = h.measurer.view_into().try_into().unwrap();
+        assert_eq!(
+            text_style(&bubble),
+            "max-width:100px;display:inline-block;overflow-wrap:break-word"
+        );
+        assert_eq!(
+            text_style(&single_line),
+            "max-width:100px;display:inline-block;white-space:nowrap"
+        );
+    }
+
+    #[test]
+    fn unmounted_view_is_reported() {
+        let mut h = Harness::new();
+        let mut measurement = Box::pin(h.measurer.attach(ElRef::new()));
+        assert!(poll(&mut measurement).is_pending());
+        platform::take_logs();
+
+        // The application's view calls `view_into()`, attached elements are not rendered there
+        assert!(h.render().is_empty());
+        assert!(platform::take_logs().is_empty());
+
+        // Seed renders a pass without calling it
+        h.orders.send_msg(Msg::WaitForRender);
+        h.settle();
+        h.orders.finish_render();
+        h.settle();
+        assert_eq!(
+            platform::take_logs(),
+            ["error: [measurer] Measurer::view() does not appear to be mounted"]
+        );
+        assert!(poll(&mut measurement).is_pending());
+    }
+
+    #[test]
+    fn measured_view_can_use_the_measurer() {
+        let mut h = Harness::new();
+        let measurer = h.measurer.clone();
+        // Borrows the measurer while it renders its measurements
+        let view = move || div![measurer.name()];
+        let mut measurement = Box::pin(h.measurer.measure_view(view));
+        assert!(poll(&mut measurement).is_pending());
+        h.settle();
+
+        let [Node::Element(container)] = <[_; 1]>::try_from(h.measurer.view_into()).unwrap() else {
+            panic!("measurement is not rendered as an element");
+        };
+        let Some(Node::Element(node)) = container.children.first() else {
+            panic!("measured view is not rendered");
+        };
+        assert!(matches!(node.children.first(), Some(Node::Text(_))));
+    }
+
+    #[test]
+    fn sanitized_nodes_keep_markup_only() {
+        let inner_ref = ElRef::<HtmlElement>::default();
+        let outer_ref = ElRef::<HtmlElement>::default();
+        let node: Node<&str> = div![
+            el_ref(&outer_ref),
+            C!["outer"],
+            style! {St::Width => "10px"},
+            ev(Ev::Click, |_| "outer clicked"),
+            span![
+                el_ref(&inner_ref),
+                attrs! {At::Title => "inner"},
+                ev(Ev::Click, |_| "inner clicked"),
+                "text",
+            ],
+        ];
+
+        let Node::Element(outer) = sanitize_for_measurement(node) else {
+            panic!("element is not sanitized into an element");
+        };
+        let no_handlers = format!("{:?}", El::<()>::empty(Tag::Div).event_handler_manager);
+        assert_eq!(format!("{:?}", outer.event_handler_manager), no_handlers);
+        assert!(outer.refs.is_empty());
+        assert_eq!(
+            outer.attrs.vals.get(&At::Class),
+            Some(&AtValue::Some("outer".to_owned()))
+        );
+        assert_eq!(
+            outer.style.to_string(),
+            style! {St::Width => "10px"}.to_string()
+        );
+
+        let [Node::Element(inner)] = &outer.children[..] else {
+            panic!("child element is not sanitized into an element");
+        };
+        assert_eq!(format!("{:?}", inner.event_handler_manager), no_handlers);
+        assert!(inner.refs.is_empty());
+        assert_eq!(
+            inner.attrs.vals.get(&At::Title),
+            Some(&AtValue::Some("inner".to_owned()))
+        );
+        assert!(matches!(&inner.children[..], [Node::Text(text)] if text.text == "text"));
+    }
+
+    #[test]
+    fn clear_drops_measure_then_callbacks_without_calling_them() {
+        let called = Rc::new(Cell::new(false));
+        let on_complete = || {
+            let called = Rc::clone(&called);
+            move |_| called.set(true)
+        };
+
+        let backend = FakeBackend::new().manual_flush();
+        let measurer = fake_measurer(&backend);
+        measurer.measure_then("hello".to_owned(), on_complete());
+        measurer.clear();
+        assert_eq!(Rc::strong_count(&called), 1);
+        backend.flush();
+
+        let mut h = Harness::new();
+        h.measurer.measure_then("hello".to_owned(), on_complete());
+        h.settle();
+        h.measurer.clear();
+        assert_eq!(Rc::strong_count(&called), 1);
+        assert!(h.render().is_empty());
+        assert!(!called.get());
+    }
+
+    #[test]
+    fn dropped_future_keeps_node_while_measurement_is_alive() {
+        let orders = MockOrders::new();
+        let msg_sender = orders.msg_sender();
+        let cancellations = Rc::new(Cell::new(0));
+        let measurer = Measurer::new(Rc::new({
+            let cancellations = Rc::clone(&cancellations);
+            move |msg| {
+                if matches!(msg, Msg::Cancelled) {
+                    cancellations.set(cancellations.get() + 1);
+                }
+                msg_sender(Some(msg));
+            }
+        }));
+        let mut h = Harness { measurer, orders };
+
+        let mut kept = h.measurer.measure_handle("kept".to_owned());
+        let mut orphaned = h.measurer.measure_handle("orphaned".to_owned());
+        assert!(poll(&mut kept).is_pending());
+        assert!(poll(&mut orphaned).is_pending());
+        let (kept_measurement, orphaned_measurement) = (kept.measurement(), orphaned.measurement());
+        h.settle();
+        assert_eq!(h.measurer.view_into().len(), 2);
+
+        // The caller still holds the measurement
+        drop(kept);
+        h.settle();
+        assert_eq!(cancellations.get(), 0);
+        assert_eq!(h.measurer.view_into().len(), 2);
+
+        drop(orphaned_measurement);
+        drop(orphaned);
+        h.settle();
+        assert_eq!(cancellations.get(), 1);
+        assert_eq!(h.measurer.view_into().len(), 1);
+
+        // Nothing else owns the measurement, so its node goes away with it
+        drop(kept_measurement);
+        assert!(h.measurer.view_into().is_empty());
+    }
+
+    /// Lays out text of measurements like a browser would with a monospace font
+    /// (8px wide characters, 16px high lines): lines are wrapped at the node's `max-width`
+    /// unless `white-space: nowrap` is applied.
+    struct MonospaceBackend;
+
+    impl MeasurerBackend for MonospaceBackend {
+        fn renders(&self) -> bool {
+            false
+        }
+
+        fn enqueue(&self, measurement: &Measurement, resolver: SizeResolver) {
+            let Node::Element(container) = measurement.view() else {
+                panic!("measurement is not rendered as an element");
+            };
+            let Some(Node::Element(node)) = container.children.first() else {
+                panic!("text is not rendered as an element");
+            };
+            let style = node.style.to_string();
+            let max_width = style
+                .split(';')
+                .find_map(|rule| rule.strip_prefix("max-width:")?.strip_suffix("px"))
+                .map_or(f64::INFINITY, |width| width.parse().unwrap());
+            let text_width = 8.0 * measurement.0.content.as_str().chars().count() as f64;
+            let lines = if style.contains("white-space:nowrap") {
+                1.0
+            } else {
+                (text_width / max_width).ceil().max(1.0)
+            };
+            resolver.resolve(text_width.min(max_width), 16.0 * lines);
+        }
+    }
+
+    #[test]
+    fn wrap_modes_change_measured_height() {
+        let measurer = Measurer::with_backend(MonospaceBackend);
+        let text = "x".repeat(200);
+        let bubble =
+            block_on(measurer.measure_with(text.clone(), MeasureOptions::chat_bubble(100.0)))
+                .unwrap();
+        let single_line = block_on(measurer.measure_with(
+            text,
+            MeasureOptions {
+                wrap: WrapMode::NoWrap,
+                ..MeasureOptions::chat_bubble(100.0)
+            },
+        ))
+        .unwrap();
+        assert_eq!(bubble.size().height, 256.0);
+        assert_eq!(single_line.size().height, 16.0);
+    }
+
+    #[test]
+    fn most_urgent_measurements_are_rendered_first() {
+        /// Texts of measurements rendered by `view_into()`.
+        fn rendered_texts(measurer: &Measurer) -> Vec<String> {
+            measurer
+                .view_into()
+                .iter()
+                .map(|node| match node {
+                    Node::Element(container) => match container.children.first() {
+                        Some(Node::Element(node)) => match node.children.first() {
+                            Some(Node::Text(text)) => text.text.to_string(),
+                            _ => panic!("text is not rendered"),
+                        },
+                        _ => panic!("text is not rendered as an element"),
+                    },
+                    _ => panic!("measurement is not rendered as an element"),
+                })
+                .collect()
+        }
+
+        let orders = MockOrders::new();
+        let msg_sender = orders.msg_sender();
+        let config = MeasurerConfig {
+            max_new_per_pass: NonZeroUsize::new(2),
+            ..MeasurerConfig::default()
+        };
+        let measurer = Measurer::with_config(Rc::new(move |msg| msg_sender(Some(msg))), config);
+        let mut h = Harness { measurer, orders };
+        let mut measurements =
+            [("low", 0), ("urgent", 5), ("normal", 1)].map(|(text, priority)| {
+                Box::pin(h.measurer.measure_with_priority(text.to_owned(), priority))
+            });
+        for measurement in &mut measurements {
+            assert!(poll(measurement).is_pending());
+        }
+        h.settle();
+        // In order of measurements, without the least urgent one
+        assert_eq!(rendered_texts(&h.measurer), ["urgent", "normal"]);
+    }
+}
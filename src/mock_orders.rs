@@ -0,0 +1,171 @@
+use std::{any::Any, cell::RefCell, collections::VecDeque, future::Future, rc::Rc};
+
+use futures::{FutureExt, Stream, StreamExt};
+use seed::app::{OrdersProxy, RenderInfo, StreamHandle, SubHandle};
+use seed::prelude::*;
+
+use crate::platform;
+
+/// In-memory `Orders` recording what `update` asks for, so that components can be driven
+/// without a running app: messages are fed back to `update` by [`MockOrders::settle`].
+///
+/// Commands and streams are spawned by [`platform::spawn_local`] and their messages queued.
+/// Seed's handles and proxies cannot be created outside of an app, so the methods
+/// returning them panic (components use [`platform::TaskHandle`] instead).
+pub(crate) struct MockOrders<Ms: 'static> {
+    messages: Rc<RefCell<VecDeque<Ms>>>,
+    after_render: Vec<Box<dyn FnOnce(RenderInfo) -> Option<Ms>>>,
+}
+
+impl<Ms: 'static> MockOrders<Ms> {
+    pub(crate) fn new() -> Self {
+        Self {
+            messages: Rc::default(),
+            after_render: Vec::new(),
+        }
+    }
+
+    /// Passes queued messages to `update` until none are left, including messages of commands
+    /// which complete meanwhile.
+    pub(crate) fn settle(&mut self, mut update: impl FnMut(Ms, &mut Self)) {
+        loop {
+            platform::run_until_stalled();
+            let Some(msg) = self.messages.borrow_mut().pop_front() else {
+                return;
+            };
+            update(msg, self);
+        }
+    }
+
+    /// Pretends the app has rendered, invoking callbacks of `after_next_render()`.
+    pub(crate) fn finish_render(&mut self) {
+        let timestamp = platform::now().unwrap_or_default();
+        for callback in std::mem::take(&mut self.after_render) {
+            let info = RenderInfo {
+                timestamp,
+                timestamp_delta: None,
+            };
+            self.queue()(callback(info));
+        }
+    }
+
+    fn queue(&self) -> impl Fn(Option<Ms>) + 'static {
+        let messages = Rc::clone(&self.messages);
+        move |msg| messages.borrow_mut().extend(msg)
+    }
+}
+
+/// Converts output of a command (or a callback) like Seed does.
+fn into_msg<Ms: 'static, MsU: 'static>(output: MsU) -> Option<Ms> {
+    let mut output = Some(output);
+    let output: &mut dyn Any = &mut output;
+    if let Some(msg) = output.downcast_mut::<Option<Ms>>() {
+        return msg.take();
+    }
+    if let Some(msg) = output.downcast_mut::<Option<Option<Ms>>>() {
+        return msg.take().flatten();
+    }
+    assert!(
+        output.is::<Option<()>>(),
+        "Cmds can return only Msg, Option<Msg> or ()!"
+    );
+    None
+}
+
+impl<Ms: 'static> Orders<Ms> for MockOrders<Ms> {
+    type AppMs = Ms;
+    type Mdl = ();
+    type INodes = Node<Ms>;
+
+    fn proxy<ChildMs: 'static>(
+        &mut self,
+        _f: impl FnOnce(ChildMs) -> Ms + 'static + Clone,
+    ) -> OrdersProxy<ChildMs, Ms, (), Node<Ms>> {
+        unimplemented!("proxies cannot be created without an app")
+    }
+
+    fn render(&mut self) -> &mut Self {
+        self
+    }
+
+    fn force_render_now(&mut self) -> &mut Self {
+        self
+    }
+
+    fn skip(&mut self) -> &mut Self {
+        self
+    }
+
+    fn notify(&mut self, _message: impl Any + Clone) -> &mut Self {
+        unimplemented!("notifications are not supported by mocked orders")
+    }
+
+    fn send_msg(&mut self, msg: Ms) -> &mut Self {
+        self.messages.borrow_mut().push_back(msg);
+        self
+    }
+
+    fn perform_cmd<MsU: 'static>(&mut self, cmd: impl Future<Output = MsU> + 'static) -> &mut Self {
+        let queue = self.queue();
+        platform::spawn_local(cmd.map(move |output| queue(into_msg(output))));
+        self
+    }
+
+    fn perform_cmd_with_handle<MsU: 'static>(
+        &mut self,
+        _cmd: impl Future<Output = MsU> + 'static,
+    ) -> CmdHandle {
+        unimplemented!("use platform::perform_cmd_with_handle")
+    }
+
+    fn clone_app(&self) -> App<Ms, (), Node<Ms>> {
+        unimplemented!("there is no app")
+    }
+
+    fn msg_mapper(&self) -> Rc<dyn Fn(Ms) -> Ms> {
+        Rc::new(|msg| msg)
+    }
+
+    fn msg_sender(&self) -> Rc<dyn Fn(Option<Ms>)> {
+        Rc::new(self.queue())
+    }
+
+    fn after_next_render<MsU: 'static>(
+        &mut self,
+        callback: impl FnOnce(RenderInfo) -> MsU + 'static,
+    ) -> &mut Self {
+        self.after_render
+            .push(Box::new(move |info| into_msg(callback(info))));
+        self
+    }
+
+    fn subscribe<MsU: 'static, SubMs: 'static + Clone>(
+        &mut self,
+        _handler: impl FnOnce(SubMs) -> MsU + Clone + 'static,
+    ) -> &mut Self {
+        unimplemented!("notifications are not supported by mocked orders")
+    }
+
+    fn subscribe_with_handle<MsU: 'static, SubMs: 'static + Clone>(
+        &mut self,
+        _handler: impl FnOnce(SubMs) -> MsU + Clone + 'static,
+    ) -> SubHandle {
+        unimplemented!("notifications are not supported by mocked orders")
+    }
+
+    fn stream<MsU: 'static>(&mut self, stream: impl Stream<Item = MsU> + 'static) -> &mut Self {
+        let queue = self.queue();
+        platform::spawn_local(stream.for_each(move |output| {
+            queue(into_msg(output));
+            async {}
+        }));
+        self
+    }
+
+    fn stream_with_handle<MsU: 'static>(
+        &mut self,
+        _stream: impl Stream<Item = MsU> + 'static,
+    ) -> StreamHandle {
+        unimplemented!("use platform::stream_with_handle")
+    }
+}
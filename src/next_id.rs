@@ -0,0 +1,39 @@
+use std::ops::Range;
+
+/// Allocates ids from a range in increasing order, wrapping around at its end.
+///
+/// Ids still in use are skipped, so a live id is never handed out twice
+/// (as long as the range is not exhausted by live ids).
+#[derive(Clone, Debug)]
+pub(crate) struct NextId {
+    range: Range<u64>,
+    next: u64,
+}
+
+impl NextId {
+    pub(crate) fn new(range: Range<u64>) -> Self {
+        assert!(!range.is_empty(), "Cannot allocate ids from an empty range");
+        Self {
+            next: range.start,
+            range,
+        }
+    }
+
+    /// Returns the next id for which `is_used` returns `false`.
+    ///
+    /// Panics if all ids of the range are used.
+    pub(crate) fn allocate(&mut self, is_used: impl Fn(u64) -> bool) -> u64 {
+        let first = self.next;
+        loop {
+            let id = self.next;
+            self.next = match id + 1 {
+                next if next == self.range.end => self.range.start,
+                next => next,
+            };
+            if !is_used(id) {
+                return id;
+            }
+            assert!(self.next != first, "All ids are in use");
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Browser services used by connections and measurers: time, timers, animation frames,
+//! spawning futures and logging.
+//!
+//! Imported JS functions panic outside of the browser, so native builds (i.e. unit tests)
+//! get stand-ins instead: the clock stands still until [`advance_time`] is called, spawned
+//! futures run in [`run_until_stalled`], animation frames are fired by [`animation_frame`]
+//! and log messages are collected for [`take_logs`].
+
+use std::future::Future;
+
+use futures::future::{self, AbortHandle};
+use futures::{FutureExt, Stream};
+use seed::prelude::Orders;
+
+#[cfg(target_arch = "wasm32")]
+use seed::prelude::{cmds, streams};
+
+/// Returns current time in milliseconds (see `Performance.now()`),
+/// if available (i.e. not in a headless environment).
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> Option<f64> {
+    web_sys::window()?
+        .performance()
+        .map(|performance| performance.now())
+}
+
+/// Returns a random number from `0.0..1.0`.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn random() -> f64 {
+    js_sys::Math::random()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn spawn_local(future: impl Future<Output = ()> + 'static) {
+    wasm_bindgen_futures::spawn_local(future);
+}
+
+/// Resolves after `ms` milliseconds.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn timeout(ms: u32) -> impl Future<Output = ()> {
+    cmds::timeout(ms, || ())
+}
+
+/// Yields every `ms` milliseconds.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn interval(ms: u32) -> impl Stream<Item = ()> {
+    streams::interval(ms, || ())
+}
+
+/// Resolves on the next animation frame.
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn next_animation_frame() {
+    let promise = js_sys::Promise::new(&mut |resolve, _reject| {
+        let _ = seed::window().request_animation_frame(&resolve);
+    });
+    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn log(message: &str) {
+    web_sys::console::log_1(&message.into());
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn debug(message: &str) {
+    web_sys::console::debug_1(&message.into());
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn warn(message: &str) {
+    web_sys::console::warn_1(&message.into());
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn error(message: &str) {
+    web_sys::console::error_1(&message.into());
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use native::*;
+
+//------------------------------------------------------------------------------
+// Task handles
+//------------------------------------------------------------------------------
+
+/// Aborts its command or stream when dropped.
+///
+/// Replaces Seed's `CmdHandle` and `StreamHandle`, which can be created only by a running app
+/// (and so not by mocked orders).
+#[must_use = "the task is aborted when its handle is dropped"]
+#[derive(Debug)]
+pub(crate) struct TaskHandle(AbortHandle);
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Like `orders.perform_cmd_with_handle(cmd)`.
+pub(crate) fn perform_cmd_with_handle<Ms: 'static>(
+    orders: &mut impl Orders<Ms>,
+    cmd: impl Future<Output = Ms> + 'static,
+) -> TaskHandle {
+    let (cmd, handle) = future::abortable(cmd);
+    orders.perform_cmd(cmd.map(Result::ok));
+    TaskHandle(handle)
+}
+
+/// Like `orders.stream_with_handle(stream)`.
+pub(crate) fn stream_with_handle<Ms: 'static>(
+    orders: &mut impl Orders<Ms>,
+    stream: impl Stream<Item = Ms> + 'static,
+) -> TaskHandle {
+    let (stream, handle) = futures::stream::abortable(stream);
+    orders.stream(stream);
+    TaskHandle(handle)
+}
+
+//------------------------------------------------------------------------------
+// Native stand-ins
+//------------------------------------------------------------------------------
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use std::{
+        cell::{Cell, RefCell},
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use futures::executor::{LocalPool, LocalSpawner};
+    use futures::task::LocalSpawnExt;
+    use futures::Stream;
+
+    thread_local! {
+        static CLOCK: Cell<f64> = const { Cell::new(0.0) };
+        static TIMER_WAKERS: RefCell<Vec<Waker>> = const { RefCell::new(Vec::new()) };
+        static FRAME: Cell<u64> = const { Cell::new(0) };
+        static FRAME_WAKERS: RefCell<Vec<Waker>> = const { RefCell::new(Vec::new()) };
+        static POOL: RefCell<LocalPool> = RefCell::new(LocalPool::new());
+        static SPAWNER: LocalSpawner = POOL.with(|pool| pool.borrow().spawner());
+        static LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn now() -> Option<f64> {
+        Some(CLOCK.with(Cell::get))
+    }
+
+    pub(crate) fn random() -> f64 {
+        0.5
+    }
+
+    pub(crate) fn spawn_local(future: impl Future<Output = ()> + 'static) {
+        SPAWNER
+            .with(|spawner| spawner.spawn_local(future))
+            .expect("local pool is alive");
+    }
+
+    pub(crate) fn timeout(ms: u32) -> impl Future<Output = ()> {
+        Deadline(CLOCK.with(Cell::get) + f64::from(ms))
+    }
+
+    pub(crate) fn interval(ms: u32) -> impl Stream<Item = ()> {
+        let start = CLOCK.with(Cell::get);
+        futures::stream::unfold(1.0, move |ticks| async move {
+            Deadline(start + ticks * f64::from(ms)).await;
+            Some(((), ticks + 1.0))
+        })
+    }
+
+    pub(crate) fn next_animation_frame() -> impl Future<Output = ()> {
+        AnimationFrame(FRAME.with(Cell::get) + 1)
+    }
+
+    pub(crate) fn log(message: &str) {
+        record("log", message);
+    }
+
+    pub(crate) fn debug(message: &str) {
+        record("debug", message);
+    }
+
+    pub(crate) fn warn(message: &str) {
+        record("warn", message);
+    }
+
+    pub(crate) fn error(message: &str) {
+        record("error", message);
+    }
+
+    fn record(level: &str, message: &str) {
+        LOGS.with(|logs| logs.borrow_mut().push(format!("{level}: {message}")));
+    }
+
+    /// Resolves when the clock reaches given time.
+    struct Deadline(f64);
+
+    impl Future for Deadline {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if CLOCK.with(Cell::get) >= self.0 {
+                return Poll::Ready(());
+            }
+            TIMER_WAKERS.with(|wakers| wakers.borrow_mut().push(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+
+    /// Resolves when given frame is fired.
+    struct AnimationFrame(u64);
+
+    impl Future for AnimationFrame {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if FRAME.with(Cell::get) >= self.0 {
+                return Poll::Ready(());
+            }
+            FRAME_WAKERS.with(|wakers| wakers.borrow_mut().push(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+
+    /// Runs spawned futures until none of them can make progress.
+    #[cfg(test)]
+    pub(crate) fn run_until_stalled() {
+        POOL.with(|pool| pool.borrow_mut().run_until_stalled());
+    }
+
+    /// Moves the clock forward, firing due timers.
+    #[cfg(test)]
+    pub(crate) fn advance_time(ms: f64) {
+        CLOCK.with(|clock| clock.set(clock.get() + ms));
+        for waker in TIMER_WAKERS.with(|wakers| wakers.take()) {
+            waker.wake();
+        }
+        run_until_stalled();
+    }
+
+    /// Fires an animation frame.
+    #[cfg(test)]
+    pub(crate) fn animation_frame() {
+        FRAME.with(|frame| frame.set(frame.get() + 1));
+        for waker in FRAME_WAKERS.with(|wakers| wakers.take()) {
+            waker.wake();
+        }
+        run_until_stalled();
+    }
+
+    /// Returns messages logged since the last call, prefixed with their level (e.g. `"error: "`).
+    #[cfg(test)]
+    pub(crate) fn take_logs() -> Vec<String> {
+        LOGS.with(|logs| logs.take())
+    }
+}
@@ -0,0 +1,176 @@
+use std::{cell::RefCell, rc::Rc};
+
+use seed::prelude::*;
+
+/// Creates a transport for given url and offered subprotocols.
+///
+/// Incoming frames and lifecycle events are not reported by the transport itself -
+/// they are delivered to `connection::update` as `Msg::Received`, `Msg::Opened` etc.
+pub(crate) type TransportFactory = Rc<dyn Fn(&str, &[String]) -> Box<dyn Transport>>;
+
+/// Writing side of a connection, i.e. a [`WebSocket`] or [`MockTransport`].
+pub(crate) trait Transport {
+    fn send_text(&self, message: &str) -> Result<(), WebSocketError>;
+
+    fn send_bytes(&self, frame: &[u8]) -> Result<(), WebSocketError>;
+
+    fn close(&self, code: Option<u16>, reason: Option<&str>) -> Result<(), WebSocketError>;
+
+    /// Subprotocol chosen by the server, empty if none.
+    fn protocol(&self) -> String;
+}
+
+impl Transport for WebSocket {
+    fn send_text(&self, message: &str) -> Result<(), WebSocketError> {
+        WebSocket::send_text(self, message)
+    }
+
+    fn send_bytes(&self, frame: &[u8]) -> Result<(), WebSocketError> {
+        WebSocket::send_bytes(self, frame)
+    }
+
+    fn close(&self, code: Option<u16>, reason: Option<&str>) -> Result<(), WebSocketError> {
+        WebSocket::close(self, code, reason)
+    }
+
+    fn protocol(&self) -> String {
+        WebSocket::protocol(self)
+    }
+}
+
+//------------------------------------------------------------------------------
+// Mock transport
+//------------------------------------------------------------------------------
+
+/// Frame written to a [`MockTransport`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SentFrame {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+/// In-memory transport recording written frames, so that a `Connection` can be driven
+/// without a server: frames from the server are fed as `Msg::Received` to `connection::update`.
+///
+/// Clones share the state, and so do all transports created by [`MockTransport::factory`]
+/// (one per (re)connection).
+#[derive(Clone, Default)]
+pub(crate) struct MockTransport {
+    state: Rc<RefCell<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    sent: Vec<SentFrame>,
+    /// Number of transports created by the factory
+    connects: usize,
+    /// Url of the last transport created by the factory
+    url: String,
+    /// Code of the last `close()` call (`Some(None)` if closed without a code)
+    closed: Option<Option<u16>>,
+    protocol: String,
+    /// Whether writes fail (as if the websocket was not open)
+    failing: bool,
+}
+
+impl MockTransport {
+    #[allow(dead_code)]
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pretends the server has chosen given subprotocol.
+    #[allow(dead_code)]
+    pub(crate) fn with_protocol(self, protocol: impl Into<String>) -> Self {
+        self.state.borrow_mut().protocol = protocol.into();
+        self
+    }
+
+    /// Returns factory for `ConnectionBuilder::transport`, sharing state with `self`.
+    #[allow(dead_code)]
+    pub(crate) fn factory(&self) -> TransportFactory {
+        let mock = self.clone();
+        Rc::new(move |url, _protocols| {
+            let mut state = mock.state.borrow_mut();
+            state.connects += 1;
+            url.clone_into(&mut state.url);
+            state.closed = None;
+            drop(state);
+            Box::new(mock.clone())
+        })
+    }
+
+    /// Makes subsequent writes fail (or succeed again).
+    #[allow(dead_code)]
+    pub(crate) fn set_failing(&self, failing: bool) {
+        self.state.borrow_mut().failing = failing;
+    }
+
+    /// Returns frames written since the last call.
+    #[allow(dead_code)]
+    pub(crate) fn take_sent(&self) -> Vec<SentFrame> {
+        std::mem::take(&mut self.state.borrow_mut().sent)
+    }
+
+    /// Returns textual frames written so far.
+    #[allow(dead_code)]
+    pub(crate) fn sent_text(&self) -> Vec<String> {
+        self.state
+            .borrow()
+            .sent
+            .iter()
+            .filter_map(|frame| match frame {
+                SentFrame::Text(text) => Some(text.clone()),
+                SentFrame::Bytes(_) => None,
+            })
+            .collect()
+    }
+
+    /// Number of transports created by [`MockTransport::factory`].
+    #[allow(dead_code)]
+    pub(crate) fn connects(&self) -> usize {
+        self.state.borrow().connects
+    }
+
+    /// Url the last transport has been created for.
+    #[allow(dead_code)]
+    pub(crate) fn url(&self) -> String {
+        self.state.borrow().url.clone()
+    }
+
+    /// Code passed to the last `close()` (`Some(None)` if closed without a code),
+    /// `None` if the current transport has not been closed.
+    #[allow(dead_code)]
+    pub(crate) fn closed(&self) -> Option<Option<u16>> {
+        self.state.borrow().closed
+    }
+
+    fn write(&self, frame: SentFrame) -> Result<(), WebSocketError> {
+        let mut state = self.state.borrow_mut();
+        if state.failing || state.closed.is_some() {
+            // Unlike `SendError`, it does not hold a `JsValue`, so it works outside of the browser
+            return Err(WebSocketError::TextError("Mock transport is not open"));
+        }
+        state.sent.push(frame);
+        Ok(())
+    }
+}
+
+impl Transport for MockTransport {
+    fn send_text(&self, message: &str) -> Result<(), WebSocketError> {
+        self.write(SentFrame::Text(message.to_owned()))
+    }
+
+    fn send_bytes(&self, frame: &[u8]) -> Result<(), WebSocketError> {
+        self.write(SentFrame::Bytes(frame.to_vec()))
+    }
+
+    fn close(&self, code: Option<u16>, _reason: Option<&str>) -> Result<(), WebSocketError> {
+        self.state.borrow_mut().closed = Some(code);
+        Ok(())
+    }
+
+    fn protocol(&self) -> String {
+        self.state.borrow().protocol.clone()
+    }
+}